@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// IP address range, e.g. `10.0.0.0/8`, `2001:db8::/32` or a single address
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug)]
+pub struct CidrParseError(String);
+
+impl std::fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid address range \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let error = || CidrParseError(cidr.to_string());
+        let (address, prefix_len) = match cidr.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (cidr.trim(), None),
+        };
+        let network = address.parse::<IpAddr>().map_err(|_| error())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| error())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(error());
+        }
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of a server bound to an IPv6 address are IPv4-mapped
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix_len: u8) -> bool {
+    network
+        .iter()
+        .zip(address)
+        .enumerate()
+        .all(|(i, (network, address))| {
+            let bits = (prefix_len as usize).saturating_sub(8 * i).min(8);
+            let mask = (0xff00u16 >> bits) as u8;
+            network & mask == address & mask
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(cidr: &str, address: &str) -> bool {
+        cidr.parse::<Cidr>()
+            .unwrap()
+            .contains(address.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        assert!(contains("10.0.0.0/8", "10.255.1.2"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.1"));
+        // prefix not on a byte boundary
+        assert!(contains("172.16.0.0/12", "172.31.255.255"));
+        assert!(!contains("172.16.0.0/12", "172.32.0.0"));
+        assert!(!contains("172.16.0.0/12", "172.15.255.255"));
+        assert!(contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(contains("203.0.113.7", "203.0.113.7"));
+        assert!(!contains("203.0.113.7", "203.0.113.8"));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("fe80::/10", "febf::1"));
+        assert!(!contains("fe80::/10", "fec0::1"));
+        assert!(contains("::1", "::1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        for cidr in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "host",
+        ] {
+            assert!(cidr.parse::<Cidr>().is_err(), "{}", cidr);
+        }
+        assert!(" 10.0.0.0/8 ".parse::<Cidr>().is_ok());
+    }
+}
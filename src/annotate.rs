@@ -0,0 +1,163 @@
+use crate::coordinate::Coordinate;
+use crate::gnss_time::{gps_to_utc, leap_seconds_gps};
+use crate::msm::MsmObservations;
+use crate::orbit::EphemerisStore;
+use chrono::NaiveDateTime;
+use rtcm_rs::Message;
+use serde_json::{json, Value};
+
+/// Adds the geodetic position derived from the antenna reference point of
+/// 1005/1006 messages, and the baseline from `reference` if supplied.
+pub fn station_position(msg: &Message, json_msg: &mut Value, reference: Option<&Coordinate>) {
+    let Some((key, _, position)) = antenna_ref_point(msg) else {
+        return;
+    };
+    let Some(fields) = json_msg.get_mut(key).and_then(Value::as_object_mut) else {
+        return;
+    };
+    fields.insert(
+        "antenna_ref_point_llh".into(),
+        json!({
+            "latitude_deg": position.latitude(),
+            "longitude_deg": position.longitude(),
+            "height_m": position.height(),
+        }),
+    );
+    if let Some(reference) = reference {
+        let enu = reference.enu_to(&position);
+        fields.insert(
+            "baseline".into(),
+            json!({
+                "east_m": enu.east,
+                "north_m": enu.north,
+                "up_m": enu.up,
+                "distance_m": reference.distance_to(&position),
+            }),
+        );
+    }
+}
+
+/// Reference station id and antenna reference point of 1005/1006 messages
+pub fn station_coordinate(msg: &Message) -> Option<(u16, Coordinate)> {
+    antenna_ref_point(msg).map(|(_, station_id, position)| (station_id, position))
+}
+
+/// Adds the position, clock bias, elevation and azimuth of each satellite of
+/// an MSM message received at GPS time `epoch`, computed from the stored
+/// ephemerides. Elevation and azimuth are only given if the position of the
+/// station is known.
+pub fn satellite_positions(
+    obs: &MsmObservations,
+    epoch: NaiveDateTime,
+    ephemerides: &EphemerisStore,
+    station: Option<&Coordinate>,
+    json_msg: &mut Value,
+) {
+    let Some(fields) = json_msg
+        .as_object_mut()
+        .and_then(|m| m.values_mut().next())
+        .and_then(Value::as_object_mut)
+    else {
+        return;
+    };
+    let mut satellite_ids: Vec<u8> = obs.signals.iter().map(|s| s.satellite_id).collect();
+    satellite_ids.dedup();
+    let mut satellites = Vec::new();
+    for satellite_id in satellite_ids {
+        let prn = obs.gnss.rinex_prn(satellite_id);
+        // the pseudorange modulo 1 ms of MSM1-3 is of no use for the travel time
+        let pseudorange_m = obs
+            .signals
+            .iter()
+            .filter(|s| s.satellite_id == satellite_id && obs.integer_ms_known())
+            .find_map(|s| s.pseudorange_m);
+        let Some((state, iode)) =
+            ephemerides.transmission_state(obs.gnss, prn, epoch, pseudorange_m)
+        else {
+            continue;
+        };
+        let [x, y, z] = state.position;
+        let mut position = json!({
+            "satellite": format!("{}{:02}", obs.gnss.system_char(), prn),
+            "iode": iode,
+            "x_m": x,
+            "y_m": y,
+            "z_m": z,
+            "clock_bias_s": state.clock_bias_s,
+        });
+        if let Some(station) = station {
+            let (elevation, azimuth) = state.elevation_azimuth(station);
+            position["elevation_deg"] = json!(elevation);
+            position["azimuth_deg"] = json!(azimuth);
+        }
+        satellites.push(position);
+    }
+    fields.insert("satellite_positions".into(), Value::Array(satellites));
+}
+
+/// Adds the epoch time of an observation message as ISO 8601 GPS time and
+/// UTC, with the leap seconds between them.
+pub fn epoch_time(gps: NaiveDateTime, json_msg: &mut Value) {
+    let Some(fields) = json_msg
+        .as_object_mut()
+        .and_then(|m| m.values_mut().next())
+        .and_then(Value::as_object_mut)
+    else {
+        return;
+    };
+    fields.insert(
+        "epoch_gps".into(),
+        json!(gps.format("%Y-%m-%dT%H:%M:%S%.3f").to_string()),
+    );
+    fields.insert(
+        "epoch_utc".into(),
+        json!(gps_to_utc(gps).format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+    );
+    fields.insert("leap_seconds".into(), json!(leap_seconds_gps(gps)));
+}
+
+/// Adds the client of the tcp server input the message was received from
+pub fn source(source: &str, json_msg: &mut Value) {
+    if let Some(fields) = json_msg
+        .as_object_mut()
+        .and_then(|m| m.values_mut().next())
+        .and_then(Value::as_object_mut)
+    {
+        fields.insert("source".into(), json!(source));
+    }
+}
+
+/// Message name, reference station id and antenna reference point of
+/// 1005/1006 messages
+fn antenna_ref_point(msg: &Message) -> Option<(&'static str, u16, Coordinate)> {
+    let (key, station_id, x, y, z) = match msg {
+        Message::Msg1005(m) => (
+            "Msg1005",
+            m.reference_station_id,
+            m.antenna_ref_point_ecef_x_m,
+            m.antenna_ref_point_ecef_y_m,
+            m.antenna_ref_point_ecef_z_m,
+        ),
+        Message::Msg1006(m) => (
+            "Msg1006",
+            m.reference_station_id,
+            m.antenna_ref_point_ecef_x_m,
+            m.antenna_ref_point_ecef_y_m,
+            m.antenna_ref_point_ecef_z_m,
+        ),
+        _ => return None,
+    };
+    Some((key, station_id, Coordinate::from_xyz(x, y, z)))
+}
+
+/// Reference station id of messages having one, otherwise 0
+pub fn reference_station_id(msg: &Message) -> u16 {
+    serde_json::to_value(msg)
+        .ok()
+        .as_ref()
+        .and_then(|value| value.as_object())
+        .and_then(|fields| fields.values().next())
+        .and_then(|fields| fields.get("reference_station_id"))
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u16
+}
@@ -2,12 +2,26 @@ use chrono::prelude::*;
 use std::f64::consts::PI;
 use std::io::Write;
 
-#[derive(Clone)]
+const A: f64 = 6378137.0;
+const F: f64 = 1.0 / 298.257223563;
+const E2: f64 = F * (2.0 - F);
+
+/// Geodetic coordinate on the WGS84 ellipsoid (degrees and meters)
+#[derive(Clone, Debug)]
 pub struct Coordinate {
     latitude: f64,
     longitude: f64,
     height: f64,
 }
+
+/// Local east, north, up components of a baseline (meters)
+#[derive(Clone, Copy, Debug)]
+pub struct Enu {
+    pub east: f64,
+    pub north: f64,
+    pub up: f64,
+}
+
 impl Coordinate {
     pub fn from_llh(latitude: f64, longitude: f64, height: f64) -> Self {
         Coordinate {
@@ -17,9 +31,6 @@ impl Coordinate {
         }
     }
     pub fn from_xyz(x: f64, y: f64, z: f64) -> Self {
-        const A: f64 = 6378137.0;
-        const F: f64 = 1.0 / 298.257223563;
-        const E2: f64 = F * (2.0 - F);
         let r2 = x * x + y * y;
         let mut z0 = z;
         let mut zk = z0 + 1.0;
@@ -45,6 +56,48 @@ impl Coordinate {
             height,
         }
     }
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+    pub fn height(&self) -> f64 {
+        self.height
+    }
+    /// Earth centered, earth fixed cartesian coordinate (x, y, z)
+    pub fn to_xyz(&self) -> (f64, f64, f64) {
+        let (sinphi, cosphi) = self.latitude.to_radians().sin_cos();
+        let (sinlam, coslam) = self.longitude.to_radians().sin_cos();
+        let v = A / (1.0 - E2 * sinphi * sinphi).sqrt();
+        (
+            (v + self.height) * cosphi * coslam,
+            (v + self.height) * cosphi * sinlam,
+            (v * (1.0 - E2) + self.height) * sinphi,
+        )
+    }
+    /// Baseline from this coordinate to `other`, expressed in the local
+    /// east, north, up frame of this coordinate
+    pub fn enu_to(&self, other: &Coordinate) -> Enu {
+        let (x0, y0, z0) = self.to_xyz();
+        let (x1, y1, z1) = other.to_xyz();
+        self.enu_of_delta(x1 - x0, y1 - y0, z1 - z0)
+    }
+    /// Rotates an ECEF difference vector into the local frame of this coordinate
+    pub fn enu_of_delta(&self, dx: f64, dy: f64, dz: f64) -> Enu {
+        let (sinphi, cosphi) = self.latitude.to_radians().sin_cos();
+        let (sinlam, coslam) = self.longitude.to_radians().sin_cos();
+        Enu {
+            east: -sinlam * dx + coslam * dy,
+            north: -sinphi * coslam * dx - sinphi * sinlam * dy + cosphi * dz,
+            up: cosphi * coslam * dx + cosphi * sinlam * dy + sinphi * dz,
+        }
+    }
+    /// Straight line (chord) distance in meters
+    pub fn distance_to(&self, other: &Coordinate) -> f64 {
+        let enu = self.enu_to(other);
+        (enu.east * enu.east + enu.north * enu.north + enu.up * enu.up).sqrt()
+    }
     pub fn write_to_stream<T: Write>(&self, stream: &mut T) -> std::io::Result<()> {
         //Create nmea
         let utc = Utc::now().round_subsecs(2);
@@ -74,7 +127,7 @@ impl Coordinate {
         for b in gga.as_bytes() {
             checksum ^= b;
         }
-        stream.write_all(&[b'$'])?;
+        stream.write_all(b"$")?;
         stream.write_all(gga.as_bytes())?;
         stream.write_all(format!("*{:02X}\r\n", checksum).as_bytes())?;
         Ok(())
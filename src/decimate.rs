@@ -0,0 +1,239 @@
+use crate::arguments::DecimateOptions;
+use crate::ephemeris::Ephemeris;
+use crate::gnss_time::{gps_epoch, TimeResolver};
+use crate::msm::Gnss;
+use crate::rules::{MessageSet, RuleParseError};
+use chrono::NaiveDate;
+use rtcm_rs::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Minimum interval between messages of a set of message types, written as
+/// `<messages>:<interval s>`, e.g. `msm:1`, `1005,1006:10` or `eph:60`
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    messages: MessageSet,
+    interval_ms: i64,
+}
+
+impl FromStr for RateLimit {
+    type Err = RuleParseError;
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        let error = || RuleParseError(format!("invalid rate limit \"{}\"", limit));
+        let (messages, interval) = limit.rsplit_once(':').ok_or_else(error)?;
+        let interval_s = interval.trim().parse::<f64>().map_err(|_| error())?;
+        if interval_s <= 0.0 {
+            return Err(error());
+        }
+        Ok(RateLimit {
+            messages: messages.parse()?,
+            interval_ms: (interval_s * 1000.0).round() as i64,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct MessageStats {
+    count_in: u64,
+    bytes_in: u64,
+    count_out: u64,
+    bytes_out: u64,
+}
+
+/// Drops messages exceeding their rate limit, the first matching limit
+/// applies to a message.
+///
+/// Observation messages (MSM and 1001-1004, 1009-1012) are decimated on their
+/// GNSS epoch time: the first epoch in each interval, aligned to GPS time, is
+/// kept with all its messages, so the MSMs of all constellations of the kept
+/// epochs stay together. Other messages are limited per message type, and
+/// ephemerides per satellite, on the time of the latest observation epoch;
+/// they are kept as long as no observation epoch has been seen.
+pub struct Decimator {
+    limits: Vec<RateLimit>,
+    time_resolver: TimeResolver,
+    /// GPS time (ms since the GPS epoch) of the first and latest observation epoch
+    first_epoch_ms: Option<i64>,
+    stream_time_ms: Option<i64>,
+    /// Kept observation epoch per rate limit
+    kept_epochs: HashMap<usize, i64>,
+    /// Time of the last kept message per message number and satellite
+    kept_messages: HashMap<(u16, u8), i64>,
+    stats: BTreeMap<u16, MessageStats>,
+    start: Instant,
+    report_interval_ms: Option<i64>,
+    last_report_ms: i64,
+}
+
+impl Decimator {
+    pub fn new(options: &DecimateOptions, date: Option<NaiveDate>) -> Self {
+        Decimator {
+            limits: options.rate_limits.clone(),
+            time_resolver: TimeResolver::from_date_or_now(date),
+            first_epoch_ms: None,
+            stream_time_ms: None,
+            kept_epochs: HashMap::new(),
+            kept_messages: HashMap::new(),
+            stats: BTreeMap::new(),
+            start: Instant::now(),
+            report_interval_ms: options
+                .bandwidth_report
+                .map(|interval_s| (interval_s * 1000.0).round() as i64),
+            last_report_ms: 0,
+        }
+    }
+    /// Whether to keep `msg` of `size` bytes (RTCM frame), counted for the report
+    pub fn keep(&mut self, msg: &Message, size: usize) -> bool {
+        let number = msg.number().unwrap_or(0);
+        let epoch_ms = observation_epoch(msg).map(|epoch| epoch.gps_ms(&mut self.time_resolver));
+        if let Some(epoch_ms) = epoch_ms {
+            self.first_epoch_ms.get_or_insert(epoch_ms);
+            self.stream_time_ms = Some(self.stream_time_ms.unwrap_or(epoch_ms).max(epoch_ms));
+        }
+        let limit = self
+            .limits
+            .iter()
+            .position(|limit| limit.messages.contains(number));
+        let keep = match (limit, epoch_ms) {
+            (None, _) => true,
+            (Some(i), Some(epoch_ms)) => {
+                let interval_ms = self.limits[i].interval_ms;
+                match self.kept_epochs.get(&i) {
+                    Some(kept) if *kept == epoch_ms => true,
+                    Some(kept)
+                        if kept.div_euclid(interval_ms) == epoch_ms.div_euclid(interval_ms) =>
+                    {
+                        false
+                    }
+                    _ => {
+                        self.kept_epochs.insert(i, epoch_ms);
+                        true
+                    }
+                }
+            }
+            (Some(i), None) => match self.stream_time_ms {
+                None => true,
+                Some(now_ms) => {
+                    let satellite = Ephemeris::from_message(msg, self.time_resolver.reference())
+                        .map_or(0, |eph| eph.prn());
+                    let key = (number, satellite);
+                    match self.kept_messages.get(&key) {
+                        Some(kept) if (now_ms - kept).abs() < self.limits[i].interval_ms => false,
+                        _ => {
+                            self.kept_messages.insert(key, now_ms);
+                            true
+                        }
+                    }
+                }
+            },
+        };
+        let stats = self.stats.entry(number).or_default();
+        stats.count_in += 1;
+        stats.bytes_in += size as u64;
+        if keep {
+            stats.count_out += 1;
+            stats.bytes_out += size as u64;
+        }
+        keep
+    }
+    /// The report if the bandwidth report interval has passed, by the time of
+    /// the observation epochs or else the elapsed time
+    pub fn periodic_report(&mut self) -> Option<String> {
+        let interval_ms = self.report_interval_ms?;
+        let now_ms = match (self.first_epoch_ms, self.stream_time_ms) {
+            (Some(first), Some(last)) => last - first,
+            _ => self.start.elapsed().as_millis() as i64,
+        };
+        if now_ms - self.last_report_ms < interval_ms {
+            return None;
+        }
+        self.last_report_ms = now_ms;
+        Some(self.report())
+    }
+    /// Messages and bytes per message type before and after decimation, and
+    /// the resulting bit rates over the time span of the observation epochs,
+    /// or the elapsed time if the stream has no observations
+    pub fn report(&self) -> String {
+        let duration_s = match (self.first_epoch_ms, self.stream_time_ms) {
+            (Some(first), Some(last)) if last > first => (last - first) as f64 * 0.001,
+            _ => self.start.elapsed().as_secs_f64(),
+        };
+        let rate = |bytes: u64| {
+            if duration_s > 0.0 {
+                format!("{:.1}", bytes as f64 * 8.0 / duration_s)
+            } else {
+                "-".to_string()
+            }
+        };
+        let mut report = format!(
+            "bandwidth over {:.1} s\n{:>8} {:>10} {:>10} {:>12} {:>12} {:>12} {:>12}\n",
+            duration_s, "message", "in", "out", "bytes in", "bytes out", "bit/s in", "bit/s out"
+        );
+        let mut total = MessageStats::default();
+        let mut line = |name: String, stats: &MessageStats| {
+            report.push_str(&format!(
+                "{:>8} {:>10} {:>10} {:>12} {:>12} {:>12} {:>12}\n",
+                name,
+                stats.count_in,
+                stats.count_out,
+                stats.bytes_in,
+                stats.bytes_out,
+                rate(stats.bytes_in),
+                rate(stats.bytes_out)
+            ));
+        };
+        for (number, stats) in self.stats.iter() {
+            line(number.to_string(), stats);
+            total.count_in += stats.count_in;
+            total.count_out += stats.count_out;
+            total.bytes_in += stats.bytes_in;
+            total.bytes_out += stats.bytes_out;
+        }
+        line("total".to_string(), &total);
+        report
+    }
+}
+
+/// Epoch of an MSM or legacy observation message
+pub struct ObservationEpoch {
+    pub reference_station_id: u16,
+    pub gnss: Gnss,
+    pub epoch_time_ms: u32,
+    pub glo_day_of_week: Option<u8>,
+    /// More observation messages of the same epoch follow (MSM multiple
+    /// message bit, legacy synchronous GNSS flag)
+    pub multiple_message: bool,
+}
+
+impl ObservationEpoch {
+    /// GPS time of the epoch in ms since the GPS epoch
+    pub fn gps_ms(&self, time_resolver: &mut TimeResolver) -> i64 {
+        let gps = time_resolver.resolve_msm(self.gnss, self.epoch_time_ms, self.glo_day_of_week);
+        (gps - gps_epoch()).num_milliseconds()
+    }
+}
+
+pub fn observation_epoch(msg: &Message) -> Option<ObservationEpoch> {
+    let number = msg.number()?;
+    let (gnss, epoch_field) = match number {
+        1001..=1004 => (Gnss::Gps, "gps_epoch_time_ms"),
+        1009..=1012 => (Gnss::Glonass, "glo_epoch_time_ms"),
+        _ => {
+            let (gnss, _) = Gnss::from_msm_number(number)?;
+            (gnss, gnss.epoch_time_field())
+        }
+    };
+    let value = serde_json::to_value(msg).ok()?;
+    let fields = value.get(format!("Msg{}", number))?;
+    let get_u64 = |field: &str| fields.get(field).and_then(|value| value.as_u64());
+    Some(ObservationEpoch {
+        reference_station_id: get_u64("reference_station_id")? as u16,
+        gnss,
+        epoch_time_ms: get_u64(epoch_field)? as u32,
+        glo_day_of_week: get_u64("glo_day_of_week").map(|day| day as u8),
+        multiple_message: get_u64("msm_multiple_message_flag")
+            .or_else(|| get_u64("synchronous_gnss_msg_flag"))
+            == Some(1),
+    })
+}
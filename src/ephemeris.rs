@@ -0,0 +1,589 @@
+use crate::gnss_time::{gps_epoch, gps_week_tow, leap_seconds_gps};
+use crate::msm::Gnss;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+use rtcm_rs::Message;
+use serde_json::{json, Map, Value};
+use std::f64::consts::PI;
+
+/// GPS week of the first BeiDou (BDT) week
+pub const BDT_WEEK_OFFSET: i64 = 1356;
+/// GPS week of the first Galileo (GST) week
+pub const GST_WEEK_OFFSET: i64 = 1024;
+
+const GPS_URA_M: [f64; 16] = [
+    2.4, 3.4, 4.85, 6.85, 9.65, 13.65, 24.0, 48.0, 96.0, 192.0, 384.0, 768.0, 1536.0, 3072.0,
+    6144.0, 32767.0,
+];
+
+/// Broadcast ephemeris of GPS, Galileo, BeiDou and QZSS satellites.
+/// Angles are in radians and times in the time scale of the constellation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeplerEphemeris {
+    pub gnss: Gnss,
+    pub prn: u8,
+    /// Week number as used in RINEX: GPS week for GPS, QZSS and Galileo,
+    /// BDT week for BeiDou
+    pub week: i64,
+    pub toc_s: f64,
+    pub toe_s: f64,
+    pub af0: f64,
+    pub af1: f64,
+    pub af2: f64,
+    /// IODE, IODnav (Galileo) or AODE (BeiDou)
+    pub iode: u32,
+    /// IODC, or AODC (BeiDou)
+    pub iodc: u32,
+    pub crs: f64,
+    pub crc: f64,
+    pub cus: f64,
+    pub cuc: f64,
+    pub cis: f64,
+    pub cic: f64,
+    pub delta_n: f64,
+    pub m0: f64,
+    pub e: f64,
+    pub sqrt_a: f64,
+    pub omega0: f64,
+    pub i0: f64,
+    pub omega: f64,
+    pub omega_dot: f64,
+    pub idot: f64,
+    /// TGD, BGD E5a/E1 and BGD E5b/E1 (Galileo), TGD1 and TGD2 (BeiDou)
+    pub tgd: [f64; 2],
+    /// URA index (GPS, QZSS, BeiDou) or SISA index (Galileo)
+    pub accuracy_index: u8,
+    pub health: u32,
+    pub codes_on_l2: u32,
+    pub l2p_flag: u32,
+    pub fit_interval_flag: u32,
+    /// Galileo data source (RINEX bit field), 0 for other constellations
+    pub data_sources: u32,
+}
+
+/// GLONASS broadcast ephemeris, positions in km, velocities in km/s and
+/// accelerations in km/s²
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlonassEphemeris {
+    pub prn: u8,
+    pub fcn: i8,
+    /// Reference time tb in UTC
+    pub toe_utc: NaiveDateTime,
+    /// Message frame time tk in seconds of day (Moscow time)
+    pub tk_s: u32,
+    pub tau_n: f64,
+    pub gamma_n: f64,
+    pub position: [f64; 3],
+    pub velocity: [f64; 3],
+    pub acceleration: [f64; 3],
+    pub health: u8,
+    pub age_days: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ephemeris {
+    Kepler(KeplerEphemeris),
+    Glonass(GlonassEphemeris),
+}
+
+impl KeplerEphemeris {
+    /// Time of clock in the time scale of the constellation, BDT for BeiDou
+    /// and GPS time for the others
+    pub fn toc(&self) -> NaiveDateTime {
+        let mut week = self.week;
+        // toc and toe may be on either side of a week boundary
+        if self.toc_s - self.toe_s > 302_400.0 {
+            week -= 1;
+        } else if self.toe_s - self.toc_s > 302_400.0 {
+            week += 1;
+        }
+        if self.gnss == Gnss::Beidou {
+            week += BDT_WEEK_OFFSET;
+        }
+        week_start(week) + Duration::try_milliseconds((self.toc_s * 1000.0).round() as i64).unwrap()
+    }
+    /// Time of ephemeris in the time scale of the constellation
+    pub fn toe(&self) -> NaiveDateTime {
+        let week = match self.gnss {
+            Gnss::Beidou => self.week + BDT_WEEK_OFFSET,
+            _ => self.week,
+        };
+        week_start(week) + Duration::try_milliseconds((self.toe_s * 1000.0).round() as i64).unwrap()
+    }
+    /// Accuracy in meters of the URA or SISA index
+    pub fn accuracy_m(&self) -> f64 {
+        match self.gnss {
+            Gnss::Galileo => match self.accuracy_index as f64 {
+                i if i < 50.0 => i * 0.01,
+                i if i < 75.0 => 0.5 + (i - 50.0) * 0.02,
+                i if i < 100.0 => 1.0 + (i - 75.0) * 0.04,
+                i if i < 126.0 => 2.0 + (i - 100.0) * 0.16,
+                _ => -1.0,
+            },
+            _ => GPS_URA_M[self.accuracy_index as usize & 0xf],
+        }
+    }
+    /// BeiDou GEO satellites broadcast D2 navigation messages
+    pub fn is_beidou_geo(&self) -> bool {
+        self.gnss == Gnss::Beidou && (self.prn <= 5 || self.prn >= 59)
+    }
+}
+
+impl Ephemeris {
+    /// Ephemeris of a 1019, 1020, 1042, 1044, 1045 or 1046 message. Truncated week
+    /// numbers and the GLONASS day are resolved relative to `reference`, a GPS time.
+    pub fn from_message(msg: &Message, reference: NaiveDateTime) -> Option<Ephemeris> {
+        let (reference_week, reference_tow_ms) = gps_week_tow(reference);
+        let eph = match msg {
+            Message::Msg1019(m) => KeplerEphemeris {
+                gnss: Gnss::Gps,
+                prn: m.gps_satellite_id,
+                week: resolve_week(m.gps_week_number as i64, 1024, reference_week),
+                toc_s: m.toc_s as f64,
+                toe_s: m.toe_s as f64,
+                af0: m.af0_s,
+                af1: m.af1_s_s as f64,
+                af2: m.af2_s_s2 as f64,
+                iode: m.iode as u32,
+                iodc: m.iodc as u32,
+                crs: m.crs_m as f64,
+                crc: m.crc_m as f64,
+                cus: m.cus_rad as f64,
+                cuc: m.cuc_rad as f64,
+                cis: m.cis_rad as f64,
+                cic: m.cic_rad as f64,
+                delta_n: m.delta_n_sc_s as f64 * PI,
+                m0: m.m0_sc * PI,
+                e: m.eccentricity,
+                sqrt_a: m.sqrt_a_sqrt_m,
+                omega0: m.omega0_sc * PI,
+                i0: m.i0_sc * PI,
+                omega: m.omega_sc * PI,
+                omega_dot: m.omegadot_sc_s * PI,
+                idot: m.idot_sc_s * PI,
+                tgd: [m.tgd_s as f64, 0.0],
+                accuracy_index: m.ura_index,
+                health: m.sv_health_ind as u32,
+                codes_on_l2: m.code_on_l2_ind as u32,
+                l2p_flag: m.l2_p_data_flag as u32,
+                fit_interval_flag: m.fit_interval_ind as u32,
+                data_sources: 0,
+            },
+            Message::Msg1044(m) => KeplerEphemeris {
+                gnss: Gnss::Qzss,
+                prn: m.qzss_satellite_id,
+                week: resolve_week(m.qzss_week_number as i64, 1024, reference_week),
+                toc_s: m.toc_s as f64,
+                toe_s: m.toe_s as f64,
+                af0: m.af0_s,
+                af1: m.af1_s_s as f64,
+                af2: m.af2_s_s2 as f64,
+                iode: m.iode as u32,
+                iodc: m.iodc as u32,
+                crs: m.crs_m as f64,
+                crc: m.crc_m as f64,
+                cus: m.cus_rad as f64,
+                cuc: m.cuc_rad as f64,
+                cis: m.cis_rad as f64,
+                cic: m.cic_rad as f64,
+                delta_n: m.delta_n_sc_s as f64 * PI,
+                m0: m.m0_sc * PI,
+                e: m.eccentricity,
+                sqrt_a: m.sqrt_a_sqrt_m,
+                omega0: m.omega0_sc * PI,
+                i0: m.i0_sc * PI,
+                omega: m.omega_sc * PI,
+                omega_dot: m.omegadot_sc_s * PI,
+                idot: m.idot_sc_s * PI,
+                tgd: [m.tgd_s as f64, 0.0],
+                accuracy_index: m.ura_index,
+                health: m.sv_health_ind as u32,
+                codes_on_l2: m.code_on_l2_ind as u32,
+                l2p_flag: 0,
+                fit_interval_flag: m.fit_interval_ind as u32,
+                data_sources: 0,
+            },
+            Message::Msg1042(m) => KeplerEphemeris {
+                gnss: Gnss::Beidou,
+                prn: m.bds_satellite_id,
+                week: resolve_week(
+                    m.bds_week_number as i64,
+                    8192,
+                    reference_week - BDT_WEEK_OFFSET,
+                ),
+                toc_s: m.toc_s as f64,
+                toe_s: m.toe_s as f64,
+                af0: m.a0_s,
+                af1: m.a1_s_s,
+                af2: m.a2_s_s2 as f64,
+                iode: m.aode as u32,
+                iodc: m.aodc as u32,
+                crs: m.crs_m as f64,
+                crc: m.crc_m as f64,
+                cus: m.cus_rad as f64,
+                cuc: m.cuc_rad as f64,
+                cis: m.cis_rad as f64,
+                cic: m.cic_rad as f64,
+                delta_n: m.delta_n_sc_s as f64 * PI,
+                m0: m.m0_sc * PI,
+                e: m.eccentricity,
+                sqrt_a: m.sqrt_a_sqrt_m,
+                omega0: m.omega0_sc * PI,
+                i0: m.i0_sc * PI,
+                omega: m.omega_sc * PI,
+                omega_dot: m.omegadot_sc_s * PI,
+                idot: m.idot_sc_s * PI,
+                tgd: [m.tgd1_s as f64, m.tgd2_s as f64],
+                accuracy_index: m.ura_index,
+                health: m.sv_health_flag as u32,
+                codes_on_l2: 0,
+                l2p_flag: 0,
+                fit_interval_flag: 0,
+                data_sources: 0,
+            },
+            Message::Msg1045(m) => KeplerEphemeris {
+                gnss: Gnss::Galileo,
+                prn: m.gal_satellite_id,
+                week: resolve_week(
+                    m.gal_week_number as i64,
+                    4096,
+                    reference_week - GST_WEEK_OFFSET,
+                ) + GST_WEEK_OFFSET,
+                toc_s: m.toc_s as f64,
+                toe_s: m.toe_s as f64,
+                af0: m.af0_s,
+                af1: m.af1_s_s,
+                af2: m.af2_s_s2 as f64,
+                iode: m.iodnav as u32,
+                iodc: m.iodnav as u32,
+                crs: m.crs_m as f64,
+                crc: m.crc_m as f64,
+                cus: m.cus_rad as f64,
+                cuc: m.cuc_rad as f64,
+                cis: m.cis_rad as f64,
+                cic: m.cic_rad as f64,
+                delta_n: m.delta_n_sc_s as f64 * PI,
+                m0: m.m0_sc * PI,
+                e: m.eccentricity,
+                sqrt_a: m.sqrt_a_sqrt_m,
+                omega0: m.omega0_sc * PI,
+                i0: m.i0_sc * PI,
+                omega: m.omega_sc * PI,
+                omega_dot: m.omegadot_sc_s * PI,
+                idot: m.idot_sc_s as f64 * PI,
+                tgd: [m.bgd_e1_e5a_s as f64, 0.0],
+                accuracy_index: m.sisa_e1_e5a_index,
+                health: ((m.e5a_sig_health_ind as u32) << 4)
+                    | ((m.e5a_data_validity_flag as u32) << 3),
+                codes_on_l2: 0,
+                l2p_flag: 0,
+                fit_interval_flag: 0,
+                // F/NAV E5a-I, clock parameters for E5a,E1
+                data_sources: (1 << 1) | (1 << 8),
+            },
+            Message::Msg1046(m) => KeplerEphemeris {
+                gnss: Gnss::Galileo,
+                prn: m.gal_satellite_id,
+                week: resolve_week(
+                    m.gal_week_number as i64,
+                    4096,
+                    reference_week - GST_WEEK_OFFSET,
+                ) + GST_WEEK_OFFSET,
+                toc_s: m.toc_s as f64,
+                toe_s: m.toe_s as f64,
+                af0: m.af0_s,
+                af1: m.af1_s_s,
+                af2: m.af2_s_s2 as f64,
+                iode: m.iodnav as u32,
+                iodc: m.iodnav as u32,
+                crs: m.crs_m as f64,
+                crc: m.crc_m as f64,
+                cus: m.cus_rad as f64,
+                cuc: m.cuc_rad as f64,
+                cis: m.cis_rad as f64,
+                cic: m.cic_rad as f64,
+                delta_n: m.delta_n_sc_s as f64 * PI,
+                m0: m.m0_sc * PI,
+                e: m.eccentricity,
+                sqrt_a: m.sqrt_a_sqrt_m,
+                omega0: m.omega0_sc * PI,
+                i0: m.i0_sc * PI,
+                omega: m.omega_sc * PI,
+                omega_dot: m.omegadot_sc_s * PI,
+                idot: m.idot_sc_s as f64 * PI,
+                tgd: [m.bgd_e1_e5a_s as f64, m.bgd_e1_e5b_s as f64],
+                accuracy_index: m.sisa_e1_e5b_index,
+                health: (m.e1_b_data_validity_flag as u32)
+                    | ((m.e1_b_sig_health_ind as u32) << 1)
+                    | ((m.e5b_data_validity_flag as u32) << 6)
+                    | ((m.e5b_sig_health_ind as u32) << 7),
+                codes_on_l2: 0,
+                l2p_flag: 0,
+                fit_interval_flag: 0,
+                // I/NAV E1-B and E5b-I, clock parameters for E5b,E1
+                data_sources: 1 | (1 << 2) | (1 << 9),
+            },
+            Message::Msg1020(m) => {
+                // tb is given in 15 minute steps of the Moscow day
+                let reference_utc_s =
+                    (reference_tow_ms / 1000 - leap_seconds_gps(reference)) as f64;
+                let tb_utc_s = m.tb_min as f64 * 60.0 - 3.0 * 3600.0;
+                let day = ((reference_utc_s - tb_utc_s) / 86400.0).round() as i64;
+                let toe_utc = week_start(reference_week)
+                    + Duration::try_seconds(day * 86400 + tb_utc_s as i64).unwrap();
+                return Some(Ephemeris::Glonass(GlonassEphemeris {
+                    prn: m.glo_satellite_id,
+                    fcn: m.glo_satellite_freq_chan_number,
+                    toe_utc,
+                    tk_s: m.tk_h as u32 * 3600 + m.tk_min as u32 * 60 + m.tk_s as u32,
+                    tau_n: m.tau_n_s,
+                    gamma_n: m.gamma_n as f64,
+                    position: [m.xn_km, m.yn_km, m.zn_km],
+                    velocity: [
+                        m.xn_first_deriv_km_s,
+                        m.yn_first_deriv_km_s,
+                        m.zn_first_deriv_km_s,
+                    ],
+                    acceleration: [
+                        m.xn_second_deriv_km_s2 as f64,
+                        m.yn_second_deriv_km_s2 as f64,
+                        m.zn_second_deriv_km_s2 as f64,
+                    ],
+                    health: m.glo_eph_health_flag,
+                    age_days: m.en_d,
+                }));
+            }
+            _ => return None,
+        };
+        Some(Ephemeris::Kepler(eph))
+    }
+    /// RTCM message of this ephemeris, the inverse of `from_message`
+    pub fn to_message(&self) -> Option<Message> {
+        let (message, fields) = match self {
+            Ephemeris::Kepler(eph) => eph.message_fields(),
+            Ephemeris::Glonass(eph) => eph.message_fields(),
+        };
+        let mut value = Map::new();
+        value.insert(message.into(), Value::Object(fields));
+        serde_json::from_value(Value::Object(value)).ok()
+    }
+    pub fn gnss(&self) -> Gnss {
+        match self {
+            Ephemeris::Kepler(eph) => eph.gnss,
+            Ephemeris::Glonass(_) => Gnss::Glonass,
+        }
+    }
+    pub fn prn(&self) -> u8 {
+        match self {
+            Ephemeris::Kepler(eph) => eph.prn,
+            Ephemeris::Glonass(eph) => eph.prn,
+        }
+    }
+    /// IODE, IODnav or AODE, and the index of the 15 minute interval tb
+    /// for GLONASS
+    pub fn iode(&self) -> u32 {
+        match self {
+            Ephemeris::Kepler(eph) => eph.iode,
+            Ephemeris::Glonass(eph) => {
+                let tb = eph.toe_utc + Duration::try_hours(3).unwrap();
+                tb.and_utc().timestamp().rem_euclid(86400) as u32 / 900
+            }
+        }
+    }
+    /// Reference epoch and issue of data identifying this ephemeris, used
+    /// to discard repetitions of the same ephemeris
+    pub fn key(&self) -> (Gnss, u8, NaiveDateTime, u32) {
+        match self {
+            Ephemeris::Kepler(eph) => (
+                eph.gnss,
+                eph.prn,
+                eph.toc(),
+                eph.iode | (eph.data_sources << 16),
+            ),
+            Ephemeris::Glonass(eph) => (Gnss::Glonass, eph.prn, eph.toe_utc, 0),
+        }
+    }
+}
+
+impl KeplerEphemeris {
+    /// Message name and fields of the serde representation of the RTCM message
+    fn message_fields(&self) -> (&'static str, Map<String, Value>) {
+        let mut fields = Map::new();
+        let (message, prefix, week) = match self.gnss {
+            Gnss::Galileo if self.data_sources & 2 != 0 => (
+                "Msg1045",
+                "gal",
+                (self.week - GST_WEEK_OFFSET).rem_euclid(4096),
+            ),
+            Gnss::Galileo => (
+                "Msg1046",
+                "gal",
+                (self.week - GST_WEEK_OFFSET).rem_euclid(4096),
+            ),
+            Gnss::Beidou => ("Msg1042", "bds", self.week.rem_euclid(8192)),
+            Gnss::Qzss => ("Msg1044", "qzss", self.week.rem_euclid(1024)),
+            _ => ("Msg1019", "gps", self.week.rem_euclid(1024)),
+        };
+        fields.insert(format!("{}_satellite_id", prefix), json!(self.prn));
+        fields.insert(format!("{}_week_number", prefix), json!(week));
+        fields.insert("toc_s".into(), json!(self.toc_s));
+        fields.insert("toe_s".into(), json!(self.toe_s));
+        fields.insert("crs_m".into(), json!(self.crs));
+        fields.insert("crc_m".into(), json!(self.crc));
+        fields.insert("cus_rad".into(), json!(self.cus));
+        fields.insert("cuc_rad".into(), json!(self.cuc));
+        fields.insert("cis_rad".into(), json!(self.cis));
+        fields.insert("cic_rad".into(), json!(self.cic));
+        fields.insert("delta_n_sc_s".into(), json!(self.delta_n / PI));
+        fields.insert("m0_sc".into(), json!(self.m0 / PI));
+        fields.insert("eccentricity".into(), json!(self.e));
+        fields.insert("sqrt_a_sqrt_m".into(), json!(self.sqrt_a));
+        fields.insert("omega0_sc".into(), json!(self.omega0 / PI));
+        fields.insert("i0_sc".into(), json!(self.i0 / PI));
+        fields.insert("omega_sc".into(), json!(self.omega / PI));
+        fields.insert("omegadot_sc_s".into(), json!(self.omega_dot / PI));
+        fields.insert("idot_sc_s".into(), json!(self.idot / PI));
+        match self.gnss {
+            Gnss::Beidou => {
+                fields.insert("a0_s".into(), json!(self.af0));
+                fields.insert("a1_s_s".into(), json!(self.af1));
+                fields.insert("a2_s_s2".into(), json!(self.af2));
+                fields.insert("ura_index".into(), json!(self.accuracy_index));
+                fields.insert("aode".into(), json!(self.iode));
+                fields.insert("aodc".into(), json!(self.iodc));
+                fields.insert("tgd1_s".into(), json!(self.tgd[0]));
+                fields.insert("tgd2_s".into(), json!(self.tgd[1]));
+                fields.insert("sv_health_flag".into(), json!(self.health & 1));
+            }
+            Gnss::Galileo => {
+                fields.insert("af0_s".into(), json!(self.af0));
+                fields.insert("af1_s_s".into(), json!(self.af1));
+                fields.insert("af2_s_s2".into(), json!(self.af2));
+                fields.insert("iodnav".into(), json!(self.iode));
+                fields.insert("bgd_e1_e5a_s".into(), json!(self.tgd[0]));
+                if message == "Msg1045" {
+                    fields.insert("sisa_e1_e5a_index".into(), json!(self.accuracy_index));
+                    fields.insert("e5a_sig_health_ind".into(), json!((self.health >> 4) & 3));
+                    fields.insert(
+                        "e5a_data_validity_flag".into(),
+                        json!((self.health >> 3) & 1),
+                    );
+                    fields.insert("reserved_489_7".into(), json!(0));
+                } else {
+                    fields.insert("sisa_e1_e5b_index".into(), json!(self.accuracy_index));
+                    fields.insert("bgd_e1_e5b_s".into(), json!(self.tgd[1]));
+                    fields.insert("e5b_sig_health_ind".into(), json!((self.health >> 7) & 3));
+                    fields.insert(
+                        "e5b_data_validity_flag".into(),
+                        json!((self.health >> 6) & 1),
+                    );
+                    fields.insert("e1_b_sig_health_ind".into(), json!((self.health >> 1) & 3));
+                    fields.insert("e1_b_data_validity_flag".into(), json!(self.health & 1));
+                    fields.insert("reserved_502_2".into(), json!(0));
+                }
+            }
+            _ => {
+                fields.insert("af0_s".into(), json!(self.af0));
+                fields.insert("af1_s_s".into(), json!(self.af1));
+                fields.insert("af2_s_s2".into(), json!(self.af2));
+                fields.insert("ura_index".into(), json!(self.accuracy_index));
+                fields.insert("iode".into(), json!(self.iode));
+                fields.insert("iodc".into(), json!(self.iodc));
+                fields.insert("tgd_s".into(), json!(self.tgd[0]));
+                fields.insert("sv_health_ind".into(), json!(self.health));
+                fields.insert("code_on_l2_ind".into(), json!(self.codes_on_l2));
+                fields.insert("fit_interval_ind".into(), json!(self.fit_interval_flag));
+                if self.gnss == Gnss::Gps {
+                    fields.insert("l2_p_data_flag".into(), json!(self.l2p_flag));
+                }
+            }
+        }
+        (message, fields)
+    }
+}
+
+impl GlonassEphemeris {
+    /// Message name and fields of the serde representation of the RTCM 1020
+    /// message, almanac and GLONASS-M fields not given in RINEX are zero
+    fn message_fields(&self) -> (&'static str, Map<String, Value>) {
+        let tb = self.toe_utc + Duration::try_hours(3).unwrap();
+        let tb_min = tb.num_seconds_from_midnight() / 60;
+        // first year of the four-year interval and day within it, Moscow time
+        let n4_year = 1996 + (tb.year() - 1996).div_euclid(4) * 4;
+        let nt_d = (tb.date() - NaiveDate::from_ymd_opt(n4_year, 1, 1).unwrap()).num_days() + 1;
+        let [x, y, z] = self.position;
+        let [vx, vy, vz] = self.velocity;
+        let [ax, ay, az] = self.acceleration;
+        let fields = json!({
+            "glo_satellite_id": self.prn,
+            "glo_satellite_freq_chan_number": self.fcn,
+            "glo_alm_health_flag": 0,
+            "glo_alm_health_avail_flag": 0,
+            "p1_ind": 0,
+            "tk_h": self.tk_s / 3600,
+            "tk_min": self.tk_s % 3600 / 60,
+            "tk_s": self.tk_s % 60 / 30 * 30,
+            "glo_eph_health_flag": self.health,
+            "p2_flag": (tb_min / 30) % 2,
+            "tb_min": tb_min,
+            "xn_first_deriv_km_s": vx,
+            "xn_km": x,
+            "xn_second_deriv_km_s2": ax,
+            "yn_first_deriv_km_s": vy,
+            "yn_km": y,
+            "yn_second_deriv_km_s2": ay,
+            "zn_first_deriv_km_s": vz,
+            "zn_km": z,
+            "zn_second_deriv_km_s2": az,
+            "p3_flag": 0,
+            "gamma_n": self.gamma_n,
+            "glo_m_p_ind": 0,
+            "glo_m_3str_ln_flag": 0,
+            "tau_n_s": self.tau_n,
+            "glo_m_delta_tau_n_s": 0.0,
+            "en_d": self.age_days,
+            "glo_m_p4_flag": 0,
+            "glo_m_ft_ind": 0,
+            "glo_m_nt_d": nt_d,
+            "glo_m_m_ind": 0,
+            "additional_data_flag": 0,
+            "na_d": 0,
+            "tau_c_s": 0.0,
+            "glo_m_n4_year": n4_year,
+            "glo_m_tau_gps_s": 0.0,
+            "glo_m_5str_ln_flag": 0,
+            "reserved_353_7": 0,
+        });
+        match fields {
+            Value::Object(fields) => ("Msg1020", fields),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// URA (GPS, QZSS, BeiDou) or SISA (Galileo) index of an accuracy in meters
+pub fn accuracy_index(gnss: Gnss, accuracy_m: f64) -> u8 {
+    match gnss {
+        Gnss::Galileo => match accuracy_m {
+            a if a < 0.0 => 255,
+            a if a < 0.5 => (a / 0.01).round() as u8,
+            a if a < 1.0 => 50 + ((a - 0.5) / 0.02).round() as u8,
+            a if a < 2.0 => 75 + ((a - 1.0) / 0.04).round() as u8,
+            a if a <= 6.0 => 100 + ((a - 2.0) / 0.16).round() as u8,
+            _ => 255,
+        },
+        _ => GPS_URA_M
+            .iter()
+            .position(|ura| *ura >= accuracy_m)
+            .unwrap_or(15) as u8,
+    }
+}
+
+/// Start of week `week` counted from the GPS epoch
+pub fn week_start(week: i64) -> NaiveDateTime {
+    gps_epoch() + Duration::try_weeks(week).unwrap()
+}
+
+/// Full week number of a week number truncated to `modulo`, nearest `reference_week`
+fn resolve_week(week: i64, modulo: i64, reference_week: i64) -> i64 {
+    week + modulo * ((reference_week - week) as f64 / modulo as f64).round() as i64
+}
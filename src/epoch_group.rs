@@ -0,0 +1,103 @@
+use crate::decimate::observation_epoch;
+use crate::gnss_time::{gps_epoch, TimeResolver};
+use chrono::{Duration, NaiveDate};
+use rtcm_rs::Message;
+use serde_json::{json, Value};
+use std::time::Instant;
+
+struct OpenEpoch {
+    /// GPS time in ms since the GPS epoch
+    gps_ms: i64,
+    opened: Instant,
+    observations: Vec<Value>,
+}
+
+/// Groups the json output per GNSS epoch: one `{"Epoch":{...}}` object with
+/// the GPS time of the epoch, the observation messages (MSM and 1001-1004,
+/// 1009-1012) of the epoch and the other messages received since the
+/// previous epoch.
+///
+/// An epoch is complete at the first observation message without the
+/// multiple message bit (MSM) or synchronous GNSS flag (legacy) set. It is
+/// emitted as incomplete when an observation message of another epoch arrives,
+/// when the timeout has passed since its first observation message, or at the
+/// end of the stream. Without further messages, the timeout is detected by
+/// `poll`.
+pub struct EpochGrouper {
+    time_resolver: TimeResolver,
+    timeout: std::time::Duration,
+    epoch: Option<OpenEpoch>,
+    messages: Vec<Value>,
+}
+
+impl EpochGrouper {
+    pub fn new(timeout: std::time::Duration, date: Option<NaiveDate>) -> Self {
+        EpochGrouper {
+            time_resolver: TimeResolver::from_date_or_now(date),
+            timeout,
+            epoch: None,
+            messages: Vec::new(),
+        }
+    }
+    /// Adds `msg` with its json output `json_msg`, returns the epochs which
+    /// have been completed or timed out
+    pub fn push(&mut self, msg: &Message, json_msg: Value) -> Vec<Value> {
+        let mut epochs = Vec::from_iter(self.poll());
+        let Some(obs_epoch) = observation_epoch(msg) else {
+            self.messages.push(json_msg);
+            return epochs;
+        };
+        let gps_ms = obs_epoch.gps_ms(&mut self.time_resolver);
+        if self
+            .epoch
+            .as_ref()
+            .is_some_and(|epoch| epoch.gps_ms != gps_ms)
+        {
+            epochs.push(self.emit(false));
+        }
+        self.epoch
+            .get_or_insert_with(|| OpenEpoch {
+                gps_ms,
+                opened: Instant::now(),
+                observations: Vec::new(),
+            })
+            .observations
+            .push(json_msg);
+        if !obs_epoch.multiple_message {
+            epochs.push(self.emit(true));
+        }
+        epochs
+    }
+    /// Emits the open epoch as incomplete if the timeout has passed, to be
+    /// called also while no messages arrive
+    pub fn poll(&mut self) -> Option<Value> {
+        self.epoch
+            .as_ref()
+            .is_some_and(|epoch| epoch.opened.elapsed() >= self.timeout)
+            .then(|| self.emit(false))
+    }
+    /// Emits the open epoch, or the remaining other messages, at the end of
+    /// the stream
+    pub fn finish(&mut self) -> Option<Value> {
+        if self.epoch.is_none() && self.messages.is_empty() {
+            return None;
+        }
+        Some(self.emit(false))
+    }
+    fn emit(&mut self, complete: bool) -> Value {
+        let epoch = self.epoch.take();
+        let gps_time = epoch.as_ref().map(|epoch| {
+            (gps_epoch() + Duration::try_milliseconds(epoch.gps_ms).unwrap())
+                .format("%Y-%m-%dT%H:%M:%S%.3f")
+                .to_string()
+        });
+        json!({
+            "Epoch": {
+                "gps_time": gps_time,
+                "complete": complete,
+                "observations": epoch.map_or_else(Vec::new, |epoch| epoch.observations),
+                "messages": std::mem::take(&mut self.messages),
+            }
+        })
+    }
+}
@@ -0,0 +1,184 @@
+use crate::msm::Gnss;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+
+const WEEK_MS: i64 = 7 * DAY_MS;
+const DAY_MS: i64 = 86_400_000;
+/// BeiDou time (BDT) is behind GPS time by 14 seconds
+const BDT_OFFSET_MS: i64 = 14_000;
+/// GLONASS time is Moscow time, i.e. UTC + 3 hours
+const GLONASS_OFFSET_MS: i64 = 3 * 3_600_000;
+
+/// UTC dates from which GPS time is ahead of UTC by the given number of seconds
+const LEAP_SECONDS: [(i32, u32, u32, i64); 18] = [
+    (1981, 7, 1, 1),
+    (1982, 7, 1, 2),
+    (1983, 7, 1, 3),
+    (1985, 7, 1, 4),
+    (1988, 1, 1, 5),
+    (1990, 1, 1, 6),
+    (1991, 1, 1, 7),
+    (1992, 7, 1, 8),
+    (1993, 7, 1, 9),
+    (1994, 7, 1, 10),
+    (1996, 1, 1, 11),
+    (1997, 7, 1, 12),
+    (1999, 1, 1, 13),
+    (2006, 1, 1, 14),
+    (2009, 1, 1, 15),
+    (2012, 7, 1, 16),
+    (2015, 7, 1, 17),
+    (2017, 1, 1, 18),
+];
+
+fn seconds(s: i64) -> Duration {
+    Duration::try_seconds(s).unwrap()
+}
+fn milliseconds(ms: i64) -> Duration {
+    Duration::try_milliseconds(ms).unwrap()
+}
+
+pub fn gps_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1980, 1, 6)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+}
+
+/// Leap seconds (GPS - UTC) in effect at the UTC time `utc`
+pub fn leap_seconds_utc(utc: NaiveDateTime) -> i64 {
+    LEAP_SECONDS
+        .iter()
+        .rev()
+        .find(|(y, m, d, _)| utc.date() >= NaiveDate::from_ymd_opt(*y, *m, *d).unwrap())
+        .map(|(_, _, _, n)| *n)
+        .unwrap_or(0)
+}
+
+/// Leap seconds (GPS - UTC) in effect at the GPS time `gps`
+pub fn leap_seconds_gps(gps: NaiveDateTime) -> i64 {
+    LEAP_SECONDS
+        .iter()
+        .rev()
+        .find(|(y, m, d, n)| {
+            gps >= NaiveDate::from_ymd_opt(*y, *m, *d)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+                + seconds(*n)
+        })
+        .map(|(_, _, _, n)| *n)
+        .unwrap_or(0)
+}
+
+pub fn utc_to_gps(utc: NaiveDateTime) -> NaiveDateTime {
+    utc + seconds(leap_seconds_utc(utc))
+}
+
+pub fn gps_to_utc(gps: NaiveDateTime) -> NaiveDateTime {
+    gps - seconds(leap_seconds_gps(gps))
+}
+
+/// MSM epoch time field and GLONASS day of week of the GPS time `gps`, the
+/// inverse of `TimeResolver::resolve_msm`
+pub fn msm_epoch_time(gnss: Gnss, gps: NaiveDateTime) -> (u32, Option<u8>) {
+    match gnss {
+        Gnss::Glonass => {
+            let moscow = gps_to_utc(gps) + milliseconds(GLONASS_OFFSET_MS);
+            let tod_ms = moscow.num_seconds_from_midnight() as i64 * 1000
+                + moscow.nanosecond() as i64 / 1_000_000;
+            (
+                tod_ms as u32,
+                Some(moscow.weekday().num_days_from_sunday() as u8),
+            )
+        }
+        Gnss::Beidou => (
+            gps_week_tow(gps - milliseconds(BDT_OFFSET_MS)).1 as u32,
+            None,
+        ),
+        _ => (gps_week_tow(gps).1 as u32, None),
+    }
+}
+
+/// GPS week number and time of week (ms)
+pub fn gps_week_tow(gps: NaiveDateTime) -> (i64, i64) {
+    let ms = (gps - gps_epoch()).num_milliseconds();
+    (ms.div_euclid(WEEK_MS), ms.rem_euclid(WEEK_MS))
+}
+
+/// Resolves GNSS epoch times, which are only given modulo a week or a day,
+/// to full GPS time. Each resolved time is used as reference for the next
+/// one, so a stream spanning several weeks is resolved correctly as long as
+/// the initial reference is within half a week of the first epoch.
+pub struct TimeResolver {
+    /// Reference time as milliseconds since the GPS epoch (GPS time scale)
+    reference_ms: i64,
+}
+
+impl TimeResolver {
+    pub fn new(reference_gps: NaiveDateTime) -> Self {
+        TimeResolver {
+            reference_ms: (reference_gps - gps_epoch()).num_milliseconds(),
+        }
+    }
+    /// Reference at noon of `date` if given, otherwise the system clock
+    pub fn from_date_or_now(date: Option<NaiveDate>) -> Self {
+        let utc = match date {
+            Some(date) => date.and_hms_opt(12, 0, 0).unwrap(),
+            None => Utc::now().naive_utc(),
+        };
+        TimeResolver::new(utc_to_gps(utc))
+    }
+    /// Replaces the reference, e.g. by the receive time of a message
+    pub fn set_reference(&mut self, reference_gps: NaiveDateTime) {
+        self.reference_ms = (reference_gps - gps_epoch()).num_milliseconds();
+    }
+    /// Current reference, i.e. the last resolved time
+    pub fn reference(&self) -> NaiveDateTime {
+        gps_epoch() + milliseconds(self.reference_ms)
+    }
+    /// GPS time of an MSM epoch time field
+    pub fn resolve_msm(
+        &mut self,
+        gnss: Gnss,
+        epoch_time_ms: u32,
+        glo_day_of_week: Option<u8>,
+    ) -> NaiveDateTime {
+        match gnss {
+            Gnss::Glonass => self.resolve_glonass(epoch_time_ms, glo_day_of_week),
+            Gnss::Beidou => self.resolve_tow(epoch_time_ms as i64 + BDT_OFFSET_MS),
+            _ => self.resolve_tow(epoch_time_ms as i64),
+        }
+    }
+    /// GPS time of a GPS time of week (ms)
+    pub fn resolve_tow(&mut self, tow_ms: i64) -> NaiveDateTime {
+        self.resolve_periodic(tow_ms, WEEK_MS, 0)
+    }
+    /// GPS time of a GLONASS time of day (ms, Moscow time) and optional day
+    /// of week (0 = Sunday, 7 = unknown)
+    pub fn resolve_glonass(&mut self, tod_ms: u32, day_of_week: Option<u8>) -> NaiveDateTime {
+        let leap_ms = leap_seconds_gps(self.reference()) * 1000;
+        // resolve in the UTC time scale, whose weeks are aligned with GPS weeks
+        let utc_ms = tod_ms as i64 - GLONASS_OFFSET_MS;
+        let utc = match day_of_week {
+            Some(day) if day < 7 => {
+                self.resolve_periodic(day as i64 * DAY_MS + utc_ms, WEEK_MS, -leap_ms)
+            }
+            _ => self.resolve_periodic(utc_ms, DAY_MS, -leap_ms),
+        };
+        utc + milliseconds(leap_ms)
+    }
+    /// Resolves `value_ms`, given modulo `period_ms` in a time scale offset
+    /// by `offset_ms` from GPS time, to the occurrence nearest the reference
+    fn resolve_periodic(&mut self, value_ms: i64, period_ms: i64, offset_ms: i64) -> NaiveDateTime {
+        let reference_ms = self.reference_ms + offset_ms;
+        let mut ms =
+            reference_ms - reference_ms.rem_euclid(period_ms) + value_ms.rem_euclid(period_ms);
+        if ms - reference_ms > period_ms / 2 {
+            ms -= period_ms;
+        } else if reference_ms - ms > period_ms / 2 {
+            ms += period_ms;
+        }
+        self.reference_ms = ms - offset_ms;
+        gps_epoch() + milliseconds(ms)
+    }
+}
@@ -0,0 +1,287 @@
+use crate::json_stream::{message_number, split_values};
+use crate::rules::MessageSet;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Clients with more pending output than this are disconnected
+const MAX_PENDING: usize = 1 << 20;
+/// Time for clients to send the request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
+    stream: TcpStream,
+    /// Message types requested with `?messages=<messages>`, all if none
+    messages: Option<MessageSet>,
+    /// Server-Sent Events, else chunked ndjson
+    event_stream: bool,
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl Client {
+    fn send(&mut self, json: &str) {
+        if self.event_stream {
+            self.pending
+                .extend_from_slice(format!("data: {}\n\n", json).as_bytes());
+        } else {
+            self.pending
+                .extend_from_slice(format!("{:x}\r\n{}\n\r\n", json.len() + 1, json).as_bytes());
+        }
+        self.flush_pending();
+    }
+    fn flush_pending(&mut self) {
+        while !self.pending.is_empty() && !self.closed {
+            match self.stream.write(&self.pending) {
+                Ok(0) => self.closed = true,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => self.closed = true,
+            }
+        }
+        if self.pending.len() > MAX_PENDING {
+            self.closed = true;
+        }
+    }
+    /// Detects clients which have closed the connection
+    fn receive(&mut self) {
+        let mut buf = [0; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.closed = true;
+                    return;
+                }
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => {
+                    self.closed = true;
+                    return;
+                }
+            }
+        }
+    }
+    fn wants(&self, number: Option<u16>) -> bool {
+        match (self.messages.as_ref(), number) {
+            (Some(messages), Some(number)) => messages.contains(number),
+            _ => true,
+        }
+    }
+}
+
+/// Last message of each message type
+type Latest = Arc<Mutex<BTreeMap<u16, Value>>>;
+
+/// HTTP output of the json messages, for clients like curl and browsers:
+///
+/// * `GET /stream` streams the messages as Server-Sent Events if the client
+///   accepts `text/event-stream`, else as chunked ndjson
+/// * `GET /latest` returns the last message of each message type as a json
+///   object keyed by message number
+///
+/// Both take an optional query `?messages=<messages>`, e.g.
+/// `/latest?messages=1005,1033`.
+pub struct HttpServer {
+    new_clients: Receiver<Client>,
+    clients: Vec<Client>,
+    latest: Latest,
+    buffer: Vec<u8>,
+}
+
+impl HttpServer {
+    pub fn new(host: String, port: u16) -> Self {
+        let listener =
+            TcpListener::bind(format!("{}:{}", host, port)).expect("Could not bind to port");
+        let (sender, new_clients) = channel();
+        let latest = Latest::default();
+        let server_latest = latest.clone();
+        let _ = std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a slow client does not hold up the requests of others
+                let (latest, sender) = (server_latest.clone(), sender.clone());
+                std::thread::spawn(move || respond(stream, &latest, &sender));
+            }
+        });
+        HttpServer {
+            new_clients,
+            clients: Vec::new(),
+            latest,
+            buffer: Vec::new(),
+        }
+    }
+    fn update_clients(&mut self) {
+        self.clients.extend(self.new_clients.try_iter());
+        for client in self.clients.iter_mut() {
+            client.receive();
+            client.flush_pending();
+        }
+        self.clients.retain(|client| !client.closed);
+    }
+}
+
+impl Write for HttpServer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update_clients();
+        self.buffer.extend_from_slice(buf);
+        let buffer = std::mem::take(&mut self.buffer);
+        // json values are sent compact, also if written pretty printed
+        let (values, consumed) = split_values(&buffer);
+        for value in values {
+            let number = message_number(&value);
+            let json = value.to_string();
+            for client in self.clients.iter_mut() {
+                if client.wants(number) {
+                    client.send(&json);
+                }
+            }
+            if let (Some(number), Ok(mut latest)) = (number, self.latest.lock()) {
+                latest.insert(number, value);
+            }
+        }
+        self.buffer = buffer[consumed..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.update_clients();
+        Ok(())
+    }
+}
+
+/// Reads the request of a new connection and answers `/latest` directly, or
+/// sends the response header of `/stream` and hands the client over to the
+/// output
+fn respond(
+    mut stream: TcpStream,
+    latest: &Latest,
+    clients: &Sender<Client>,
+) -> std::io::Result<()> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") && !request.ends_with(b"\n\n") {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let n = stream.read(&mut buf)?;
+        if n == 0 || request.len() > 8192 {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let (method, target) = (request_line.next(), request_line.next().unwrap_or(""));
+    let event_stream = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("accept") && value.contains("text/event-stream")
+        })
+    });
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let messages = match query
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("messages="))
+        .map(|messages| percent_decode(messages).parse::<MessageSet>())
+    {
+        Some(Ok(messages)) => Some(messages),
+        Some(Err(e)) => return error(&mut stream, "400 Bad Request", &e.0),
+        None => None,
+    };
+    if method != Some("GET") {
+        return error(
+            &mut stream,
+            "405 Method Not Allowed",
+            "only GET is supported",
+        );
+    }
+    match path {
+        "/stream" => {
+            let content_type = if event_stream {
+                "text/event-stream"
+            } else {
+                "application/x-ndjson\r\nTransfer-Encoding: chunked"
+            };
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\n\r\n",
+                content_type
+            )?;
+            stream.set_nonblocking(true)?;
+            stream.set_write_timeout(None)?;
+            let _ = clients.send(Client {
+                stream,
+                messages,
+                event_stream,
+                pending: Vec::new(),
+                closed: false,
+            });
+            Ok(())
+        }
+        "/latest" => {
+            let body = match latest.lock() {
+                Ok(latest) => Value::Object(
+                    latest
+                        .iter()
+                        .filter(|(number, _)| {
+                            messages.as_ref().is_none_or(|m| m.contains(**number))
+                        })
+                        .map(|(number, value)| (number.to_string(), value.clone()))
+                        .collect::<Map<String, Value>>(),
+                )
+                .to_string(),
+                Err(_) => "{}".to_string(),
+            };
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => error(&mut stream, "404 Not Found", "expected /stream or /latest"),
+    }
+}
+
+fn error(stream: &mut TcpStream, status: &str, message: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        message.len() + 1,
+        message
+    )
+}
+
+/// Decodes `%xx` escapes of a query parameter, e.g. `1005%2C1033`
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
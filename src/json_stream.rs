@@ -0,0 +1,40 @@
+use serde_json::Value;
+
+/// Complete json values at the start of `buffer`, e.g. the json output written
+/// to a server output in pieces, and the number of bytes they take. Lines
+/// which are not valid json are skipped.
+pub fn split_values(buffer: &[u8]) -> (Vec<Value>, usize) {
+    let mut stream = serde_json::Deserializer::from_slice(buffer).into_iter::<Value>();
+    let mut values = Vec::new();
+    let mut consumed = 0;
+    loop {
+        match stream.next() {
+            Some(Ok(value)) => {
+                consumed = stream.byte_offset();
+                values.push(value);
+            }
+            Some(Err(e)) if e.is_eof() => break,
+            Some(Err(_)) => {
+                consumed = buffer[consumed..]
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .map_or(buffer.len(), |end| consumed + end + 1);
+                break;
+            }
+            None => {
+                consumed = buffer.len();
+                break;
+            }
+        }
+    }
+    (values, consumed)
+}
+
+/// Message number of a json message, `{"Msg1077":{...}}` or decoded MSM
+pub fn message_number(value: &Value) -> Option<u16> {
+    let (key, fields) = value.as_object()?.iter().next()?;
+    match key.strip_prefix("Msg") {
+        Some(number) => number.parse().ok(),
+        None => fields.get("message_number")?.as_u64().map(|n| n as u16),
+    }
+}
@@ -0,0 +1,139 @@
+use crate::decimate::observation_epoch;
+use crate::gnss_time::{utc_to_gps, TimeResolver};
+use chrono::{NaiveDateTime, Utc};
+use rtcm_rs::Message;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Number of latest latencies per message type and station kept for the
+/// percentiles
+const WINDOW: usize = 10_000;
+
+#[derive(Default)]
+struct Latencies {
+    count: u64,
+    sum_ms: i64,
+    min_ms: i64,
+    max_ms: i64,
+    window: VecDeque<i64>,
+}
+
+impl Latencies {
+    fn add(&mut self, latency_ms: i64) {
+        if self.count == 0 || latency_ms < self.min_ms {
+            self.min_ms = latency_ms;
+        }
+        if self.count == 0 || latency_ms > self.max_ms {
+            self.max_ms = latency_ms;
+        }
+        self.count += 1;
+        self.sum_ms += latency_ms;
+        if self.window.len() == WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(latency_ms);
+    }
+    /// Nearest rank percentile of the window
+    fn percentile(sorted: &[i64], p: f64) -> i64 {
+        let rank = ((p / 100.0 * sorted.len() as f64).ceil() as usize).max(1);
+        sorted[rank.min(sorted.len()) - 1]
+    }
+}
+
+pub struct LatencySample {
+    pub number: u16,
+    pub station: u16,
+    pub epoch_gps: NaiveDateTime,
+    pub latency_ms: i64,
+}
+
+/// Latency of observation messages (MSM and 1001-1004, 1009-1012), i.e. the
+/// time between their GNSS epoch and their reception, per message type and
+/// reference station.
+///
+/// Min, mean and max are over all messages, the percentiles over the latest
+/// 10000 messages of each message type and station.
+pub struct LatencyMonitor {
+    time_resolver: TimeResolver,
+    alert_ms: Option<i64>,
+    report_interval: Option<Duration>,
+    last_report: Instant,
+    latencies: BTreeMap<(u16, u16), Latencies>,
+}
+
+impl LatencyMonitor {
+    pub fn new(report_interval_s: Option<f64>, alert_ms: Option<f64>) -> Self {
+        LatencyMonitor {
+            time_resolver: TimeResolver::from_date_or_now(None),
+            alert_ms: alert_ms.map(|ms| ms.round() as i64),
+            report_interval: report_interval_s.map(Duration::from_secs_f64),
+            last_report: Instant::now(),
+            latencies: BTreeMap::new(),
+        }
+    }
+    /// Records the latency of `msg` if it is an observation message received now
+    pub fn record(&mut self, msg: &Message) -> Option<LatencySample> {
+        let epoch = observation_epoch(msg)?;
+        let received = utc_to_gps(Utc::now().naive_utc());
+        self.time_resolver.set_reference(received);
+        let gps =
+            self.time_resolver
+                .resolve_msm(epoch.gnss, epoch.epoch_time_ms, epoch.glo_day_of_week);
+        let sample = LatencySample {
+            number: msg.number()?,
+            station: epoch.reference_station_id,
+            epoch_gps: gps,
+            latency_ms: (received - gps).num_milliseconds(),
+        };
+        self.latencies
+            .entry((sample.number, sample.station))
+            .or_default()
+            .add(sample.latency_ms);
+        Some(sample)
+    }
+    /// Alert if the latency of `sample` exceeds the threshold
+    pub fn alert(&self, sample: &LatencySample) -> Option<String> {
+        match self.alert_ms {
+            Some(alert_ms) if sample.latency_ms > alert_ms => Some(format!(
+                "latency alert: message {} of station {} epoch {} received {} ms late",
+                sample.number,
+                sample.station,
+                sample.epoch_gps.format("%Y-%m-%dT%H:%M:%S%.3f"),
+                sample.latency_ms
+            )),
+            _ => None,
+        }
+    }
+    /// The report if the report interval has passed
+    pub fn periodic_report(&mut self) -> Option<String> {
+        if self.last_report.elapsed() < self.report_interval? {
+            return None;
+        }
+        self.last_report = Instant::now();
+        Some(self.report())
+    }
+    /// Latency statistics (ms) per message type and station
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "{:>8} {:>8} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}\n",
+            "message", "station", "count", "min", "mean", "max", "p50", "p95", "p99"
+        );
+        for ((number, station), latencies) in self.latencies.iter() {
+            let mut sorted: Vec<i64> = latencies.window.iter().copied().collect();
+            sorted.sort_unstable();
+            report.push_str(&format!(
+                "{:>8} {:>8} {:>10} {:>8} {:>8.1} {:>8} {:>8} {:>8} {:>8}\n",
+                number,
+                station,
+                latencies.count,
+                latencies.min_ms,
+                latencies.sum_ms as f64 / latencies.count as f64,
+                latencies.max_ms,
+                Latencies::percentile(&sorted, 50.0),
+                Latencies::percentile(&sorted, 95.0),
+                Latencies::percentile(&sorted, 99.0)
+            ));
+        }
+        report
+    }
+}
@@ -0,0 +1,332 @@
+use crate::msm::{Gnss, MsmObservations, PhaseOffsets, SignalObservation, CLIGHT, RANGE_MS};
+use rtcm_rs::Message;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// Range of the phaserange - L1 pseudorange fields (DF012, DF018, DF042, DF048)
+const PHASE_DIFF_LIMIT_M: f64 = 262.1435;
+/// Range of the L2 - L1 pseudorange difference fields (DF017, DF047)
+const PSEUDORANGE_DIFF_LIMIT_M: f64 = 163.82;
+/// Resolution of the L1 pseudorange fields (DF011, DF041)
+const PSEUDORANGE_RESOLUTION_M: f64 = 0.02;
+/// Maximum number of satellites of a legacy observation message
+const MAX_SATELLITES: usize = 31;
+/// Signal attributes of the L2 code indicators (DF016) of GPS, DF016 value 2
+/// (cross-correlated P(Y)) has no MSM signal and is treated as semi-codeless
+const GPS_L2_ATTRIBUTES: [char; 4] = ['X', 'P', 'W', 'W'];
+/// Signal preference for the L1 and L2 observables of legacy messages
+const GPS_L1_PREFERENCE: [char; 3] = ['C', 'P', 'W'];
+const GPS_L2_PREFERENCE: [char; 6] = ['W', 'P', 'X', 'L', 'S', 'C'];
+const GLONASS_L1_PREFERENCE: [char; 2] = ['C', 'P'];
+const GLONASS_L2_PREFERENCE: [char; 2] = ['P', 'C'];
+
+/// Observables of a GPS (1002, 1004) or GLONASS (1010, 1012) legacy observation
+/// message, as MSM4 observables. Messages without the integer millisecond
+/// ambiguity (1001, 1003, 1009, 1011) are not decoded.
+pub fn decode_legacy(msg: &Message) -> Option<MsmObservations> {
+    let gnss = match msg.number()? {
+        1002 | 1004 => Gnss::Gps,
+        1010 | 1012 => Gnss::Glonass,
+        _ => return None,
+    };
+    let value = serde_json::to_value(msg).ok()?;
+    let fields = value.get(format!("Msg{}", msg.number()?))?;
+    let epoch_field = match gnss {
+        Gnss::Glonass => "glo_epoch_time_ms",
+        _ => "gps_epoch_time_ms",
+    };
+    let mut signals = Vec::new();
+    for sat in fields.get("satellites")?.as_array()? {
+        let (satellite_id, fcn, l1_attributes, l2_attributes) = match gnss {
+            Gnss::Glonass => (
+                get_u64(sat, "glo_satellite_id")? as u8,
+                sat.get("glo_satellite_freq_chan_number")
+                    .and_then(Value::as_i64)
+                    .map(|fcn| fcn as i8),
+                ['C', 'P'],
+                ['C', 'P', 'C', 'P'],
+            ),
+            _ => (
+                get_u64(sat, "gps_satellite_id")? as u8,
+                None,
+                ['C', 'P'],
+                GPS_L2_ATTRIBUTES,
+            ),
+        };
+        let code_prefix = match gnss {
+            Gnss::Glonass => "glo",
+            _ => "gps",
+        };
+        let Some(l1_pseudorange) = get_f64(sat, "l1_pseudorange_m")
+            .zip(get_f64(sat, "l1_pseudorange_amb_m"))
+            .map(|(pr, ambiguity)| pr + ambiguity)
+        else {
+            continue;
+        };
+        let l1_code = get_u64(sat, &format!("{}_l1_code_ind", code_prefix)).unwrap_or(0);
+        let l1 = SignalObservation {
+            satellite_id,
+            band: 1,
+            attribute: l1_attributes[l1_code as usize & 1],
+            glonass_fcn: fcn,
+            pseudorange_m: Some(l1_pseudorange),
+            carrier_phase_cycles: get_f64(sat, "l1_phase_pseudorange_diff_m")
+                .zip(gnss.frequency(1, fcn))
+                .map(|(diff, freq)| (l1_pseudorange + diff) * freq / CLIGHT),
+            doppler_hz: None,
+            cnr_dbhz: get_f64(sat, "l1_cnr_dbhz"),
+            lock_time_ms: get_u64(sat, "l1_lock_time_index")
+                .map(|ind| lock_time_s(ind as u8) * 1000),
+            half_cycle_ambiguity: None,
+        };
+        signals.push(l1);
+        if sat.get("l2_lock_time_index").is_none() {
+            continue;
+        }
+        let pseudorange =
+            get_f64(sat, "l2_l1_pseudorange_diff_m").map(|diff| l1_pseudorange + diff);
+        let carrier_phase_cycles = get_f64(sat, "l2_phase_l1_pseudorange_diff_m")
+            .zip(gnss.frequency(2, fcn))
+            .map(|(diff, freq)| (l1_pseudorange + diff) * freq / CLIGHT);
+        if pseudorange.is_none() && carrier_phase_cycles.is_none() {
+            continue;
+        }
+        let l2_code = get_u64(sat, &format!("{}_l2_code_ind", code_prefix)).unwrap_or(0);
+        signals.push(SignalObservation {
+            satellite_id,
+            band: 2,
+            attribute: l2_attributes[l2_code as usize & 3],
+            glonass_fcn: fcn,
+            pseudorange_m: pseudorange,
+            carrier_phase_cycles,
+            doppler_hz: None,
+            cnr_dbhz: get_f64(sat, "l2_cnr_dbhz"),
+            lock_time_ms: get_u64(sat, "l2_lock_time_index")
+                .map(|ind| lock_time_s(ind as u8) * 1000),
+            half_cycle_ambiguity: None,
+        });
+    }
+    Some(MsmObservations {
+        gnss,
+        msm_type: 4,
+        reference_station_id: get_u64(fields, "reference_station_id")? as u16,
+        epoch_time_ms: get_u64(fields, epoch_field)? as u32,
+        glo_day_of_week: None,
+        multiple_message: get_u64(fields, "synchronous_gnss_msg_flag") == Some(1),
+        issue_of_data_station: None,
+        clock_steering_ind: 0,
+        external_clock_ind: 0,
+        smoothing_type_ind: get_u64(fields, "divergence_free_smoothing_flag").unwrap_or(0) as u8,
+        smoothing_interval_index: get_u64(fields, "smoothing_interval_index").unwrap_or(0) as u8,
+        signals,
+    })
+}
+
+/// Encodes GPS and GLONASS observables into 1004 and 1012 messages, with one
+/// L1 and one L2 signal per satellite chosen by preference. Carrier phases
+/// are shifted to fit the range relative to the L1 pseudorange (see `PhaseOffsets`).
+#[derive(Default)]
+pub struct LegacyEncoder {
+    phase_offsets: PhaseOffsets,
+}
+
+impl LegacyEncoder {
+    pub fn new() -> Self {
+        LegacyEncoder::default()
+    }
+    /// Encodes `obs` as 1004 or 1012 messages of at most 31 satellites. Returns
+    /// no message for other constellations, or if the integer millisecond part
+    /// of the pseudoranges is unknown (MSM1-3).
+    pub fn encode(&mut self, obs: &MsmObservations) -> Vec<Message> {
+        if !obs.integer_ms_known() || !matches!(obs.gnss, Gnss::Gps | Gnss::Glonass) {
+            return Vec::new();
+        }
+        let mut satellites: BTreeMap<u8, Vec<&SignalObservation>> = BTreeMap::new();
+        for sig in obs.signals.iter() {
+            satellites.entry(sig.satellite_id).or_default().push(sig);
+        }
+        let satellite_data: Vec<Value> = satellites
+            .values()
+            .filter_map(|sigs| self.encode_satellite(obs.gnss, sigs))
+            .collect();
+        let count = satellite_data.len().div_ceil(MAX_SATELLITES);
+        satellite_data
+            .chunks(MAX_SATELLITES)
+            .enumerate()
+            .filter_map(|(i, chunk)| {
+                let (message, epoch_field) = match obs.gnss {
+                    Gnss::Glonass => ("Msg1012", "glo_epoch_time_ms"),
+                    _ => ("Msg1004", "gps_epoch_time_ms"),
+                };
+                let mut fields = Map::new();
+                fields.insert(
+                    "reference_station_id".into(),
+                    json!(obs.reference_station_id),
+                );
+                fields.insert(epoch_field.into(), json!(obs.epoch_time_ms));
+                fields.insert(
+                    "synchronous_gnss_msg_flag".into(),
+                    json!((obs.multiple_message || i + 1 < count) as u8),
+                );
+                fields.insert(
+                    "divergence_free_smoothing_flag".into(),
+                    json!(obs.smoothing_type_ind & 1),
+                );
+                fields.insert(
+                    "smoothing_interval_index".into(),
+                    json!(obs.smoothing_interval_index & 7),
+                );
+                fields.insert("satellites".into(), Value::Array(chunk.to_vec()));
+                let mut value = Map::new();
+                value.insert(message.into(), Value::Object(fields));
+                serde_json::from_value::<Message>(Value::Object(value)).ok()
+            })
+            .collect()
+    }
+    fn encode_satellite(&mut self, gnss: Gnss, sigs: &[&SignalObservation]) -> Option<Value> {
+        let (l1_preference, l2_preference, modulus): (&[char], &[char], f64) = match gnss {
+            Gnss::Glonass => (
+                &GLONASS_L1_PREFERENCE,
+                &GLONASS_L2_PREFERENCE,
+                2.0 * RANGE_MS,
+            ),
+            _ => (&GPS_L1_PREFERENCE, &GPS_L2_PREFERENCE, RANGE_MS),
+        };
+        let select = |band: u8, preference: &[char]| {
+            preference.iter().find_map(|attribute| {
+                sigs.iter()
+                    .find(|s| s.band == band && s.attribute == *attribute)
+                    .copied()
+            })
+        };
+        let l1 = select(1, l1_preference).filter(|s| s.pseudorange_m.is_some())?;
+        let fcn = sigs.iter().find_map(|s| s.glonass_fcn);
+        if gnss == Gnss::Glonass && fcn.is_none() {
+            return None;
+        }
+        let ambiguity = (l1.pseudorange_m? / modulus).floor();
+        let remainder = ((l1.pseudorange_m? - ambiguity * modulus) / PSEUDORANGE_RESOLUTION_M)
+            .round()
+            * PSEUDORANGE_RESOLUTION_M;
+        // phases are given relative to the pseudorange as transmitted
+        let l1_pseudorange = ambiguity * modulus + remainder;
+        let (l1_phase_diff, l1_lock_ms) = self.phase_difference(gnss, l1, l1_pseudorange);
+        let mut sat = Map::new();
+        match gnss {
+            Gnss::Glonass => {
+                sat.insert("glo_satellite_id".into(), json!(l1.satellite_id));
+                sat.insert("glo_l1_code_ind".into(), json!((l1.attribute != 'C') as u8));
+                sat.insert("glo_satellite_freq_chan_number".into(), json!(fcn));
+            }
+            _ => {
+                sat.insert("gps_satellite_id".into(), json!(l1.satellite_id));
+                sat.insert("gps_l1_code_ind".into(), json!((l1.attribute != 'C') as u8));
+            }
+        }
+        sat.insert("l1_pseudorange_m".into(), json!(remainder));
+        sat.insert("l1_phase_pseudorange_diff_m".into(), json!(l1_phase_diff));
+        sat.insert(
+            "l1_lock_time_index".into(),
+            json!(lock_time_ind(l1_lock_ms / 1000)),
+        );
+        sat.insert("l1_pseudorange_amb_m".into(), json!(ambiguity * modulus));
+        sat.insert("l1_cnr_dbhz".into(), json!(cnr(l1)));
+        let l2 = select(2, l2_preference);
+        let l2_code = l2.map_or(0, |s| match (gnss, s.attribute) {
+            (Gnss::Glonass, 'P') => 1,
+            (Gnss::Glonass, _) => 0,
+            (_, 'P') => 1,
+            (_, 'W') => 3,
+            _ => 0,
+        });
+        let (l2_phase_diff, l2_lock_ms) = l2.map_or((None, 0), |s| {
+            self.phase_difference(gnss, s, l1_pseudorange)
+        });
+        let l2_pseudorange_diff = l2
+            .and_then(|s| s.pseudorange_m)
+            .map(|pr| pr - l1_pseudorange)
+            .filter(|diff| diff.abs() < PSEUDORANGE_DIFF_LIMIT_M);
+        let code_field = match gnss {
+            Gnss::Glonass => "glo_l2_code_ind",
+            _ => "gps_l2_code_ind",
+        };
+        sat.insert(code_field.into(), json!(l2_code));
+        sat.insert(
+            "l2_l1_pseudorange_diff_m".into(),
+            json!(l2_pseudorange_diff),
+        );
+        sat.insert(
+            "l2_phase_l1_pseudorange_diff_m".into(),
+            json!(l2_phase_diff),
+        );
+        sat.insert(
+            "l2_lock_time_index".into(),
+            json!(lock_time_ind(l2_lock_ms / 1000)),
+        );
+        sat.insert("l2_cnr_dbhz".into(), json!(l2.and_then(cnr)));
+        Some(Value::Object(sat))
+    }
+    /// Shifted carrier phase minus the L1 pseudorange (m), and lock time (ms)
+    fn phase_difference(
+        &mut self,
+        gnss: Gnss,
+        sig: &SignalObservation,
+        l1_pseudorange: f64,
+    ) -> (Option<f64>, u64) {
+        match gnss.frequency(sig.band, sig.glonass_fcn).and_then(|freq| {
+            self.phase_offsets.phase_minus_reference(
+                gnss,
+                sig,
+                CLIGHT / freq,
+                l1_pseudorange,
+                PHASE_DIFF_LIMIT_M,
+            )
+        }) {
+            Some((diff, lock_time_ms)) => (Some(diff), lock_time_ms),
+            None => (None, 0),
+        }
+    }
+}
+
+/// Minimum lock time (s) of the legacy lock time indicator (DF013, DF019,
+/// DF043, DF049)
+pub fn lock_time_s(ind: u8) -> u64 {
+    let i = ind as u64;
+    match i {
+        0..=23 => i,
+        24..=47 => 2 * i - 24,
+        48..=71 => 4 * i - 120,
+        72..=95 => 8 * i - 408,
+        96..=119 => 16 * i - 1176,
+        120..=126 => 32 * i - 3096,
+        _ => 937,
+    }
+}
+
+/// Legacy lock time indicator of a lock time (s), the inverse of `lock_time_s`
+pub fn lock_time_ind(lock_time_s: u64) -> u8 {
+    let t = lock_time_s;
+    (match t {
+        0..=23 => t,
+        24..=71 => (t + 24) / 2,
+        72..=167 => (t + 120) / 4,
+        168..=359 => (t + 408) / 8,
+        360..=743 => (t + 1176) / 16,
+        744..=936 => (t + 3096) / 32,
+        _ => 127,
+    }) as u8
+}
+
+/// CNR rounded to the 0.25 dB-Hz resolution of the legacy messages
+fn cnr(sig: &SignalObservation) -> Option<f64> {
+    sig.cnr_dbhz
+        .map(|cnr| (cnr * 4.0).round() / 4.0)
+        .filter(|cnr| (0.25..64.0).contains(cnr))
+}
+
+fn get_f64(value: &Value, key: &str) -> Option<f64> {
+    value.get(key).and_then(Value::as_f64)
+}
+fn get_u64(value: &Value, key: &str) -> Option<u64> {
+    value.get(key).and_then(Value::as_u64)
+}
@@ -1,17 +1,61 @@
 use circular::Buffer;
+use decimate::{observation_epoch, Decimator};
+use epoch_group::EpochGrouper;
+use gnss_time::TimeResolver;
+use http_server::HttpServer;
+use latency::LatencyMonitor;
+use metrics::{count_crc_failures, Metrics};
+use mqtt_client::MqttClient;
+use msm::{MsmDecoder, MsmEncoder};
+use obs_convert::ObsConverter;
+use orbit::EphemerisStore;
+use proxy::Proxy;
+use rinex_nav::{read_rinex_nav, RinexNavWriter};
+use rinex_obs::{RinexObsReader, RinexObsWriter};
+use rules::{apply_rules, Rule};
+use scheduler::Scheduler;
 use rtcm_rs::{self, prelude::*};
-use serde_json;
-use tcp_server::TcpServer;
+use tcp_server::{Framing, InputSource, TcpServer};
+use timeout_reader::TimeoutReader;
+use watchdog::Watchdog;
+use websocket_server::WebSocketServer;
+use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 #[macro_use]
 extern crate version;
 
+mod access;
+mod annotate;
 mod arguments;
 mod coordinate;
+mod decimate;
+mod ephemeris;
+mod epoch_group;
+mod gnss_time;
+mod http_server;
+mod json_stream;
+mod latency;
+mod legacy;
+mod metrics;
+mod mqtt_client;
+mod msm;
 mod ntrip_client;
+mod obs_convert;
+mod orbit;
+mod proxy;
+mod rinex_nav;
+mod rinex_obs;
+mod rules;
+mod scheduler;
 mod tcp_client;
 mod tcp_handler;
 mod tcp_server;
+mod timeout_reader;
+mod tls;
+mod watchdog;
+mod websocket_server;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let arguments = arguments::parse_arguments().expect("Error parsing arguments");
@@ -20,29 +64,155 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         input,
         output,
         conv_dir,
-        pretty_print,
+        forward_options,
+        backward_options,
+        rinex_options,
+        rinex_input_options,
+        date,
+        rules,
+        decimate_options,
+        scheduler_options,
+        watchdog_options,
+        metrics_address,
+        mqtt_options,
+        access_options,
+        tls_options,
+        reconnect_options,
+        proxy,
     } = arguments;
 
-    let input: Box<dyn BufRead> = setup_input(input)?;
-    let output: Box<dyn Write> = setup_output(output)?;
+    let mut monitoring = Monitoring {
+        watchdog: watchdog_options.map(Watchdog::new),
+        metrics: metrics_address.map(|(host, port)| Metrics::new(host, port)),
+        input_source: None,
+    };
+    let framing = match conv_dir {
+        arguments::ConvDir::Backward | arguments::ConvDir::RinexInput => Framing::Lines,
+        arguments::ConvDir::Forward | arguments::ConvDir::RinexObs => Framing::Rtcm,
+    };
+    let connections = Connections {
+        mqtt: mqtt_options,
+        access: access_options,
+        reconnect: reconnect_options,
+        proxy,
+        client_tls: match tls_options.client {
+            true => Some(tls::client_config(&tls_options)?),
+            false => None,
+        },
+        server_tls: match (tls_options.cert_file.as_ref(), tls_options.key_file.as_ref()) {
+            (Some(cert_file), Some(key_file)) => Some(tls::server_config(cert_file, key_file)?),
+            _ => None,
+        },
+    };
+    let mut input_connections = connections.clone();
+    if matches!(
+        (&input, &output),
+        (arguments::Input::Mqtt { .. }, arguments::Output::Mqtt { .. })
+    ) {
+        // a broker disconnects clients connecting with the same identifier
+        input_connections.mqtt.client_id.push_str("-in");
+    }
+    let input: Box<dyn BufRead + Send> =
+        setup_input(input, framing, &input_connections, &mut monitoring)?;
+    let output: Box<dyn Write> = setup_output(output, framing, &connections, &monitoring)?;
 
-    if let arguments::ConvDir::Forward = conv_dir {
-        forward(input, output, pretty_print);
-    } else {
-        backward(input, output);
+    match conv_dir {
+        arguments::ConvDir::Forward => {
+            forward(
+                input,
+                output,
+                &forward_options,
+                date,
+                &rules,
+                &decimate_options,
+                &monitoring,
+            )?
+        }
+        arguments::ConvDir::Backward => {
+            let output: Box<dyn Write> = match scheduler_options {
+                Some(scheduler_options) => {
+                    Box::new(Scheduler::new(output, &scheduler_options, date))
+                }
+                None => output,
+            };
+            backward(
+                input,
+                output,
+                &backward_options,
+                date,
+                &rules,
+                &decimate_options,
+                &monitoring,
+            )?
+        }
+        arguments::ConvDir::RinexObs => rinex_obs(input, output, &rinex_options, date)?,
+        arguments::ConvDir::RinexInput => rinex_input(input, output, &rinex_input_options)?,
     }
     Ok(())
 }
 
-fn setup_input(input: arguments::Input) -> Result<Box<dyn BufRead>, Box<dyn std::error::Error>> {
+/// Watchdog and metrics of the conversion, shared with the input and output
+/// connections, and the client of the tcp server input messages are read from
+struct Monitoring {
+    watchdog: Option<Watchdog>,
+    metrics: Option<Metrics>,
+    input_source: Option<InputSource>,
+}
+
+/// Options of the input and output connections
+#[derive(Clone)]
+struct Connections {
+    mqtt: arguments::MqttOptions,
+    access: arguments::AccessOptions,
+    reconnect: arguments::ReconnectOptions,
+    proxy: Option<Proxy>,
+    client_tls: Option<Arc<rustls::ClientConfig>>,
+    server_tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+fn setup_input(
+    input: arguments::Input,
+    framing: Framing,
+    connections: &Connections,
+    monitoring: &mut Monitoring,
+) -> Result<Box<dyn BufRead + Send>, Box<dyn std::error::Error>> {
     match input {
-        arguments::Input::StdIn => Ok(Box::new(std::io::stdin().lock())),
+        arguments::Input::StdIn => Ok(Box::new(BufReader::new(std::io::stdin()))),
         arguments::Input::File { path } => {
             Ok(Box::new(BufReader::new(std::fs::File::open(&path)?)))
         }
-        arguments::Input::TcpClient { host, port } => Ok(Box::new(BufReader::new(
-            tcp_client::TcpClient::new(host, port, 10),
-        ))),
+        arguments::Input::TcpClient { host, port } => {
+            let mut client = tcp_client::TcpClient::new(host, port, connections.reconnect.clone());
+            if let Some(config) = connections.client_tls.as_ref() {
+                client.set_tls(config.clone());
+            }
+            if let Some(proxy) = connections.proxy.as_ref() {
+                client.set_proxy(proxy.clone());
+            }
+            if let Some(watchdog) = monitoring.watchdog.as_ref() {
+                client.set_watchdog(watchdog.clone());
+            }
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                client.set_metrics(metrics.clone());
+            }
+            Ok(Box::new(BufReader::new(client)))
+        }
+        arguments::Input::TcpServer { host, port } => {
+            let mut server = TcpServer::new(host, port);
+            server.set_framing(framing);
+            server.set_access(connections.access.clone());
+            if let Some(config) = connections.server_tls.as_ref() {
+                server.set_tls(config.clone());
+            }
+            if let Some(watchdog) = monitoring.watchdog.as_ref() {
+                server.set_watchdog(watchdog.clone());
+            }
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                server.set_metrics(metrics.clone());
+            }
+            monitoring.input_source = Some(server.source());
+            Ok(Box::new(BufReader::new(server)))
+        }
         arguments::Input::NtripClient {
             host,
             port,
@@ -51,65 +221,519 @@ fn setup_input(input: arguments::Input) -> Result<Box<dyn BufRead>, Box<dyn std:
             password,
             coordinate,
             nmea_int,
+            handshake_timeout,
+            version,
         } => {
-            let mut nclient = ntrip_client::NtripClient::new(host, port, 10, mountpoint);
+            let mut nclient = ntrip_client::NtripClient::new(
+                host,
+                port,
+                connections.reconnect.clone(),
+                mountpoint,
+            );
+            nclient.set_handshake_timeout(handshake_timeout);
+            nclient.set_version(version);
+            if let Some(config) = connections.client_tls.as_ref() {
+                nclient.set_tls(config.clone());
+            }
+            if let Some(proxy) = connections.proxy.as_ref() {
+                nclient.set_proxy(proxy.clone());
+            }
             if let (Some(username), Some(password)) = (username, password) {
                 nclient.set_credentials(username, password);
             }
             if let Some(coordinate) = coordinate {
                 nclient.set_nmea(coordinate, nmea_int);
             }
+            if let Some(watchdog) = monitoring.watchdog.as_ref() {
+                nclient.set_watchdog(watchdog.clone());
+            }
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                nclient.set_metrics(metrics.clone());
+            }
             Ok(Box::new(BufReader::new(nclient)))
         }
+        arguments::Input::Mqtt { host, port } => {
+            let mut client = MqttClient::new(
+                host,
+                port,
+                connections.reconnect.clone(),
+                connections.mqtt.client_id.clone(),
+            );
+            if let Some(config) = connections.client_tls.as_ref() {
+                client.set_tls(config.clone());
+            }
+            if let Some(proxy) = connections.proxy.as_ref() {
+                client.set_proxy(proxy.clone());
+            }
+            if let Some(username) = connections.mqtt.username.clone() {
+                client.set_credentials(username, connections.mqtt.password.clone());
+            }
+            client.set_subscriptions(connections.mqtt.subscriptions.clone());
+            if let Some(watchdog) = monitoring.watchdog.as_ref() {
+                client.set_watchdog(watchdog.clone());
+            }
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                client.set_metrics(metrics.clone());
+            }
+            Ok(Box::new(BufReader::new(client)))
+        }
     }
 }
 
-fn setup_output(output: arguments::Output) -> Result<Box<dyn Write>, Box<dyn std::error::Error>> {
+fn setup_output(
+    output: arguments::Output,
+    input_framing: Framing,
+    connections: &Connections,
+    monitoring: &Monitoring,
+) -> Result<Box<dyn Write>, Box<dyn std::error::Error>> {
     match output {
         arguments::Output::StdOut => Ok(Box::new(std::io::stdout().lock())),
         arguments::Output::File { path } => Ok(Box::new(std::fs::File::create(&path)?)),
         arguments::Output::TcpClient { host, port } => {
-            Ok(Box::new(tcp_client::TcpClient::new(host, port, 10)))
+            let mut client = tcp_client::TcpClient::new(host, port, connections.reconnect.clone());
+            if let Some(config) = connections.client_tls.as_ref() {
+                client.set_tls(config.clone());
+            }
+            if let Some(proxy) = connections.proxy.as_ref() {
+                client.set_proxy(proxy.clone());
+            }
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                client.set_metrics(metrics.clone());
+            }
+            Ok(Box::new(client))
         }
-        arguments::Output::TcpServer { host, port } => {
-            Ok(Box::new(TcpServer::new(host, port)))
+        arguments::Output::TcpServer {
+            host,
+            port,
+            queue_size,
+            slow_client,
+        } => {
+            let mut server = TcpServer::new(host, port);
+            // json is output for rtcm input and rtcm for json input
+            server.set_framing(match input_framing {
+                Framing::Rtcm => Framing::Lines,
+                Framing::Lines => Framing::Rtcm,
+            });
+            server.set_queue(queue_size, slow_client);
+            server.set_access(connections.access.clone());
+            if let Some(config) = connections.server_tls.as_ref() {
+                server.set_tls(config.clone());
+            }
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                server.set_metrics(metrics.clone());
+            }
+            Ok(Box::new(server))
         },
+        arguments::Output::WebSocketServer { host, port, binary } => {
+            Ok(Box::new(WebSocketServer::new(host, port, binary)))
+        }
+        arguments::Output::HttpServer { host, port } => {
+            Ok(Box::new(HttpServer::new(host, port)))
+        }
+        arguments::Output::Mqtt { host, port, binary } => {
+            let mut client = MqttClient::new(
+                host,
+                port,
+                connections.reconnect.clone(),
+                connections.mqtt.client_id.clone(),
+            );
+            if let Some(config) = connections.client_tls.as_ref() {
+                client.set_tls(config.clone());
+            }
+            if let Some(proxy) = connections.proxy.as_ref() {
+                client.set_proxy(proxy.clone());
+            }
+            if let Some(username) = connections.mqtt.username.clone() {
+                client.set_credentials(username, connections.mqtt.password.clone());
+            }
+            client.set_topic(connections.mqtt.topic.clone());
+            client.set_binary(binary);
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                client.set_metrics(metrics.clone());
+            }
+            Ok(Box::new(client))
+        }
     }
 }
 
-fn forward(mut rtcm_input: Box<dyn BufRead>, mut json_output: Box<dyn Write>, pretty_print:bool) {
+/// Calls `on_frame` for each RTCM frame of the input, and with `None` when
+/// a read of the input has timed out, until it returns false. Frame headers
+/// failing the CRC check are counted in `metrics`. Fails when the input
+/// connection was given up.
+fn read_frames<F>(
+    mut rtcm_input: Box<dyn BufRead>,
+    metrics: Option<&Metrics>,
+    mut on_frame: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(Option<&MessageFrame>) -> bool,
+{
     let mut buffer = Buffer::with_capacity(2 * 1029);
     loop {
-        if let Ok(n) = rtcm_input.read(buffer.space()) {
-            if n == 0 {
-                break;
+        match rtcm_input.read(buffer.space()) {
+            Ok(0) => break,
+            Ok(n) => {
+                buffer.fill(n);
+            }
+            Err(e) if tcp_handler::is_timeout(&e) => {
+                if !on_frame(None) {
+                    return Ok(());
+                }
             }
-            buffer.fill(n);
+            Err(e) if tcp_handler::gave_up(&e) => return Err(e),
+            Err(_) => (),
         }
-        let mut iter = MsgFrameIter::new(buffer.data());
-        for mf in &mut iter {
-            if let Ok(json_msg) = if pretty_print {
-                serde_json::to_string_pretty(&mf.get_message())
-            } else {
-                serde_json::to_string(&mf.get_message())
-            } {
-                let _ = json_output.write_all(json_msg.as_bytes());
-                let _ = json_output.write_all("\r\n".as_bytes());
+        let data = buffer.data();
+        let mut consumed = 0;
+        loop {
+            let (n, mf) = next_msg_frame(&data[consumed..]);
+            let skipped = n - mf.as_ref().map_or(0, |mf| mf.frame_len());
+            if let Some(metrics) = metrics.filter(|_| skipped > 0) {
+                metrics.crc_failures(count_crc_failures(&data[consumed..consumed + skipped]));
+            }
+            consumed += n;
+            match mf {
+                Some(mf) => {
+                    if !on_frame(Some(&mf)) {
+                        return Ok(());
+                    }
+                }
+                None => break,
             }
         }
-        buffer.consume(iter.consumed());
+        buffer.consume(consumed);
+    }
+    Ok(())
+}
+
+fn forward(
+    rtcm_input: Box<dyn BufRead + Send>,
+    mut json_output: Box<dyn Write>,
+    options: &arguments::ForwardOptions,
+    date: Option<chrono::NaiveDate>,
+    rules: &[Rule],
+    decimate_options: &arguments::DecimateOptions,
+    monitoring: &Monitoring,
+) -> std::io::Result<()> {
+    let mut msm_decoder = MsmDecoder::new();
+    let mut time_resolver = TimeResolver::from_date_or_now(date);
+    let mut ephemerides = EphemerisStore::new();
+    let mut stations = HashMap::new();
+    let mut decimator = decimate_options
+        .is_active()
+        .then(|| Decimator::new(decimate_options, date));
+    let mut epoch_grouper = options
+        .epoch_timeout
+        .map(|timeout| EpochGrouper::new(timeout, date));
+    // the timeout of an open epoch also passes while the input is silent
+    let mut input_source = monitoring.input_source.clone();
+    let rtcm_input: Box<dyn BufRead> = match options.epoch_timeout {
+        Some(timeout) => {
+            let reader = TimeoutReader::new(
+                rtcm_input,
+                (timeout / 10).max(Duration::from_millis(10)),
+                input_source.clone(),
+            );
+            // the reader reads ahead, the source is that of the data returned
+            input_source = input_source.map(|_| reader.source());
+            Box::new(BufReader::new(reader))
+        }
+        None => rtcm_input,
+    };
+    let latency_enabled = options.latency_stats.is_some()
+        || options.latency_alert.is_some()
+        || monitoring.metrics.is_some();
+    let mut latency_monitor = latency_enabled.then(|| {
+        LatencyMonitor::new(
+            options.latency_stats.filter(|interval| *interval > 0.0),
+            options.latency_alert,
+        )
+    });
+    let mut write_result = Ok(());
+    let read_result = read_frames(rtcm_input, monitoring.metrics.as_ref(), |mf| {
+        let Some(mf) = mf else {
+            if let Some(epoch) = epoch_grouper.as_mut().and_then(EpochGrouper::poll) {
+                write_result = write_json(&mut json_output, &epoch, options.pretty_print)
+                    .and_then(|_| json_output.flush());
+            }
+            return !output_gave_up(&write_result);
+        };
+        let msg = apply_rules(rules, mf.get_message());
+        if let Some(watchdog) = monitoring.watchdog.as_ref() {
+            watchdog.message(&msg);
+        }
+        if let Some(metrics) = monitoring.metrics.as_ref() {
+            let number = msg.number().unwrap_or(0);
+            metrics.message(number, annotate::reference_station_id(&msg), mf.frame_len());
+        }
+        if let Some(latency_monitor) = latency_monitor.as_mut() {
+            if let Some(sample) = latency_monitor.record(&msg) {
+                if let Some(metrics) = monitoring.metrics.as_ref() {
+                    metrics.latency(sample.number, sample.station, sample.latency_ms as f64 / 1000.0);
+                }
+                if let Some(alert) = latency_monitor.alert(&sample) {
+                    eprintln!("{}", alert);
+                }
+            }
+            if let Some(report) = latency_monitor.periodic_report() {
+                eprint!("{}", report);
+            }
+        }
+        if let Some(decimator) = decimator.as_mut() {
+            if !decimator.keep(&msg, mf.frame_len()) {
+                return true;
+            }
+            if let Some(report) = decimator.periodic_report() {
+                eprint!("{}", report);
+            }
+        }
+        let decoded_msm = msm_decoder.decode(&msg);
+        let mut json_msg = match decoded_msm.as_ref() {
+            Some(obs) if options.decoded_msm => obs.to_json(),
+            _ => match serde_json::to_value(&msg) {
+                Ok(json_msg) => json_msg,
+                Err(_) => return true,
+            },
+        };
+        if let Some(source) = input_source.as_ref().and_then(InputSource::get) {
+            annotate::source(&source, &mut json_msg);
+        }
+        if options.epoch_time == Some(arguments::EpochTimeReference::Receive) {
+            time_resolver.set_reference(gnss_time::utc_to_gps(chrono::Utc::now().naive_utc()));
+        }
+        if options.epoch_time.is_some() {
+            if let Some(epoch) = observation_epoch(&msg) {
+                let gps = time_resolver.resolve_msm(
+                    epoch.gnss,
+                    epoch.epoch_time_ms,
+                    epoch.glo_day_of_week,
+                );
+                annotate::epoch_time(gps, &mut json_msg);
+            }
+        }
+        if options.annotate_position {
+            annotate::station_position(
+                &msg,
+                &mut json_msg,
+                options.reference_coordinate.as_ref(),
+            );
+        }
+        if options.satellite_positions {
+            if let Some((station_id, position)) = annotate::station_coordinate(&msg) {
+                stations.insert(station_id, position);
+            }
+            ephemerides.add_message(&msg, time_resolver.reference());
+            if let Some(obs) = decoded_msm.as_ref() {
+                let epoch =
+                    time_resolver.resolve_msm(obs.gnss, obs.epoch_time_ms, obs.glo_day_of_week);
+                annotate::satellite_positions(
+                    obs,
+                    epoch,
+                    &ephemerides,
+                    stations.get(&obs.reference_station_id),
+                    &mut json_msg,
+                );
+            }
+        }
+        write_result = match epoch_grouper.as_mut() {
+            Some(epoch_grouper) => epoch_grouper
+                .push(&msg, json_msg)
+                .iter()
+                .map(|epoch| write_json(&mut json_output, epoch, options.pretty_print))
+                .fold(Ok(()), Result::and),
+            None => write_json(&mut json_output, &json_msg, options.pretty_print),
+        };
+        !output_gave_up(&write_result)
+    });
+    if let Some(epoch) = epoch_grouper.as_mut().and_then(EpochGrouper::finish) {
+        let _ = write_json(&mut json_output, &epoch, options.pretty_print);
     }
     let _ = json_output.flush();
+    if let Some(decimator) = decimator {
+        eprint!("{}", decimator.report());
+    }
+    if let Some(latency_monitor) = latency_monitor.filter(|_| options.latency_stats.is_some()) {
+        eprint!("{}", latency_monitor.report());
+    }
+    read_result?;
+    match write_result {
+        Err(e) if tcp_handler::gave_up(&e) => Err(e),
+        _ => Ok(()),
+    }
 }
-fn backward(json_input: Box<dyn BufRead>, mut rtcm_output: Box<dyn Write>) {
+/// Write failed as the output connection was given up, which ends the
+/// conversion. Other write errors lose the message only.
+fn output_gave_up(result: &std::io::Result<()>) -> bool {
+    result.as_ref().is_err_and(tcp_handler::gave_up)
+}
+fn write_json(
+    json_output: &mut dyn Write,
+    json_msg: &serde_json::Value,
+    pretty_print: bool,
+) -> std::io::Result<()> {
+    if let Ok(json_msg) = if pretty_print {
+        serde_json::to_string_pretty(json_msg)
+    } else {
+        serde_json::to_string(json_msg)
+    } {
+        json_output.write_all(json_msg.as_bytes())?;
+        json_output.write_all("\r\n".as_bytes())?;
+    }
+    Ok(())
+}
+fn rinex_obs(
+    rtcm_input: Box<dyn BufRead>,
+    mut rinex_output: Box<dyn Write>,
+    options: &arguments::RinexOptions,
+    date: Option<chrono::NaiveDate>,
+) -> std::io::Result<()> {
+    let mut rinex_writer = RinexObsWriter::new(options, TimeResolver::from_date_or_now(date));
+    let mut nav_writer = RinexNavWriter::new(options);
+    let mut write_result = Ok(());
+    let read_result = read_frames(rtcm_input, None, |mf| {
+        let Some(mf) = mf else {
+            return true;
+        };
+        let msg = mf.get_message();
+        let more = nav_writer.add_message(&msg, rinex_writer.reference())
+            || rinex_writer.add_message(&msg);
+        write_result = rinex_writer.write_completed(&mut rinex_output);
+        more && write_result.is_ok()
+    });
+    write_result?;
+    rinex_writer.write(&mut rinex_output)?;
+    if let Some(nav_path) = options.nav_path.as_ref() {
+        nav_writer.write(&mut std::fs::File::create(nav_path)?)?;
+    }
+    read_result
+}
+fn rinex_input(
+    rinex_input: Box<dyn BufRead>,
+    mut rtcm_output: Box<dyn Write>,
+    options: &arguments::RinexInputOptions,
+) -> std::io::Result<()> {
+    let mut ephemerides = match options.nav_path.as_ref() {
+        Some(path) => read_rinex_nav(BufReader::new(std::fs::File::open(path)?))?,
+        None => Vec::new(),
+    };
+    ephemerides.sort_by_key(|(transmission, _)| *transmission);
+    let mut reader = RinexObsReader::new(rinex_input, options.msm_type, options.station_id)?;
+    for (_, eph) in ephemerides.iter() {
+        if let ephemeris::Ephemeris::Glonass(eph) = eph {
+            reader.set_glonass_fcn(eph.prn, eph.fcn);
+        }
+    }
+    let mut ephemerides = ephemerides.into_iter().peekable();
+    let mut msm_encoder = MsmEncoder::new();
+    let mut msg_builder = MessageBuilder::new();
+    let station_data = reader
+        .station_message()
+        .and_then(|msg| msg_builder.build_message(&msg).ok().map(<[u8]>::to_vec));
+    let mut pacing_start = None;
+    let mut last_station_message: Option<chrono::NaiveDateTime> = None;
+    while let Some((epoch, observations)) = reader.next_epoch()? {
+        if let Some(pacing) = options.pacing {
+            let (start, first_epoch) = *pacing_start.get_or_insert((Instant::now(), epoch));
+            let elapsed_s = (epoch - first_epoch).num_milliseconds().max(0) as f64 * 0.001;
+            let due = start + Duration::from_secs_f64(elapsed_s / pacing);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        }
+        // station position every 10 s, ephemerides once at their transmission time
+        if last_station_message.is_none_or(|last| (epoch - last).num_seconds() >= 10) {
+            if let Some(station_data) = station_data.as_ref() {
+                let written = rtcm_output.write_all(station_data);
+                if output_gave_up(&written) {
+                    return written;
+                }
+            }
+            last_station_message = Some(epoch);
+        }
+        let mut messages = Vec::new();
+        while let Some((_, eph)) = ephemerides.next_if(|(transmission, _)| *transmission <= epoch) {
+            messages.extend(eph.to_message());
+        }
+        let count = observations.len();
+        for (i, mut obs) in observations.into_iter().enumerate() {
+            obs.multiple_message = i + 1 < count;
+            messages.extend(msm_encoder.encode(&obs));
+        }
+        for msg in messages.iter() {
+            if let Ok(msg_data) = msg_builder.build_message(msg) {
+                let written = rtcm_output.write_all(msg_data);
+                if output_gave_up(&written) {
+                    return written;
+                }
+            }
+        }
+        let _ = rtcm_output.flush();
+    }
+    Ok(())
+}
+fn backward(
+    json_input: Box<dyn BufRead>,
+    mut rtcm_output: Box<dyn Write>,
+    options: &arguments::BackwardOptions,
+    date: Option<chrono::NaiveDate>,
+    rules: &[Rule],
+    decimate_options: &arguments::DecimateOptions,
+    monitoring: &Monitoring,
+) -> std::io::Result<()> {
     let mut msg_builder = MessageBuilder::new();
-    for json_msg in json_input.lines() {
-        if let Some(msg_data) = json_msg
-            .ok()
-            .and_then(|msg| serde_json::from_str::<Message>(msg.as_str()).ok())
-            .and_then(|msg| msg_builder.build_message(&msg).ok())
-        {
-            let _ = rtcm_output.write_all(msg_data);
+    let mut obs_converter = options.obs_target.map(ObsConverter::new);
+    let mut decimator = decimate_options
+        .is_active()
+        .then(|| Decimator::new(decimate_options, date));
+    let mut result = Ok(());
+    'input: for json_msg in json_input.lines() {
+        let json_msg = match json_msg {
+            Ok(json_msg) => json_msg,
+            Err(e) if tcp_handler::gave_up(&e) => {
+                result = Err(e);
+                break;
+            }
+            Err(_) => continue,
+        };
+        if json_msg.trim().is_empty() {
+            continue;
+        }
+        let Ok(msg) = serde_json::from_str::<Message>(json_msg.as_str()) else {
+            if let Some(metrics) = monitoring.metrics.as_ref() {
+                metrics.parse_failure();
+            }
+            continue;
+        };
+        let msg = apply_rules(rules, msg);
+        let messages = match obs_converter.as_mut() {
+            Some(converter) => converter.convert(msg),
+            None => vec![msg],
+        };
+        for msg in messages.iter() {
+            if let Ok(msg_data) = msg_builder.build_message(msg) {
+                if let Some(decimator) = decimator.as_mut() {
+                    if !decimator.keep(msg, msg_data.len()) {
+                        continue;
+                    }
+                    if let Some(report) = decimator.periodic_report() {
+                        eprint!("{}", report);
+                    }
+                }
+                if let Some(metrics) = monitoring.metrics.as_ref() {
+                    let number = msg.number().unwrap_or(0);
+                    metrics.message(number, annotate::reference_station_id(msg), msg_data.len());
+                }
+                let written = rtcm_output.write_all(msg_data);
+                if output_gave_up(&written) {
+                    result = written;
+                    break 'input;
+                }
+            }
         }
     }
+    let _ = rtcm_output.flush();
+    if let Some(decimator) = decimator {
+        eprint!("{}", decimator.report());
+    }
+    result
 }
@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds (s) of the latency histogram buckets
+const LATENCY_BUCKETS_S: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0];
+/// Time for scrapers to send the request and receive the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, the last one is +Inf
+    buckets: [u64; LATENCY_BUCKETS_S.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Connection {
+    connected: bool,
+    reconnects: u64,
+    read_timeouts: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Messages and bytes per message number and reference station
+    messages: BTreeMap<(u16, u16), (u64, u64)>,
+    crc_failures: u64,
+    parse_failures: u64,
+    connections: BTreeMap<(String, u16), Connection>,
+    tcp_server_clients: usize,
+    /// Messages dropped per tcp server client
+    tcp_server_drops: BTreeMap<String, u64>,
+    ntrip_handshake_failures: u64,
+    latencies: BTreeMap<(u16, u16), Histogram>,
+}
+
+/// Metrics of the conversion and the connections, served in the Prometheus
+/// text exposition format on `http://<host>:<port>/metrics`.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new(host: String, port: u16) -> Self {
+        let listener =
+            TcpListener::bind(format!("{}:{}", host, port)).expect("Could not bind to port");
+        let metrics = Metrics {
+            registry: Arc::new(Mutex::new(Registry::default())),
+        };
+        let server_metrics = metrics.clone();
+        let _ = std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a slow client does not hold up the scrapes of others
+                let server_metrics = server_metrics.clone();
+                std::thread::spawn(move || server_metrics.respond(stream));
+            }
+        });
+        metrics
+    }
+    fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut request = String::new();
+        // only the request line is read, up to 8 kB
+        BufReader::new((&stream).take(8192)).read_line(&mut request)?;
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+        if path == "/metrics" || path.starts_with("/metrics?") {
+            let body = self.render();
+            write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            stream.write_all(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+        }
+    }
+    fn update<F: FnOnce(&mut Registry)>(&self, f: F) {
+        if let Ok(mut registry) = self.registry.lock() {
+            f(&mut registry);
+        }
+    }
+    /// Counts a message of `bytes` bytes (RTCM frame)
+    pub fn message(&self, number: u16, station: u16, bytes: usize) {
+        self.update(|registry| {
+            let (count, total) = registry.messages.entry((number, station)).or_default();
+            *count += 1;
+            *total += bytes as u64;
+        });
+    }
+    pub fn crc_failures(&self, failures: u64) {
+        self.update(|registry| registry.crc_failures += failures);
+    }
+    pub fn parse_failure(&self) {
+        self.update(|registry| registry.parse_failures += 1);
+    }
+    /// Connection state of a tcp connection, `reconnect` if it has been
+    /// connected before
+    pub fn connection(&self, host: &str, port: u16, connected: bool, reconnect: bool) {
+        self.update(|registry| {
+            let connection = registry
+                .connections
+                .entry((host.to_string(), port))
+                .or_default();
+            connection.connected = connected;
+            if reconnect {
+                connection.reconnects += 1;
+            }
+        });
+    }
+    pub fn read_timeout(&self, host: &str, port: u16) {
+        self.update(|registry| {
+            registry
+                .connections
+                .entry((host.to_string(), port))
+                .or_default()
+                .read_timeouts += 1
+        });
+    }
+    pub fn tcp_server_clients(&self, clients: usize) {
+        self.update(|registry| registry.tcp_server_clients = clients);
+    }
+    pub fn tcp_server_drops(&self, client: &str, messages: u64) {
+        self.update(|registry| {
+            *registry
+                .tcp_server_drops
+                .entry(client.to_string())
+                .or_default() += messages
+        });
+    }
+    pub fn ntrip_handshake_failure(&self) {
+        self.update(|registry| registry.ntrip_handshake_failures += 1);
+    }
+    pub fn latency(&self, number: u16, station: u16, latency_s: f64) {
+        self.update(|registry| {
+            let histogram = registry.latencies.entry((number, station)).or_default();
+            let bucket = LATENCY_BUCKETS_S
+                .iter()
+                .position(|bound| latency_s <= *bound)
+                .unwrap_or(LATENCY_BUCKETS_S.len());
+            histogram.buckets[bucket] += 1;
+            histogram.count += 1;
+            histogram.sum += latency_s;
+        });
+    }
+    fn render(&self) -> String {
+        let Ok(registry) = self.registry.lock() else {
+            return String::new();
+        };
+        let mut out = String::new();
+        header(
+            &mut out,
+            "rtcm_messages_total",
+            "counter",
+            "RTCM messages per message type and station",
+        );
+        for ((number, station), (count, _)) in registry.messages.iter() {
+            let _ = writeln!(
+                out,
+                "rtcm_messages_total{{message=\"{}\",station=\"{}\"}} {}",
+                number, station, count
+            );
+        }
+        header(
+            &mut out,
+            "rtcm_bytes_total",
+            "counter",
+            "RTCM frame bytes per message type and station",
+        );
+        for ((number, station), (_, bytes)) in registry.messages.iter() {
+            let _ = writeln!(
+                out,
+                "rtcm_bytes_total{{message=\"{}\",station=\"{}\"}} {}",
+                number, station, bytes
+            );
+        }
+        header(
+            &mut out,
+            "rtcm_crc_failures_total",
+            "counter",
+            "RTCM frame headers failing the CRC check",
+        );
+        let _ = writeln!(out, "rtcm_crc_failures_total {}", registry.crc_failures);
+        header(
+            &mut out,
+            "rtcm_json_parse_failures_total",
+            "counter",
+            "JSON lines of the backward conversion not parsed as messages",
+        );
+        let _ = writeln!(
+            out,
+            "rtcm_json_parse_failures_total {}",
+            registry.parse_failures
+        );
+        header(
+            &mut out,
+            "rtcm_connection_up",
+            "gauge",
+            "Connection state of tcp and Ntrip client connections",
+        );
+        for ((host, port), connection) in registry.connections.iter() {
+            let _ = writeln!(
+                out,
+                "rtcm_connection_up{{host=\"{}\",port=\"{}\"}} {}",
+                host, port, connection.connected as u8
+            );
+        }
+        header(
+            &mut out,
+            "rtcm_reconnects_total",
+            "counter",
+            "Reconnections of tcp and Ntrip client connections",
+        );
+        for ((host, port), connection) in registry.connections.iter() {
+            let _ = writeln!(
+                out,
+                "rtcm_reconnects_total{{host=\"{}\",port=\"{}\"}} {}",
+                host, port, connection.reconnects
+            );
+        }
+        header(
+            &mut out,
+            "rtcm_read_timeouts_total",
+            "counter",
+            "Connections without data for the read timeout, then reconnected",
+        );
+        for ((host, port), connection) in registry.connections.iter() {
+            let _ = writeln!(
+                out,
+                "rtcm_read_timeouts_total{{host=\"{}\",port=\"{}\"}} {}",
+                host, port, connection.read_timeouts
+            );
+        }
+        header(
+            &mut out,
+            "rtcm_tcp_server_clients",
+            "gauge",
+            "Clients connected to the tcp server output",
+        );
+        let _ = writeln!(
+            out,
+            "rtcm_tcp_server_clients {}",
+            registry.tcp_server_clients
+        );
+        header(
+            &mut out,
+            "rtcm_tcp_server_dropped_messages_total",
+            "counter",
+            "Messages dropped for slow clients of the tcp server output",
+        );
+        for (client, dropped) in registry.tcp_server_drops.iter() {
+            let _ = writeln!(
+                out,
+                "rtcm_tcp_server_dropped_messages_total{{client=\"{}\"}} {}",
+                client, dropped
+            );
+        }
+        header(
+            &mut out,
+            "rtcm_ntrip_handshake_failures_total",
+            "counter",
+            "Ntrip caster responses other than ICY 200 OK and handshake timeouts",
+        );
+        let _ = writeln!(
+            out,
+            "rtcm_ntrip_handshake_failures_total {}",
+            registry.ntrip_handshake_failures
+        );
+        header(
+            &mut out,
+            "rtcm_latency_seconds",
+            "histogram",
+            "Time between epoch and reception of observation messages",
+        );
+        for ((number, station), histogram) in registry.latencies.iter() {
+            let labels = format!("message=\"{}\",station=\"{}\"", number, station);
+            let mut cumulative = 0;
+            for (i, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count;
+                let bound = LATENCY_BUCKETS_S
+                    .get(i)
+                    .map_or("+Inf".to_string(), |bound| bound.to_string());
+                let _ = writeln!(
+                    out,
+                    "rtcm_latency_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rtcm_latency_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "rtcm_latency_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+        out
+    }
+}
+
+/// Number of RTCM frame headers in `skipped`, data which has been skipped
+/// while searching frames, i.e. headers of frames failing the CRC check
+pub fn count_crc_failures(skipped: &[u8]) -> u64 {
+    skipped
+        .windows(3)
+        .filter(|header| header[0] == 0xd3 && header[1] & 0xfc == 0)
+        .count() as u64
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
@@ -0,0 +1,450 @@
+use crate::annotate::reference_station_id;
+use crate::arguments::ReconnectOptions;
+use crate::json_stream::{message_number, split_values};
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::tcp_handler::{gave_up, is_timeout, Connection, TcpHandler};
+use crate::tls::Stream;
+use crate::watchdog::Watchdog;
+use rtcm_rs::prelude::*;
+use rustls::ClientConfig;
+use serde_json::Value;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+/// MQTT (v. 3.1.1) client with QoS 0, publishing the output messages or
+/// reading the payloads of subscribed topics as input.
+///
+/// Published messages go to a topic from a template where `{station}` is
+/// replaced by the reference station id and `{msg_type}` by the message
+/// number, e.g. `rtcm/{station}/{msg_type}`. The payload is the compact json
+/// message, or in binary mode the RTCM frame.
+pub struct MqttClient {
+    tcp_handler: TcpHandler,
+    client_id: String,
+    credentials: Option<(String, Option<String>)>,
+    keep_alive: Duration,
+    topic: String,
+    subscriptions: Vec<String>,
+    binary: bool,
+    /// Output written but not yet published as complete messages
+    buffer: Vec<u8>,
+    /// Packets received but not yet complete
+    input: Vec<u8>,
+    /// Payloads received but not yet read
+    payloads: Vec<u8>,
+    last_sent: Instant,
+    /// Brokers answer the pings, so a connection without any packet received
+    /// for 1.5 keep-alive intervals is considered dead
+    last_received: Instant,
+}
+
+impl MqttClient {
+    pub fn new(addr: String, port: u16, reconnect: ReconnectOptions, client_id: String) -> Self {
+        MqttClient {
+            tcp_handler: TcpHandler::new(addr, port, reconnect),
+            client_id,
+            credentials: None,
+            keep_alive: Duration::from_secs(60),
+            topic: "rtcm/{station}/{msg_type}".into(),
+            subscriptions: Vec::new(),
+            binary: false,
+            buffer: Vec::new(),
+            input: Vec::new(),
+            payloads: Vec::new(),
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        }
+    }
+    pub fn set_credentials(&mut self, username: String, password: Option<String>) {
+        self.credentials = Some((username, password));
+    }
+    /// Topic template of published messages
+    pub fn set_topic(&mut self, topic: String) {
+        self.topic = topic;
+    }
+    /// Topic filters to subscribe to, e.g. `rtcm/#`
+    pub fn set_subscriptions(&mut self, subscriptions: Vec<String>) {
+        self.subscriptions = subscriptions;
+    }
+    /// Publishes RTCM frames instead of json messages
+    pub fn set_binary(&mut self, binary: bool) {
+        self.binary = binary;
+    }
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.tcp_handler.set_watchdog(watchdog);
+    }
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.tcp_handler.set_metrics(metrics);
+    }
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>) {
+        self.tcp_handler.set_tls(config);
+    }
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.tcp_handler.set_proxy(proxy);
+    }
+    /// Connected stream, after the CONNECT and SUBSCRIBE handshake of a new
+    /// connection
+    fn stream(&mut self) -> std::io::Result<&mut Stream> {
+        let new_connection = matches!(
+            self.tcp_handler.get_stream_reconnect()?,
+            Connection::NewConnection(_)
+        );
+        if new_connection {
+            self.input.clear();
+            if let Err(e) = self.handshake() {
+                self.tcp_handler.discard_stream();
+                return Err(e);
+            }
+        }
+        self.tcp_handler
+            .get_stream()
+            .ok_or_else(|| ErrorKind::NotConnected.into())
+    }
+    fn handshake(&mut self) -> std::io::Result<()> {
+        let mut flags = 0x02; // clean session
+        let mut payload = encode_string(&self.client_id);
+        if let Some((username, password)) = self.credentials.as_ref() {
+            flags |= 0x80;
+            payload.extend(encode_string(username));
+            if let Some(password) = password {
+                flags |= 0x40;
+                payload.extend(encode_string(password));
+            }
+        }
+        let mut connect = encode_string("MQTT");
+        connect.extend([4, flags]);
+        connect.extend((self.keep_alive.as_secs() as u16).to_be_bytes());
+        connect.extend(payload);
+        let keep_alive = self.keep_alive;
+        let stream = self
+            .tcp_handler
+            .get_stream()
+            .ok_or(ErrorKind::NotConnected)?;
+        stream.tcp().set_read_timeout(Some(keep_alive / 2))?;
+        stream.write_all(&encode_packet(CONNECT, &connect))?;
+        let (header, connack) = read_packet(stream, &mut self.input)?;
+        if header & 0xf0 != CONNACK || connack.get(1) != Some(&0) {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        if !self.subscriptions.is_empty() {
+            let mut subscribe = 1u16.to_be_bytes().to_vec();
+            for filter in self.subscriptions.iter() {
+                subscribe.extend(encode_string(filter));
+                subscribe.push(0);
+            }
+            stream.write_all(&encode_packet(SUBSCRIBE, &subscribe))?;
+            let (header, suback) = read_packet(stream, &mut self.input)?;
+            if header & 0xf0 != SUBACK || suback.iter().skip(2).any(|rc| *rc == 0x80) {
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+        }
+        self.last_sent = Instant::now();
+        self.last_received = Instant::now();
+        Ok(())
+    }
+    fn send(&mut self, packet: &[u8]) -> std::io::Result<()> {
+        let result = self.stream()?.write_all(packet);
+        if result.is_err() {
+            self.tcp_handler.discard_stream();
+        } else {
+            self.last_sent = Instant::now();
+        }
+        result
+    }
+    /// Publishes `payload`, failing only when the connection was given up
+    fn publish(
+        &mut self,
+        number: Option<String>,
+        station: Option<u16>,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        let topic = self
+            .topic
+            .replace(
+                "{station}",
+                &station.map_or("none".to_string(), |s| s.to_string()),
+            )
+            .replace("{msg_type}", number.as_deref().unwrap_or("none"));
+        let mut publish = encode_string(&topic);
+        publish.extend_from_slice(payload);
+        // QoS 0, messages are not resent if the connection is lost
+        match self.send(&encode_packet(PUBLISH, &publish)) {
+            Err(e) if gave_up(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+    /// Handles the packets received, keeping the payloads of PUBLISH.
+    /// Discards the connection on a malformed packet, as the following
+    /// packets cannot be found.
+    fn receive(&mut self) -> std::io::Result<()> {
+        loop {
+            let (header, body, len) = match decode_packet(&self.input) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    self.input.clear();
+                    self.tcp_handler.discard_stream();
+                    return Err(e);
+                }
+            };
+            self.input.drain(..len);
+            if header & 0xf0 != PUBLISH {
+                continue;
+            }
+            let qos = (header >> 1) & 0x03;
+            let Some(topic_len) = body
+                .get(..2)
+                .map(|l| u16::from_be_bytes([l[0], l[1]]) as usize)
+            else {
+                continue;
+            };
+            let mut offset = 2 + topic_len;
+            if qos > 0 {
+                if let Some(packet_id) = body.get(offset..offset + 2) {
+                    let packet_id = packet_id.to_vec();
+                    self.send(&encode_packet(PUBACK, &packet_id))?;
+                }
+                offset += 2;
+            }
+            let Some(payload) = body.get(offset..) else {
+                continue;
+            };
+            self.payloads.extend_from_slice(payload);
+            // json messages published without line ending
+            if payload.first() != Some(&0xd3) && !payload.ends_with(b"\n") {
+                self.payloads.push(b'\n');
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for MqttClient {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            self.receive()?;
+            if !self.payloads.is_empty() {
+                break;
+            }
+            let mut packet_buf = [0; 4096];
+            let result = self.stream()?.read(&mut packet_buf);
+            match result {
+                Ok(0) => {
+                    self.tcp_handler.discard_stream();
+                    return Err(ErrorKind::NotConnected.into());
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&packet_buf[..n]);
+                    self.last_received = Instant::now();
+                }
+                Err(e) if is_timeout(&e) => {
+                    let silent = self.last_received.elapsed();
+                    if silent > self.keep_alive * 3 / 2 {
+                        self.tcp_handler.discard_stalled_stream(silent);
+                        return Err(ErrorKind::TimedOut.into());
+                    }
+                }
+                Err(e) => {
+                    self.tcp_handler.discard_stream();
+                    return Err(e);
+                }
+            }
+            if self.last_sent.elapsed() >= self.keep_alive / 2 {
+                self.send(&[PINGREQ, 0])?;
+            }
+        }
+        let n = buf.len().min(self.payloads.len());
+        buf[..n].copy_from_slice(&self.payloads[..n]);
+        self.payloads.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for MqttClient {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let buffer = std::mem::take(&mut self.buffer);
+        let consumed = if self.binary {
+            let mut iter = MsgFrameIter::new(&buffer);
+            for mf in &mut iter {
+                let number = mf.message_number().map(|n| n.to_string());
+                let station = reference_station_id(&mf.get_message());
+                self.publish(number, Some(station), mf.frame_data())?;
+            }
+            iter.consumed()
+        } else {
+            let (values, consumed) = split_values(&buffer);
+            for value in values {
+                let number = message_number(&value)
+                    .map(|n| n.to_string())
+                    .or_else(|| value.as_object()?.keys().next().cloned());
+                let station = value
+                    .as_object()
+                    .and_then(|fields| fields.values().next())
+                    .and_then(|fields| fields.get("reference_station_id"))
+                    .and_then(Value::as_u64)
+                    .map(|station| station as u16);
+                self.publish(number, station, value.to_string().as_bytes())?;
+            }
+            consumed
+        };
+        self.buffer = buffer[consumed..].to_vec();
+        if self.last_sent.elapsed() >= self.keep_alive / 2 {
+            let _ = self.send(&[PINGREQ, 0]);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.tcp_handler.get_stream() {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MqttClient {
+    fn drop(&mut self) {
+        if let Some(stream) = self.tcp_handler.get_stream() {
+            let _ = stream.write_all(&[DISCONNECT, 0]);
+        }
+    }
+}
+
+fn encode_string(s: &str) -> Vec<u8> {
+    let mut encoded = (s.len() as u16).to_be_bytes().to_vec();
+    encoded.extend_from_slice(s.as_bytes());
+    encoded
+}
+
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            packet.push(byte | 0x80);
+        } else {
+            packet.push(byte);
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Header, body and length of the first complete packet in `data`, none if
+/// incomplete. Fails for a remaining length of more than 4 bytes.
+fn decode_packet(data: &[u8]) -> std::io::Result<Option<(u8, Vec<u8>, usize)>> {
+    let Some(&header) = data.first() else {
+        return Ok(None);
+    };
+    let mut len = 0;
+    let mut offset = 1;
+    loop {
+        let Some(&byte) = data.get(offset) else {
+            return Ok(None);
+        };
+        len += ((byte & 0x7f) as usize) << (7 * (offset - 1));
+        offset += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if offset > 4 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "malformed packet remaining length",
+            ));
+        }
+    }
+    Ok(data
+        .get(offset..offset + len)
+        .map(|body| (header, body.to_vec(), offset + len)))
+}
+
+/// Reads a single packet during the handshake, keeping any following data in
+/// `input`
+fn read_packet(stream: &mut Stream, input: &mut Vec<u8>) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut buf = [0; 1024];
+    loop {
+        if let Some((header, body, len)) = decode_packet(input)? {
+            input.drain(..len);
+            return Ok((header, body));
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(ErrorKind::NotConnected.into());
+        }
+        input.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length_encoding() {
+        // examples of the MQTT 3.1.1 specification, section 2.2.3
+        for (len, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xff, 0xff, 0x7f]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let body = vec![0x5a; len];
+            let packet = encode_packet(PUBLISH, &body);
+            assert_eq!(packet[0], PUBLISH);
+            assert_eq!(&packet[1..1 + encoded.len()], encoded, "{} bytes", len);
+            let (header, decoded, packet_len) = decode_packet(&packet).unwrap().unwrap();
+            assert_eq!((header, packet_len), (PUBLISH, packet.len()));
+            assert_eq!(decoded, body);
+        }
+    }
+
+    #[test]
+    fn decode_packets() {
+        let mut data = encode_packet(SUBACK, &[0, 1, 0]);
+        assert_eq!(data, [SUBACK, 3, 0, 1, 0]);
+        data.extend(encode_packet(PUBLISH, &encode_string("a/b")));
+        assert_eq!(
+            decode_packet(&data).unwrap(),
+            Some((SUBACK, vec![0, 1, 0], 5))
+        );
+        assert_eq!(
+            decode_packet(&data[5..]).unwrap(),
+            Some((PUBLISH, vec![0, 3, b'a', b'/', b'b'], 7))
+        );
+        // incomplete packets
+        for len in 0..5 {
+            assert_eq!(decode_packet(&data[..len]).unwrap(), None);
+        }
+        assert_eq!(
+            decode_packet(&[PUBLISH, 0xff, 0xff, 0xff, 0x7f]).unwrap(),
+            None
+        );
+        assert_eq!(decode_packet(&[PUBLISH, 0x80, 0x80, 0x80]).unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_remaining_length() {
+        let error = decode_packet(&[PUBLISH, 0xff, 0xff, 0xff, 0xff, 0x01]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(decode_packet(&[PUBLISH, 0x80, 0x80, 0x80, 0x80]).is_err());
+    }
+}
@@ -0,0 +1,875 @@
+use rtcm_rs::msg::{BdsSigId, GalSigId, GloSigId, GpsSigId, NavicSigId, QzssSigId, SbasSigId};
+use rtcm_rs::Message;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub const CLIGHT: f64 = 299792458.0;
+/// Range in meters corresponding to one light millisecond
+pub const RANGE_MS: f64 = CLIGHT * 0.001;
+/// Maximum number of cells (satellite and signal combinations) of an MSM message
+const MAX_CELLS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Gnss {
+    Gps,
+    Glonass,
+    Galileo,
+    Sbas,
+    Qzss,
+    Beidou,
+    Navic,
+}
+
+impl Gnss {
+    /// Constellation and MSM type (1-7) of an MSM message number
+    pub fn from_msm_number(number: u16) -> Option<(Gnss, u8)> {
+        let gnss = match number / 10 {
+            107 => Gnss::Gps,
+            108 => Gnss::Glonass,
+            109 => Gnss::Galileo,
+            110 => Gnss::Sbas,
+            111 => Gnss::Qzss,
+            112 => Gnss::Beidou,
+            113 => Gnss::Navic,
+            _ => return None,
+        };
+        let msm_type = (number % 10) as u8;
+        (1..=7).contains(&msm_type).then_some((gnss, msm_type))
+    }
+    /// Constellation of a RINEX satellite system identifier
+    pub fn from_system_char(system: char) -> Option<Gnss> {
+        match system {
+            'G' => Some(Gnss::Gps),
+            'R' => Some(Gnss::Glonass),
+            'E' => Some(Gnss::Galileo),
+            'S' => Some(Gnss::Sbas),
+            'J' => Some(Gnss::Qzss),
+            'C' => Some(Gnss::Beidou),
+            'I' => Some(Gnss::Navic),
+            _ => None,
+        }
+    }
+    pub fn msm_number(self, msm_type: u8) -> u16 {
+        let base = match self {
+            Gnss::Gps => 1070,
+            Gnss::Glonass => 1080,
+            Gnss::Galileo => 1090,
+            Gnss::Sbas => 1100,
+            Gnss::Qzss => 1110,
+            Gnss::Beidou => 1120,
+            Gnss::Navic => 1130,
+        };
+        base + msm_type as u16
+    }
+    /// RINEX satellite system identifier
+    pub fn system_char(self) -> char {
+        match self {
+            Gnss::Gps => 'G',
+            Gnss::Glonass => 'R',
+            Gnss::Galileo => 'E',
+            Gnss::Sbas => 'S',
+            Gnss::Qzss => 'J',
+            Gnss::Beidou => 'C',
+            Gnss::Navic => 'I',
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Gnss::Gps => "GPS",
+            Gnss::Glonass => "GLONASS",
+            Gnss::Galileo => "Galileo",
+            Gnss::Sbas => "SBAS",
+            Gnss::Qzss => "QZSS",
+            Gnss::Beidou => "BeiDou",
+            Gnss::Navic => "NavIC",
+        }
+    }
+    /// Name of the epoch time field in the serde representation of MSM messages
+    pub fn epoch_time_field(self) -> &'static str {
+        match self {
+            Gnss::Gps | Gnss::Sbas => "gps_epoch_time_ms",
+            Gnss::Glonass => "glo_epoch_time_ms",
+            Gnss::Galileo => "gal_epoch_time_ms",
+            Gnss::Qzss => "qzss_epoch_time_ms",
+            Gnss::Beidou => "bds_epoch_time_ms",
+            Gnss::Navic => "navic_epoch_time_ms",
+        }
+    }
+    /// RINEX satellite number of an MSM satellite id
+    pub fn rinex_prn(self, satellite_id: u8) -> u8 {
+        match self {
+            // MSM satellite id 1 corresponds to SBAS PRN 120, i.e. RINEX S20
+            Gnss::Sbas => satellite_id + 19,
+            _ => satellite_id,
+        }
+    }
+    /// MSM satellite id of a RINEX satellite number, the inverse of `rinex_prn`
+    pub fn msm_satellite_id(self, prn: u8) -> Option<u8> {
+        let satellite_id = match self {
+            Gnss::Sbas => prn.checked_sub(19)?,
+            _ => prn,
+        };
+        (1..=64).contains(&satellite_id).then_some(satellite_id)
+    }
+    /// Whether MSM messages of this constellation can carry the signal
+    pub fn is_msm_signal(self, band: u8, attribute: char) -> bool {
+        match self {
+            Gnss::Gps => GpsSigId::new(band, attribute).is_valid(),
+            Gnss::Glonass => GloSigId::new(band, attribute).is_valid(),
+            Gnss::Galileo => GalSigId::new(band, attribute).is_valid(),
+            Gnss::Sbas => SbasSigId::new(band, attribute).is_valid(),
+            Gnss::Qzss => QzssSigId::new(band, attribute).is_valid(),
+            Gnss::Beidou => BdsSigId::new(band, attribute).is_valid(),
+            Gnss::Navic => NavicSigId::new(band, attribute).is_valid(),
+        }
+    }
+    /// Carrier frequency (Hz) of a RINEX frequency band,
+    /// GLONASS FDMA bands require the frequency channel number
+    pub fn frequency(self, band: u8, glonass_fcn: Option<i8>) -> Option<f64> {
+        match (self, band) {
+            (Gnss::Glonass, 1) => glonass_fcn.map(|k| 1602.0e6 + k as f64 * 0.5625e6),
+            (Gnss::Glonass, 2) => glonass_fcn.map(|k| 1246.0e6 + k as f64 * 0.4375e6),
+            (Gnss::Glonass, 3) => Some(1202.025e6),
+            (Gnss::Glonass, 4) => Some(1600.995e6),
+            (Gnss::Glonass, 6) => Some(1248.06e6),
+            (Gnss::Beidou, 2) => Some(1561.098e6),
+            (Gnss::Beidou, 6) => Some(1268.52e6),
+            (Gnss::Navic, 5) => Some(1176.45e6),
+            (Gnss::Navic, 9) => Some(2492.028e6),
+            (Gnss::Galileo | Gnss::Qzss, 6) => Some(1278.75e6),
+            (Gnss::Galileo | Gnss::Beidou, 7) => Some(1207.14e6),
+            (Gnss::Galileo | Gnss::Beidou, 8) => Some(1191.795e6),
+            (Gnss::Gps | Gnss::Qzss, 2) => Some(1227.60e6),
+            (Gnss::Glonass | Gnss::Navic, _) => None,
+            (_, 1) => Some(1575.42e6),
+            (_, 5) => Some(1176.45e6),
+            _ => None,
+        }
+    }
+}
+
+/// Observables of one signal of one satellite in an MSM message
+#[derive(Clone, Debug, Default)]
+pub struct SignalObservation {
+    pub satellite_id: u8,
+    pub band: u8,
+    pub attribute: char,
+    pub glonass_fcn: Option<i8>,
+    /// Full pseudorange if the integer millisecond part is known (MSM4-7),
+    /// otherwise the pseudorange modulo one light millisecond (MSM1-3)
+    pub pseudorange_m: Option<f64>,
+    /// Full carrier phase if the integer millisecond part is known (MSM4-7),
+    /// otherwise the carrier phase modulo one light millisecond (MSM2-3)
+    pub carrier_phase_cycles: Option<f64>,
+    pub doppler_hz: Option<f64>,
+    pub cnr_dbhz: Option<f64>,
+    /// Minimum lock time in milliseconds
+    pub lock_time_ms: Option<u64>,
+    pub half_cycle_ambiguity: Option<bool>,
+}
+
+impl SignalObservation {
+    /// RINEX observation code suffix, e.g. "1C"
+    pub fn code(&self) -> String {
+        format!("{}{}", self.band, self.attribute)
+    }
+}
+
+/// MSM message content in engineering units
+#[derive(Clone, Debug)]
+pub struct MsmObservations {
+    pub gnss: Gnss,
+    pub msm_type: u8,
+    pub reference_station_id: u16,
+    /// Time of week in ms, or time of day (Moscow time) for GLONASS
+    pub epoch_time_ms: u32,
+    pub glo_day_of_week: Option<u8>,
+    pub multiple_message: bool,
+    pub issue_of_data_station: Option<u8>,
+    pub clock_steering_ind: u8,
+    pub external_clock_ind: u8,
+    pub smoothing_type_ind: u8,
+    pub smoothing_interval_index: u8,
+    pub signals: Vec<SignalObservation>,
+}
+
+impl MsmObservations {
+    pub fn message_number(&self) -> u16 {
+        self.gnss.msm_number(self.msm_type)
+    }
+    pub fn integer_ms_known(&self) -> bool {
+        self.msm_type >= 4
+    }
+    pub fn to_json(&self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("message_number".into(), json!(self.message_number()));
+        fields.insert("gnss".into(), json!(self.gnss.name()));
+        fields.insert("msm_type".into(), json!(self.msm_type));
+        fields.insert(
+            "reference_station_id".into(),
+            json!(self.reference_station_id),
+        );
+        if let Some(day) = self.glo_day_of_week {
+            fields.insert("glo_day_of_week".into(), json!(day));
+        }
+        fields.insert("epoch_time_ms".into(), json!(self.epoch_time_ms));
+        fields.insert("multiple_message".into(), json!(self.multiple_message));
+        fields.insert(
+            "issue_of_data_station".into(),
+            json!(self.issue_of_data_station),
+        );
+        fields.insert("clock_steering_ind".into(), json!(self.clock_steering_ind));
+        fields.insert("external_clock_ind".into(), json!(self.external_clock_ind));
+        fields.insert("smoothing_type_ind".into(), json!(self.smoothing_type_ind));
+        fields.insert(
+            "smoothing_interval_index".into(),
+            json!(self.smoothing_interval_index),
+        );
+        let observations = self
+            .signals
+            .iter()
+            .map(|s| {
+                let mut obs = Map::new();
+                obs.insert(
+                    "satellite".into(),
+                    json!(format!(
+                        "{}{:02}",
+                        self.gnss.system_char(),
+                        self.gnss.rinex_prn(s.satellite_id)
+                    )),
+                );
+                obs.insert("signal".into(), json!(s.code()));
+                if let Some(fcn) = s.glonass_fcn {
+                    obs.insert("glonass_fcn".into(), json!(fcn));
+                }
+                let (pseudorange_key, carrier_phase_key) = if self.integer_ms_known() {
+                    ("pseudorange_m", "carrier_phase_cycles")
+                } else {
+                    ("pseudorange_mod_1ms_m", "carrier_phase_mod_1ms_cycles")
+                };
+                let optional = [
+                    (pseudorange_key, s.pseudorange_m.map(|v| json!(v))),
+                    (carrier_phase_key, s.carrier_phase_cycles.map(|v| json!(v))),
+                    ("doppler_hz", s.doppler_hz.map(|v| json!(v))),
+                    ("cnr_dbhz", s.cnr_dbhz.map(|v| json!(v))),
+                    (
+                        "lock_time_s",
+                        s.lock_time_ms.map(|v| json!(v as f64 * 0.001)),
+                    ),
+                    (
+                        "half_cycle_ambiguity",
+                        s.half_cycle_ambiguity.map(|v| json!(v)),
+                    ),
+                ];
+                for (key, value) in optional {
+                    if let Some(value) = value {
+                        obs.insert(key.into(), value);
+                    }
+                }
+                Value::Object(obs)
+            })
+            .collect::<Vec<_>>();
+        fields.insert("observations".into(), Value::Array(observations));
+        json!({ "DecodedMsm": fields })
+    }
+}
+
+/// Decodes MSM messages into observables. GLONASS frequency channel numbers
+/// are learned from MSM5/7, 1010, 1012 and 1020 messages to allow conversion of
+/// GLONASS carrier phase and Doppler in MSM types lacking them.
+#[derive(Default)]
+pub struct MsmDecoder {
+    glonass_fcn: HashMap<u8, i8>,
+}
+
+impl MsmDecoder {
+    pub fn new() -> Self {
+        MsmDecoder::default()
+    }
+    pub fn glonass_fcn(&self, satellite_id: u8) -> Option<i8> {
+        self.glonass_fcn.get(&satellite_id).copied()
+    }
+    /// Decodes `msg` if it is an MSM message, and learns GLONASS frequency
+    /// channel numbers from any message carrying them
+    pub fn decode(&mut self, msg: &Message) -> Option<MsmObservations> {
+        match msg {
+            Message::Msg1020(m) => {
+                self.glonass_fcn
+                    .insert(m.glo_satellite_id, m.glo_satellite_freq_chan_number);
+                return None;
+            }
+            Message::Msg1010(m) => {
+                for sat in m.satellites.iter() {
+                    self.glonass_fcn
+                        .insert(sat.glo_satellite_id, sat.glo_satellite_freq_chan_number);
+                }
+                return None;
+            }
+            Message::Msg1012(m) => {
+                for sat in m.satellites.iter() {
+                    self.glonass_fcn
+                        .insert(sat.glo_satellite_id, sat.glo_satellite_freq_chan_number);
+                }
+                return None;
+            }
+            _ => {}
+        }
+        let (gnss, msm_type) = msg.number().and_then(Gnss::from_msm_number)?;
+        let value = serde_json::to_value(msg).ok()?;
+        let fields = value.get(format!("Msg{}", gnss.msm_number(msm_type)))?;
+        self.decode_fields(gnss, msm_type, fields)
+    }
+    fn decode_fields(
+        &mut self,
+        gnss: Gnss,
+        msm_type: u8,
+        fields: &Value,
+    ) -> Option<MsmObservations> {
+        let data_segment = fields.get("data_segment")?;
+        let mut satellites: HashMap<u8, SatelliteData> = HashMap::new();
+        for sat in data_segment.get("satellite_data")?.as_array()? {
+            let satellite_id = get_u64(sat, "satellite_id")? as u8;
+            let mod1ms = get_f64(sat, "gnss_satellite_rough_range_mod1ms_ms");
+            let rough_range = if msm_type >= 4 {
+                get_f64(sat, "gnss_satellite_rough_range_integer_ms")
+                    .and_then(|int_ms| mod1ms.map(|m| int_ms + m))
+            } else {
+                mod1ms
+            };
+            let rough_rate = get_f64(sat, "gnss_satellite_rough_phaserange_rates_m_s");
+            let fcn = get_f64(sat, "glonass_satellite_frequency_channel_number").map(|v| v as i8);
+            if let (Gnss::Glonass, Some(fcn)) = (gnss, fcn) {
+                self.glonass_fcn.insert(satellite_id, fcn);
+            }
+            satellites.insert(
+                satellite_id,
+                SatelliteData {
+                    rough_range,
+                    rough_rate,
+                    fcn,
+                },
+            );
+        }
+        let mut signals = Vec::new();
+        for sig in data_segment.get("signal_data")?.as_array()? {
+            let satellite_id = get_u64(sig, "satellite_id")? as u8;
+            let signal_id = sig.get("signal_id")?.as_array()?;
+            let band = signal_id.first()?.as_u64()? as u8;
+            let attribute = signal_id.get(1)?.as_str()?.chars().next()?;
+            let SatelliteData {
+                rough_range,
+                rough_rate,
+                fcn,
+            } = satellites.get(&satellite_id).copied().unwrap_or_default();
+            let glonass_fcn = match gnss {
+                Gnss::Glonass => fcn.or_else(|| self.glonass_fcn(satellite_id)),
+                _ => None,
+            };
+            let frequency = gnss.frequency(band, glonass_fcn);
+            let fine_pseudorange = get_f64(sig, "gnss_signal_fine_pseudorange_ms")
+                .or_else(|| get_f64(sig, "gnss_signal_fine_pseudorange_ext_ms"));
+            let fine_phaserange = get_f64(sig, "gnss_signal_fine_phaserange_ms")
+                .or_else(|| get_f64(sig, "gnss_signal_fine_phaserange_ext_ms"));
+            let fine_rate = get_f64(sig, "gnss_signal_fine_phaserange_rate_m_s");
+            let lock_time_ms = get_u64(sig, "gnss_phaserange_lock_time_ind")
+                .map(lock_time_ind_ms)
+                .or_else(|| {
+                    get_u64(sig, "gnss_phaserange_lock_time_ext_ind").map(lock_time_ext_ind_ms)
+                });
+            signals.push(SignalObservation {
+                satellite_id,
+                band,
+                attribute,
+                glonass_fcn,
+                pseudorange_m: rough_range
+                    .zip(fine_pseudorange)
+                    .map(|(r, f)| (r + f) * RANGE_MS),
+                carrier_phase_cycles: rough_range
+                    .zip(fine_phaserange)
+                    .zip(frequency)
+                    .map(|((r, f), freq)| (r + f) * RANGE_MS * freq / CLIGHT),
+                doppler_hz: rough_rate
+                    .zip(fine_rate)
+                    .zip(frequency)
+                    .map(|((r, f), freq)| -(r + f) * freq / CLIGHT),
+                cnr_dbhz: get_f64(sig, "gnss_signal_cnr_dbhz")
+                    .or_else(|| get_f64(sig, "gnss_signal_cnr_ext_dbhz")),
+                lock_time_ms,
+                half_cycle_ambiguity: get_u64(sig, "half_cycle_ambiguity_ind").map(|v| v != 0),
+            });
+        }
+        Some(MsmObservations {
+            gnss,
+            msm_type,
+            reference_station_id: get_u64(fields, "reference_station_id")? as u16,
+            epoch_time_ms: get_u64(fields, gnss.epoch_time_field())? as u32,
+            glo_day_of_week: get_u64(fields, "glo_day_of_week").map(|v| v as u8),
+            multiple_message: get_u64(fields, "msm_multiple_message_flag")? != 0,
+            issue_of_data_station: get_u64(fields, "issue_of_data_station").map(|v| v as u8),
+            clock_steering_ind: get_u64(fields, "clock_steering_ind").unwrap_or(0) as u8,
+            external_clock_ind: get_u64(fields, "external_clock_ind").unwrap_or(0) as u8,
+            smoothing_type_ind: get_u64(fields, "gnss_smoothing_type_ind").unwrap_or(0) as u8,
+            smoothing_interval_index: get_u64(fields, "gnss_smoothing_interval_index").unwrap_or(0)
+                as u8,
+            signals,
+        })
+    }
+}
+
+/// Integer numbers of cycles removed from carrier phases so they fit the
+/// range of a message field relative to a reference range. The shift is kept
+/// per signal and the lock time restarts whenever it changes.
+#[derive(Default)]
+pub struct PhaseOffsets {
+    /// Cycles removed from the carrier phase, and lock time (ms) at the last
+    /// change of the shift, per constellation, satellite and signal
+    offsets: HashMap<(Gnss, u8, u8, char), (f64, u64)>,
+}
+
+impl PhaseOffsets {
+    /// Shifted carrier phase of `sig` in meters minus `reference_m`, within
+    /// ±`limit_m`, and the lock time (ms) since the last change of the shift
+    pub fn phase_minus_reference(
+        &mut self,
+        gnss: Gnss,
+        sig: &SignalObservation,
+        wavelength: f64,
+        reference_m: f64,
+        limit_m: f64,
+    ) -> Option<(f64, u64)> {
+        let cycles = sig.carrier_phase_cycles?;
+        let mut lock_time_ms = sig.lock_time_ms.unwrap_or(0);
+        let key = (gnss, sig.satellite_id, sig.band, sig.attribute);
+        let (offset, lock_base) = self.offsets.entry(key).or_insert((f64::NAN, lock_time_ms));
+        let mut difference = (cycles - *offset) * wavelength - reference_m;
+        if offset.is_nan() || difference.abs() >= limit_m {
+            *offset = ((cycles * wavelength - reference_m) / wavelength).round();
+            *lock_base = lock_time_ms;
+            difference = (cycles - *offset) * wavelength - reference_m;
+        }
+        if lock_time_ms < *lock_base {
+            *lock_base = 0;
+        }
+        lock_time_ms -= *lock_base;
+        Some((difference, lock_time_ms))
+    }
+}
+
+/// Encodes observables into MSM messages, with carrier phases shifted to fit
+/// the MSM range relative to the rough range (see `PhaseOffsets`)
+#[derive(Default)]
+pub struct MsmEncoder {
+    phase_offsets: PhaseOffsets,
+}
+
+impl MsmEncoder {
+    pub fn new() -> Self {
+        MsmEncoder::default()
+    }
+    /// Encodes `obs` as MSM messages of type `obs.msm_type`, split so that no
+    /// message exceeds 64 cells. The multiple message flag is set on all but
+    /// the last message, or on all messages if `obs.multiple_message` is set.
+    /// Signals not defined for MSM and satellites without pseudorange are left out.
+    pub fn encode(&mut self, obs: &MsmObservations) -> Vec<Message> {
+        let mut satellites: BTreeMap<u8, Vec<&SignalObservation>> = BTreeMap::new();
+        for sig in obs
+            .signals
+            .iter()
+            .filter(|s| obs.gnss.is_msm_signal(s.band, s.attribute))
+        {
+            satellites.entry(sig.satellite_id).or_default().push(sig);
+        }
+        let mut chunks = Vec::new();
+        let mut chunk = Vec::new();
+        let mut chunk_signals = BTreeSet::new();
+        for (_, sigs) in satellites {
+            let mut signals = chunk_signals.clone();
+            signals.extend(sigs.iter().map(|s| (s.band, s.attribute)));
+            if !chunk.is_empty() && (chunk.len() + 1) * signals.len() > MAX_CELLS {
+                chunks.push(std::mem::take(&mut chunk));
+                signals = sigs.iter().map(|s| (s.band, s.attribute)).collect();
+            }
+            chunk_signals = signals;
+            chunk.push(sigs);
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        let count = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, chunk)| {
+                self.encode_message(obs, &chunk, obs.multiple_message || i + 1 < count)
+            })
+            .collect()
+    }
+    fn encode_message(
+        &mut self,
+        obs: &MsmObservations,
+        satellites: &[Vec<&SignalObservation>],
+        multiple_message: bool,
+    ) -> Option<Message> {
+        let msm_type = obs.msm_type;
+        let extended = msm_type >= 6;
+        let mut satellite_data = Vec::new();
+        let mut signal_data = Vec::new();
+        for sigs in satellites {
+            let Some(rough_ms) = sigs
+                .iter()
+                .find_map(|s| s.pseudorange_m)
+                .map(|pr| (pr / RANGE_MS * 1024.0).round() / 1024.0)
+                .filter(|ms| (0.0..255.0).contains(ms))
+            else {
+                continue;
+            };
+            let fcn = sigs.iter().find_map(|s| s.glonass_fcn);
+            let rough_rate = sigs
+                .iter()
+                .find_map(|s| {
+                    s.doppler_hz
+                        .zip(obs.gnss.frequency(s.band, s.glonass_fcn))
+                        .map(|(doppler, freq)| (-doppler * CLIGHT / freq).round())
+                })
+                .filter(|rate| rate.abs() < 8192.0);
+            let mut sat = Map::new();
+            sat.insert("satellite_id".into(), json!(sigs[0].satellite_id));
+            if msm_type >= 4 {
+                sat.insert(
+                    "gnss_satellite_rough_range_integer_ms".into(),
+                    json!(rough_ms.floor() as u8),
+                );
+            }
+            if msm_type == 5 || msm_type == 7 {
+                match obs.gnss {
+                    Gnss::Glonass => sat.insert(
+                        "glonass_satellite_frequency_channel_number".into(),
+                        json!(fcn),
+                    ),
+                    _ => sat.insert("reserved_8_4".into(), json!(0)),
+                };
+            }
+            sat.insert(
+                "gnss_satellite_rough_range_mod1ms_ms".into(),
+                json!(rough_ms.fract()),
+            );
+            if msm_type == 5 || msm_type == 7 {
+                sat.insert(
+                    "gnss_satellite_rough_phaserange_rates_m_s".into(),
+                    json!(rough_rate.map(|rate| rate as i16)),
+                );
+            }
+            satellite_data.push(Value::Object(sat));
+
+            for s in sigs {
+                let frequency = obs.gnss.frequency(s.band, s.glonass_fcn);
+                let fine_pseudorange = s
+                    .pseudorange_m
+                    .map(|pr| pr / RANGE_MS - rough_ms)
+                    .filter(|ms| ms.abs() < 1.0 / 1024.0);
+                let (fine_phaserange, lock_time_ms) = match frequency.and_then(|freq| {
+                    self.phase_offsets.phase_minus_reference(
+                        obs.gnss,
+                        s,
+                        CLIGHT / freq,
+                        rough_ms * RANGE_MS,
+                        RANGE_MS / 256.0,
+                    )
+                }) {
+                    Some((fine_m, lock_time_ms)) => (Some(fine_m / RANGE_MS), lock_time_ms),
+                    None => (None, s.lock_time_ms.unwrap_or(0)),
+                };
+                let fine_rate = s
+                    .doppler_hz
+                    .zip(frequency)
+                    .zip(rough_rate)
+                    .map(|((doppler, freq), rough)| -doppler * CLIGHT / freq - rough)
+                    .filter(|rate| rate.abs() < 1.6383);
+                let mut sig = Map::new();
+                sig.insert("satellite_id".into(), json!(s.satellite_id));
+                sig.insert("signal_id".into(), json!([s.band, s.attribute.to_string()]));
+                let suffix = if extended { "_ext" } else { "" };
+                if msm_type != 2 {
+                    sig.insert(
+                        format!("gnss_signal_fine_pseudorange{}_ms", suffix),
+                        json!(fine_pseudorange),
+                    );
+                }
+                if msm_type >= 2 {
+                    sig.insert(
+                        format!("gnss_signal_fine_phaserange{}_ms", suffix),
+                        json!(fine_phaserange),
+                    );
+                    let lock_time_ind = if extended {
+                        json!(lock_time_ext_ind(lock_time_ms))
+                    } else {
+                        json!(lock_time_ind(lock_time_ms))
+                    };
+                    sig.insert(
+                        format!("gnss_phaserange_lock_time{}_ind", suffix),
+                        lock_time_ind,
+                    );
+                    sig.insert(
+                        "half_cycle_ambiguity_ind".into(),
+                        json!(s.half_cycle_ambiguity.unwrap_or(false) as u8),
+                    );
+                }
+                if msm_type >= 4 {
+                    let cnr = if extended {
+                        s.cnr_dbhz
+                            .map(|cnr| (cnr * 16.0).round() / 16.0)
+                            .filter(|cnr| (0.0625..64.0).contains(cnr))
+                            .map(|cnr| json!(cnr))
+                    } else {
+                        s.cnr_dbhz
+                            .map(f64::round)
+                            .filter(|cnr| (1.0..64.0).contains(cnr))
+                            .map(|cnr| json!(cnr as u8))
+                    };
+                    sig.insert(format!("gnss_signal_cnr{}_dbhz", suffix), json!(cnr));
+                }
+                if msm_type == 5 || msm_type == 7 {
+                    sig.insert(
+                        "gnss_signal_fine_phaserange_rate_m_s".into(),
+                        json!(fine_rate),
+                    );
+                }
+                signal_data.push(Value::Object(sig));
+            }
+        }
+        if satellite_data.is_empty() {
+            return None;
+        }
+        let mut fields = Map::new();
+        fields.insert(
+            "reference_station_id".into(),
+            json!(obs.reference_station_id),
+        );
+        if obs.gnss == Gnss::Glonass {
+            fields.insert("glo_day_of_week".into(), json!(obs.glo_day_of_week));
+        }
+        fields.insert(obs.gnss.epoch_time_field().into(), json!(obs.epoch_time_ms));
+        fields.insert(
+            "msm_multiple_message_flag".into(),
+            json!(multiple_message as u8),
+        );
+        fields.insert(
+            "issue_of_data_station".into(),
+            json!(obs.issue_of_data_station),
+        );
+        fields.insert("reserved_58_7".into(), json!(0));
+        fields.insert("clock_steering_ind".into(), json!(obs.clock_steering_ind));
+        fields.insert("external_clock_ind".into(), json!(obs.external_clock_ind));
+        fields.insert(
+            "gnss_smoothing_type_ind".into(),
+            json!(obs.smoothing_type_ind),
+        );
+        fields.insert(
+            "gnss_smoothing_interval_index".into(),
+            json!(obs.smoothing_interval_index),
+        );
+        fields.insert(
+            "data_segment".into(),
+            json!({ "satellite_data": satellite_data, "signal_data": signal_data }),
+        );
+        let mut value = Map::new();
+        value.insert(
+            format!("Msg{}", obs.message_number()),
+            Value::Object(fields),
+        );
+        serde_json::from_value::<Message>(Value::Object(value)).ok()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct SatelliteData {
+    /// Rough range in ms
+    rough_range: Option<f64>,
+    /// Rough phase range rate in m/s
+    rough_rate: Option<f64>,
+    fcn: Option<i8>,
+}
+
+/// Minimum lock time (ms) of the 4 bit lock time indicator (DF402)
+pub fn lock_time_ind_ms(ind: u64) -> u64 {
+    if ind == 0 {
+        0
+    } else {
+        1 << (ind.min(15) + 4)
+    }
+}
+
+/// Minimum lock time (ms) of the extended lock time indicator (DF407)
+pub fn lock_time_ext_ind_ms(ind: u64) -> u64 {
+    if ind < 64 {
+        ind
+    } else {
+        let k = ((ind.min(704) - 32) / 32) as u32;
+        (1 << (k + 5)) + (1 << k) * (ind.min(704) - (32 * k as u64 + 32))
+    }
+}
+
+/// Lock time indicator (DF402) of a lock time in ms
+pub fn lock_time_ind(lock_time_ms: u64) -> u8 {
+    if lock_time_ms < 32 {
+        0
+    } else {
+        (lock_time_ms.ilog2() - 4).min(15) as u8
+    }
+}
+
+/// Extended lock time indicator (DF407) of a lock time in ms
+pub fn lock_time_ext_ind(lock_time_ms: u64) -> u16 {
+    if lock_time_ms < 64 {
+        lock_time_ms as u16
+    } else {
+        let k = lock_time_ms.ilog2() - 5;
+        let ind = 32 * k as u64 + 32 + ((lock_time_ms - (1 << (k + 5))) >> k);
+        ind.min(704) as u16
+    }
+}
+
+fn get_f64(value: &Value, key: &str) -> Option<f64> {
+    value.get(key).and_then(Value::as_f64)
+}
+fn get_u64(value: &Value, key: &str) -> Option<u64> {
+    value.get(key).and_then(Value::as_u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequencies() {
+        assert_eq!(Gnss::Gps.frequency(1, None), Some(1575.42e6));
+        assert_eq!(Gnss::Gps.frequency(5, None), Some(1176.45e6));
+        assert_eq!(Gnss::Navic.frequency(5, None), Some(1176.45e6));
+        assert_eq!(Gnss::Navic.frequency(9, None), Some(2492.028e6));
+        assert_eq!(Gnss::Glonass.frequency(1, Some(-7)), Some(1598.0625e6));
+        assert_eq!(Gnss::Glonass.frequency(2, None), None);
+        assert_eq!(Gnss::Beidou.frequency(2, None), Some(1561.098e6));
+    }
+
+    #[test]
+    fn lock_time_indicators() {
+        // DF402, minimum lock time 2^(i + 4) ms
+        for (lock_time_ms, ind) in [
+            (0, 0),
+            (31, 0),
+            (32, 1),
+            (63, 1),
+            (64, 2),
+            (524_287, 14),
+            (524_288, 15),
+            (u64::MAX, 15),
+        ] {
+            assert_eq!(lock_time_ind(lock_time_ms), ind, "{} ms", lock_time_ms);
+        }
+        assert_eq!(lock_time_ind_ms(0), 0);
+        assert_eq!(lock_time_ind_ms(1), 32);
+        assert_eq!(lock_time_ind_ms(15), 524_288);
+        // DF407, in ms the indicator below 64, then 2i - 64, 4i - 256, ...
+        for (lock_time_ms, ind) in [
+            (0, 0),
+            (63, 63),
+            (64, 64),
+            (126, 95),
+            (127, 95),
+            (128, 96),
+            (600_000, 484),
+            (66_060_288, 703),
+            (67_108_864, 704),
+            (u64::MAX, 704),
+        ] {
+            assert_eq!(lock_time_ext_ind(lock_time_ms), ind, "{} ms", lock_time_ms);
+        }
+        assert_eq!(lock_time_ext_ind_ms(63), 63);
+        assert_eq!(lock_time_ext_ind_ms(95), 126);
+        assert_eq!(lock_time_ext_ind_ms(96), 128);
+        assert_eq!(lock_time_ext_ind_ms(484), 589_824);
+        assert_eq!(lock_time_ext_ind_ms(703), 66_060_288);
+        assert_eq!(lock_time_ext_ind_ms(704), 67_108_864);
+        assert_eq!(lock_time_ext_ind_ms(1023), 67_108_864);
+        for ind in 0..=704 {
+            assert_eq!(lock_time_ext_ind(lock_time_ext_ind_ms(ind)), ind as u16);
+        }
+    }
+
+    fn observations(msm_type: u8, signal: SignalObservation) -> MsmObservations {
+        MsmObservations {
+            gnss: Gnss::Gps,
+            msm_type,
+            reference_station_id: 1,
+            epoch_time_ms: 345_600_000,
+            glo_day_of_week: None,
+            multiple_message: false,
+            issue_of_data_station: Some(0),
+            clock_steering_ind: 0,
+            external_clock_ind: 0,
+            smoothing_type_ind: 0,
+            smoothing_interval_index: 0,
+            signals: vec![signal],
+        }
+    }
+
+    #[test]
+    fn range_fields() {
+        let signal = SignalObservation {
+            satellite_id: 5,
+            band: 1,
+            attribute: 'C',
+            pseudorange_m: Some(20_000_000.0),
+            doppler_hz: Some(-1234.5),
+            cnr_dbhz: Some(45.3),
+            lock_time_ms: Some(600_000),
+            ..Default::default()
+        };
+        let messages = MsmEncoder::new().encode(&observations(7, signal.clone()));
+        assert_eq!(messages.len(), 1);
+        let value = serde_json::to_value(&messages[0]).unwrap();
+        let fields = &value["Msg1077"];
+        let sat = &fields["data_segment"]["satellite_data"][0];
+        let sig = &fields["data_segment"]["signal_data"][0];
+        // 20000 km = 66.712819 light ms, the rough range rounded to 2^-10 ms
+        assert_eq!(sat["gnss_satellite_rough_range_integer_ms"], 66);
+        assert_eq!(sat["gnss_satellite_rough_range_mod1ms_ms"], 0.712890625);
+        let fine = get_f64(sig, "gnss_signal_fine_pseudorange_ext_ms").unwrap();
+        assert!((fine - -7.158537e-5).abs() < 2f64.powi(-29), "{}", fine);
+        // -1234.5 Hz on L1 = 234.9175 m/s
+        assert_eq!(sat["gnss_satellite_rough_phaserange_rates_m_s"], 235);
+        let fine_rate = get_f64(sig, "gnss_signal_fine_phaserange_rate_m_s").unwrap();
+        assert!((fine_rate - -0.0825).abs() < 0.0001, "{}", fine_rate);
+        assert_eq!(sig["gnss_phaserange_lock_time_ext_ind"], 484);
+        assert_eq!(sig["gnss_signal_cnr_ext_dbhz"], 45.3125);
+
+        let decoded = MsmDecoder::new().decode(&messages[0]).unwrap();
+        let decoded = &decoded.signals[0];
+        assert!((decoded.pseudorange_m.unwrap() - 20_000_000.0).abs() < 0.001);
+        assert!((decoded.doppler_hz.unwrap() - -1234.5).abs() < 0.001);
+        assert_eq!(decoded.lock_time_ms, Some(589_824));
+
+        let messages = MsmEncoder::new().encode(&observations(4, signal));
+        let value = serde_json::to_value(&messages[0]).unwrap();
+        let sig = &value["Msg1074"]["data_segment"]["signal_data"][0];
+        let fine = get_f64(sig, "gnss_signal_fine_pseudorange_ms").unwrap();
+        assert!((fine - -7.158537e-5).abs() < 2f64.powi(-24), "{}", fine);
+        assert_eq!(sig["gnss_phaserange_lock_time_ind"], 15);
+        assert_eq!(sig["gnss_signal_cnr_dbhz"], 45);
+    }
+
+    #[test]
+    fn range_out_of_range_not_encoded() {
+        // rough range of 255 ms or more does not fit DF397
+        let signal = SignalObservation {
+            satellite_id: 5,
+            band: 1,
+            attribute: 'C',
+            pseudorange_m: Some(255.0 * RANGE_MS),
+            ..Default::default()
+        };
+        assert!(MsmEncoder::new()
+            .encode(&observations(7, signal))
+            .is_empty());
+    }
+}
@@ -1,16 +1,29 @@
+use crate::arguments::ReconnectOptions;
 use crate::coordinate::Coordinate;
-use crate::tcp_handler::TcpHandler;
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::tcp_handler::{is_timeout, Connection, TcpHandler};
+use crate::watchdog::Watchdog;
 use base64::Engine as _;
+use rustls::ClientConfig;
 use std::io::{Read, Write};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 pub struct NtripClient {
     tcp_handler: TcpHandler,
+    /// `<host>:<port>` of the caster, for the Host header of Ntrip 2
+    authority: String,
     mountpoint: String,
+    handshake_timeout: Duration,
+    version: u8,
+    /// Decoder of the chunked transfer encoding, if used by the caster
+    chunked: Option<ChunkedDecoder>,
     credentials: Option<Credentials>,
     nmea_freq: Option<Duration>,
     nmea_coord: Option<Coordinate>,
     latest_nmea_write: Option<Instant>,
+    metrics: Option<Metrics>,
 }
 
 struct Credentials {
@@ -19,65 +32,152 @@ struct Credentials {
 }
 
 impl NtripClient {
-    pub fn new(addr: String, port: u16, reconnect: u64, mountpoint: String) -> Self {
+    pub fn new(addr: String, port: u16, reconnect: ReconnectOptions, mountpoint: String) -> Self {
+        let authority = match addr.contains(':') {
+            true => format!("[{}]:{}", addr, port),
+            false => format!("{}:{}", addr, port),
+        };
         NtripClient {
-            tcp_handler: TcpHandler::new(addr, port, Duration::from_secs(reconnect)),
+            tcp_handler: TcpHandler::new(addr, port, reconnect),
+            authority,
             mountpoint,
+            handshake_timeout: Duration::from_secs(10),
+            version: 1,
+            chunked: None,
             credentials: None,
             nmea_freq: None,
             nmea_coord: None,
             latest_nmea_write: None,
+            metrics: None,
         }
     }
+    /// Time for the caster to respond to the request of the mountpoint
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = handshake_timeout;
+    }
+    /// Ntrip protocol version of the request, 1 or 2
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
     pub fn set_credentials(&mut self, username: String, password: String) {
         self.credentials = Some(Credentials { username, password });
     }
     pub fn set_nmea(&mut self, nmea_coord: Coordinate, nmea_freq: Option<u64>) {
         self.nmea_coord = Some(nmea_coord);
-        self.nmea_freq = nmea_freq.map(|nf| Duration::from_secs(nf));
+        self.nmea_freq = nmea_freq.map(Duration::from_secs);
+    }
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.tcp_handler.set_watchdog(watchdog);
+    }
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.tcp_handler.set_metrics(metrics.clone());
+        self.metrics = Some(metrics);
+    }
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>) {
+        self.tcp_handler.set_tls(config);
+    }
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.tcp_handler.set_proxy(proxy);
+    }
+}
+
+impl NtripClient {
+    /// Requests the mountpoint on a new connection and verifies the response
+    /// of the caster within the handshake timeout
+    fn handshake(&mut self) -> std::io::Result<()> {
+        let handshake_timeout = self.handshake_timeout;
+        let read_timeout = self.tcp_handler.read_timeout();
+        let stream = self
+            .tcp_handler
+            .get_stream()
+            .ok_or(std::io::ErrorKind::NotConnected)?;
+        stream.tcp().set_read_timeout(Some(handshake_timeout))?;
+        let authorization = if let Some(cr) = self.credentials.as_ref() {
+            format!("Authorization: Basic {}\r\n", encode_credentials(cr))
+        } else {
+            "".into()
+        };
+        let request = match self.version {
+            2 => format!(
+                "GET /{} HTTP/1.1\r\nHost: {}\r\nNtrip-Version: Ntrip/2.0\r\nUser-Agent: NTRIP rtcm-json/{}\r\nConnection: close\r\n{}\r\n",
+                &self.mountpoint,
+                &self.authority,
+                version!(),
+                authorization
+            ),
+            _ => format!(
+                "GET /{} HTTP/1.0\r\nUser-Agent: NTRIP rtcm-json/{}\r\nAccept: */*\r\n{}\r\n",
+                &self.mountpoint,
+                version!(),
+                authorization
+            ),
+        };
+        stream.write_all(request.as_bytes())?;
+        //verify response, "ICY 200 OK" of Ntrip 1 or an HTTP status line and
+        //headers of Ntrip 2
+        let status = read_line(stream)?;
+        self.chunked = None;
+        if status != "ICY 200 OK" {
+            let mut fields = status.split_whitespace();
+            let ok = fields.next().is_some_and(|v| v.starts_with("HTTP/1."))
+                && fields.next() == Some("200");
+            if !ok {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("caster responded {:?}", status),
+                ));
+            }
+            loop {
+                let header = read_line(stream)?;
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("transfer-encoding")
+                        && value.trim().eq_ignore_ascii_case("chunked")
+                    {
+                        self.chunked = Some(ChunkedDecoder::default());
+                    }
+                }
+            }
+        }
+        stream.tcp().set_read_timeout(read_timeout)?;
+        //send nmea gga
+        if let Some(coord) = self.nmea_coord.as_ref() {
+            coord.write_to_stream(stream)?;
+            self.latest_nmea_write = Some(Instant::now());
+        }
+        Ok(())
     }
 }
 
 impl Read for NtripClient {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let stream = match self.tcp_handler.get_stream_reconnect() {
-            crate::tcp_handler::Connection::ExistingConnection(stream) => stream,
-            crate::tcp_handler::Connection::NewConnection(stream) => {
-                //initialize connection
-                stream.write_all(
-                    format!(
-                        "GET /{} HTTP/1.0\r\nUser-Agent: NTRIP rtcm-json/{}\r\nAccept: */*\r\n{}\r\n",                        
-                        &self.mountpoint,
-                        version!(),
-                        if let Some(cr) = self.credentials.as_ref() {
-                            format!("Authorization: Basic {}\r\n", encode_credentials(cr))
-                        } else {
-                            "".into()
-                        }
-                    )
-                    .as_bytes(),
-                )?;                
-                //verify response
-                let mut resp_buf: [u8; 12] = [0; 12];
-                let mut written: usize = 0;
-                while written < resp_buf.len() {
-                    let n = stream.read(&mut resp_buf[written..])?;
-                    written += n;
-                    if &"ICY 200 OK\r\n".as_bytes()[..written] != &resp_buf[..written] {
-                        return Err(std::io::ErrorKind::PermissionDenied.into());
-                    }                    
+        let new_connection = matches!(
+            self.tcp_handler.get_stream_reconnect()?,
+            Connection::NewConnection(_)
+        );
+        if new_connection {
+            if let Err(e) = self.handshake() {
+                let e = match is_timeout(&e) {
+                    true => std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("no response within {:.1} s", self.handshake_timeout.as_secs_f64()),
+                    ),
+                    false => e,
+                };
+                eprintln!("Ntrip handshake with mountpoint {} failed ({})", self.mountpoint, e);
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.ntrip_handshake_failure();
                 }
-                if "ICY 200 OK\r\n".as_bytes() != &resp_buf {
-                    return Err(std::io::ErrorKind::PermissionDenied.into());
-                }
-                //send nmea gga
-                if let Some(coord) = self.nmea_coord.as_ref() {
-                    coord.write_to_stream(stream)?;
-                    self.latest_nmea_write = Some(Instant::now());
-                }                
-                stream
+                self.tcp_handler.discard_stream();
+                return Err(e);
             }
-        };
+        }
+        let stream = self
+            .tcp_handler
+            .get_stream()
+            .ok_or(std::io::ErrorKind::NotConnected)?;
         //check if it's time to send nmea gga position message
         if let (Some(freq), Some(coord), Some(latest_write)) = (
             self.nmea_freq.as_ref(),
@@ -90,24 +190,197 @@ impl Read for NtripClient {
             }
         }
 
-        match stream.read(&mut *buf) {
-            Ok(v) => {
-                if v == 0 {
+        loop {
+            if self.chunked.as_ref().is_some_and(ChunkedDecoder::ended) {
+                self.tcp_handler.discard_stream();
+                return Err(std::io::ErrorKind::NotConnected.into());
+            }
+            let v = match stream.read(&mut *buf) {
+                Ok(v) => v,
+                Err(v) if is_timeout(&v) => {
+                    let silent = self.tcp_handler.read_timeout().unwrap_or_default();
+                    self.tcp_handler.discard_stalled_stream(silent);
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+                Err(v) => {
                     self.tcp_handler.discard_stream();
-                    Err(std::io::ErrorKind::NotConnected.into())
-                } else {
-                    Ok(v)
+                    return Err(v);
                 }
-            }
-            Err(v) => {
+            };
+            if v == 0 {
                 self.tcp_handler.discard_stream();
-                Err(v)
+                return Err(std::io::ErrorKind::NotConnected.into());
+            }
+            let Some(decoder) = self.chunked.as_mut() else {
+                return Ok(v);
+            };
+            match decoder.decode(&mut buf[..v]) {
+                // only chunk sizes received
+                Ok(0) => (),
+                Ok(n) => return Ok(n),
+                Err(e) => {
+                    self.tcp_handler.discard_stream();
+                    return Err(e);
+                }
             }
         }
     }
 }
 
+/// Reads a line of the response header, without the line end
+fn read_line(stream: &mut impl Read) -> std::io::Result<String> {
+    // read byte by byte to leave the data following the header in the stream
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\n") {
+        if stream.read(&mut byte)? == 0 || line.len() > 8192 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+#[derive(Default)]
+enum ChunkState {
+    #[default]
+    Size,
+    Extension,
+    SizeEnd,
+    Data,
+    DataEnd,
+    End,
+}
+
+/// Removes the framing of the chunked transfer encoding of Ntrip 2 responses
+#[derive(Default)]
+struct ChunkedDecoder {
+    state: ChunkState,
+    /// Size of the current chunk, or the bytes remaining of it
+    size: usize,
+}
+
+impl ChunkedDecoder {
+    /// Removes the chunk sizes and line ends from `data` in place, returns
+    /// the length of the data of the chunks
+    fn decode(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        let invalid = || {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid chunked encoding")
+        };
+        let mut len = 0;
+        let mut i = 0;
+        while i < data.len() {
+            let byte = data[i];
+            match self.state {
+                ChunkState::Data => {
+                    let n = self.size.min(data.len() - i);
+                    data.copy_within(i..i + n, len);
+                    len += n;
+                    i += n;
+                    self.size -= n;
+                    if self.size == 0 {
+                        self.state = ChunkState::DataEnd;
+                    }
+                    continue;
+                }
+                ChunkState::Size => match (byte as char).to_digit(16) {
+                    Some(digit) => {
+                        self.size = self
+                            .size
+                            .checked_mul(16)
+                            .map(|size| size + digit as usize)
+                            .ok_or_else(invalid)?
+                    }
+                    None if byte == b';' => self.state = ChunkState::Extension,
+                    None if byte == b'\r' => self.state = ChunkState::SizeEnd,
+                    None => return Err(invalid()),
+                },
+                ChunkState::Extension => {
+                    if byte == b'\r' {
+                        self.state = ChunkState::SizeEnd;
+                    }
+                }
+                ChunkState::SizeEnd => {
+                    if byte != b'\n' {
+                        return Err(invalid());
+                    }
+                    // the last chunk has size 0
+                    self.state = match self.size {
+                        0 => ChunkState::End,
+                        _ => ChunkState::Data,
+                    };
+                }
+                ChunkState::DataEnd => match byte {
+                    b'\r' => (),
+                    b'\n' => self.state = ChunkState::Size,
+                    _ => return Err(invalid()),
+                },
+                ChunkState::End => break,
+            }
+            i += 1;
+        }
+        Ok(len)
+    }
+    fn ended(&self) -> bool {
+        matches!(self.state, ChunkState::End)
+    }
+}
+
 fn encode_credentials(credentials: &Credentials) -> String {
     base64::engine::general_purpose::STANDARD
         .encode(format!("{}:{}", credentials.username, credentials.password))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNKED: &[u8] =
+        b"5\r\nhello\r\n1a;name=value\r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\n\r\n";
+
+    #[test]
+    fn chunked_decoding() {
+        let mut decoder = ChunkedDecoder::default();
+        let mut data = CHUNKED.to_vec();
+        let len = decoder.decode(&mut data).unwrap();
+        assert_eq!(&data[..len], b"helloabcdefghijklmnopqrstuvwxyz");
+        assert!(decoder.ended());
+    }
+
+    #[test]
+    fn chunked_decoding_split_reads() {
+        // the framing split at every position across reads
+        for split in 1..CHUNKED.len() {
+            let mut decoder = ChunkedDecoder::default();
+            let mut decoded = Vec::new();
+            for part in [&CHUNKED[..split], &CHUNKED[split..]] {
+                let mut data = part.to_vec();
+                let len = decoder.decode(&mut data).unwrap();
+                decoded.extend_from_slice(&data[..len]);
+            }
+            assert_eq!(
+                decoded, b"helloabcdefghijklmnopqrstuvwxyz",
+                "split at {}",
+                split
+            );
+            assert!(decoder.ended());
+        }
+        let mut decoder = ChunkedDecoder::default();
+        let mut data = b"A\r\n".to_vec();
+        assert_eq!(decoder.decode(&mut data).unwrap(), 0);
+        assert!(!decoder.ended());
+    }
+
+    #[test]
+    fn invalid_chunked_encoding() {
+        for data in [
+            &b"x\r\n"[..],
+            b"5\rhello",
+            b"2\r\nabc\r\n",
+            b"fffffffffffffffff\r\n",
+        ] {
+            let mut data = data.to_vec();
+            assert!(ChunkedDecoder::default().decode(&mut data).is_err());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::legacy::{decode_legacy, LegacyEncoder};
+use crate::msm::{MsmDecoder, MsmEncoder};
+use rtcm_rs::Message;
+
+/// Target of the observation message conversion
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObsTarget {
+    /// MSM type 1 to 7
+    Msm(u8),
+    /// 1004 (GPS) and 1012 (GLONASS)
+    Legacy,
+}
+
+/// Converts MSM and legacy observation messages to another MSM type or to
+/// legacy observation messages. Messages which cannot be converted are passed
+/// on unchanged, as are all other messages: MSM of other constellations than
+/// GPS and GLONASS to legacy, GLONASS MSM1-4/6 to legacy before the frequency
+/// channel numbers are known (from MSM5/7, 1010, 1012 or 1020), and MSM1-3 to
+/// MSM4-7 as they lack the integer millisecond part of the pseudoranges.
+pub struct ObsConverter {
+    target: ObsTarget,
+    msm_decoder: MsmDecoder,
+    msm_encoder: MsmEncoder,
+    legacy_encoder: LegacyEncoder,
+}
+
+impl ObsConverter {
+    pub fn new(target: ObsTarget) -> Self {
+        ObsConverter {
+            target,
+            msm_decoder: MsmDecoder::new(),
+            msm_encoder: MsmEncoder::new(),
+            legacy_encoder: LegacyEncoder::new(),
+        }
+    }
+    pub fn convert(&mut self, msg: Message) -> Vec<Message> {
+        let number = msg.number().unwrap_or(0);
+        let is_legacy = matches!(number, 1002 | 1004 | 1010 | 1012);
+        // the MSM decoder also learns GLONASS frequency channel numbers
+        // from 1010, 1012 and 1020 messages
+        let obs = self
+            .msm_decoder
+            .decode(&msg)
+            .or_else(|| decode_legacy(&msg));
+        let Some(mut obs) = obs else {
+            return vec![msg];
+        };
+        let converted = match self.target {
+            ObsTarget::Legacy if is_legacy => return vec![msg],
+            ObsTarget::Legacy => self.legacy_encoder.encode(&obs),
+            ObsTarget::Msm(msm_type) if !is_legacy && obs.msm_type == msm_type => return vec![msg],
+            ObsTarget::Msm(msm_type) if msm_type >= 4 && !obs.integer_ms_known() => {
+                return vec![msg]
+            }
+            ObsTarget::Msm(msm_type) => {
+                obs.msm_type = msm_type;
+                self.msm_encoder.encode(&obs)
+            }
+        };
+        if converted.is_empty() {
+            vec![msg]
+        } else {
+            converted
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use crate::coordinate::Coordinate;
+use crate::ephemeris::{Ephemeris, GlonassEphemeris, KeplerEphemeris};
+use crate::gnss_time::utc_to_gps;
+use crate::msm::{Gnss, CLIGHT};
+use chrono::{Duration, NaiveDateTime};
+use rtcm_rs::Message;
+use std::collections::BTreeMap;
+
+/// Earth rotation rate (rad/s) and gravitational constant (m³/s²) used by
+/// the broadcast ephemerides of each constellation
+const OMEGA_E_GPS: f64 = 7.2921151467e-5;
+const MU_GPS: f64 = 3.9860050e14;
+const OMEGA_E_GAL: f64 = 7.2921151467e-5;
+const MU_GAL: f64 = 3.986004418e14;
+const OMEGA_E_BDS: f64 = 7.292115e-5;
+const MU_BDS: f64 = 3.986004418e14;
+/// PZ-90 constants of the GLONASS ICD
+const OMEGA_E_GLO: f64 = 7.292115e-5;
+const MU_GLO: f64 = 3.9860044e14;
+const J2_GLO: f64 = 1.0826257e-3;
+const RE_GLO: f64 = 6378136.0;
+/// Integration step of the GLONASS orbit (s)
+const GLONASS_STEP_S: f64 = 60.0;
+/// Maximum age of a broadcast ephemeris relative to its reference time (s)
+const GPS_MAX_AGE_S: f64 = 7200.0;
+const GAL_MAX_AGE_S: f64 = 14400.0;
+const BDS_MAX_AGE_S: f64 = 21600.0;
+const GLO_MAX_AGE_S: f64 = 1800.0;
+/// Ephemerides older than this relative to the newest one of the same
+/// satellite are dropped from the store (s)
+const STORE_SPAN_S: f64 = 86400.0;
+/// Nominal signal travel time used when the pseudorange is unknown (s)
+const NOMINAL_TRAVEL_TIME_S: f64 = 0.075;
+
+/// Satellite position (ECEF, meters) and clock bias (seconds) at signal
+/// transmission, computed from a broadcast ephemeris
+#[derive(Clone, Copy, Debug)]
+pub struct SatelliteState {
+    pub position: [f64; 3],
+    pub clock_bias_s: f64,
+}
+
+impl SatelliteState {
+    /// Elevation and azimuth in degrees of the satellite seen from `station`
+    pub fn elevation_azimuth(&self, station: &Coordinate) -> (f64, f64) {
+        let (x, y, z) = station.to_xyz();
+        let [sx, sy, sz] = self.position;
+        let enu = station.enu_of_delta(sx - x, sy - y, sz - z);
+        let horizontal = (enu.east * enu.east + enu.north * enu.north).sqrt();
+        let elevation = enu.up.atan2(horizontal).to_degrees();
+        let azimuth = enu.east.atan2(enu.north).to_degrees().rem_euclid(360.0);
+        (elevation, azimuth)
+    }
+}
+
+impl KeplerEphemeris {
+    /// Satellite state at `time` in the time scale of the constellation
+    pub fn state(&self, time: NaiveDateTime) -> SatelliteState {
+        let (mu, omega_e) = match self.gnss {
+            Gnss::Galileo => (MU_GAL, OMEGA_E_GAL),
+            Gnss::Beidou => (MU_BDS, OMEGA_E_BDS),
+            _ => (MU_GPS, OMEGA_E_GPS),
+        };
+        let tk = seconds_between(time, self.toe());
+        let a = self.sqrt_a * self.sqrt_a;
+        let n = (mu / (a * a * a)).sqrt() + self.delta_n;
+        let m = self.m0 + n * tk;
+        let mut e_anomaly = m;
+        for _ in 0..30 {
+            let next = m + self.e * e_anomaly.sin();
+            let converged = (next - e_anomaly).abs() < 1e-14;
+            e_anomaly = next;
+            if converged {
+                break;
+            }
+        }
+        let (sin_e, cos_e) = e_anomaly.sin_cos();
+        let phi = ((1.0 - self.e * self.e).sqrt() * sin_e).atan2(cos_e - self.e) + self.omega;
+        let (sin_2phi, cos_2phi) = (2.0 * phi).sin_cos();
+        let u = phi + self.cus * sin_2phi + self.cuc * cos_2phi;
+        let r = a * (1.0 - self.e * cos_e) + self.crs * sin_2phi + self.crc * cos_2phi;
+        let i = self.i0 + self.idot * tk + self.cis * sin_2phi + self.cic * cos_2phi;
+        let (x, y) = (r * u.cos(), r * u.sin());
+        let (sin_i, cos_i) = i.sin_cos();
+        let position = if self.is_beidou_geo() {
+            // GEO orbits are given in an inertial frame rotated by -5° around x
+            let omega = self.omega0 + self.omega_dot * tk - omega_e * self.toe_s;
+            let (sin_o, cos_o) = omega.sin_cos();
+            let xg = x * cos_o - y * cos_i * sin_o;
+            let yg = x * sin_o + y * cos_i * cos_o;
+            let zg = y * sin_i;
+            let (sin_5, cos_5) = (-5.0f64).to_radians().sin_cos();
+            let (sin_r, cos_r) = (omega_e * tk).sin_cos();
+            [
+                xg * cos_r + yg * sin_r * cos_5 + zg * sin_r * sin_5,
+                -xg * sin_r + yg * cos_r * cos_5 + zg * cos_r * sin_5,
+                -yg * sin_5 + zg * cos_5,
+            ]
+        } else {
+            let omega = self.omega0 + (self.omega_dot - omega_e) * tk - omega_e * self.toe_s;
+            let (sin_o, cos_o) = omega.sin_cos();
+            [
+                x * cos_o - y * cos_i * sin_o,
+                x * sin_o + y * cos_i * cos_o,
+                y * sin_i,
+            ]
+        };
+        let tc = seconds_between(time, self.toc());
+        let relativistic = -2.0 * (mu * a).sqrt() * self.e * sin_e / (CLIGHT * CLIGHT);
+        SatelliteState {
+            position,
+            clock_bias_s: self.af0 + self.af1 * tc + self.af2 * tc * tc + relativistic,
+        }
+    }
+}
+
+impl GlonassEphemeris {
+    /// Reference time tb in GPS time
+    pub fn toe_gps(&self) -> NaiveDateTime {
+        utc_to_gps(self.toe_utc)
+    }
+    /// Satellite state at GPS time `time`, integrating the equations of
+    /// motion of the GLONASS ICD from the reference time
+    pub fn state(&self, time: NaiveDateTime) -> SatelliteState {
+        let tk = seconds_between(time, self.toe_gps());
+        let mut x = [0.0; 6];
+        for i in 0..3 {
+            x[i] = self.position[i] * 1000.0;
+            x[i + 3] = self.velocity[i] * 1000.0;
+        }
+        let acceleration = self.acceleration.map(|a| a * 1000.0);
+        let mut t = tk;
+        while t.abs() > 1e-9 {
+            let step = t.signum() * t.abs().min(GLONASS_STEP_S);
+            x = glonass_rk4(&x, &acceleration, step);
+            t -= step;
+        }
+        SatelliteState {
+            position: [x[0], x[1], x[2]],
+            clock_bias_s: -self.tau_n + self.gamma_n * tk,
+        }
+    }
+}
+
+impl Ephemeris {
+    /// Reference time in GPS time
+    fn toe_gps(&self) -> NaiveDateTime {
+        match self {
+            Ephemeris::Kepler(eph) if eph.gnss == Gnss::Beidou => {
+                eph.toe() + Duration::try_seconds(14).unwrap()
+            }
+            Ephemeris::Kepler(eph) => eph.toe(),
+            Ephemeris::Glonass(eph) => eph.toe_gps(),
+        }
+    }
+    fn max_age_s(&self) -> f64 {
+        match self.gnss() {
+            Gnss::Galileo => GAL_MAX_AGE_S,
+            Gnss::Beidou => BDS_MAX_AGE_S,
+            Gnss::Glonass => GLO_MAX_AGE_S,
+            _ => GPS_MAX_AGE_S,
+        }
+    }
+    /// Satellite state at GPS time `time`
+    pub fn state(&self, time: NaiveDateTime) -> SatelliteState {
+        match self {
+            Ephemeris::Kepler(eph) if eph.gnss == Gnss::Beidou => {
+                eph.state(time - Duration::try_seconds(14).unwrap())
+            }
+            Ephemeris::Kepler(eph) => eph.state(time),
+            Ephemeris::Glonass(eph) => eph.state(time),
+        }
+    }
+}
+
+/// Broadcast ephemerides seen in a stream, keyed by constellation, PRN and
+/// issue of data
+#[derive(Default)]
+pub struct EphemerisStore {
+    ephemerides: BTreeMap<(Gnss, u8, u32), Ephemeris>,
+}
+
+impl EphemerisStore {
+    pub fn new() -> Self {
+        EphemerisStore::default()
+    }
+    /// Stores the ephemeris of `msg` if it is an ephemeris message, see
+    /// `Ephemeris::from_message` for `reference`. Returns true if it was one.
+    pub fn add_message(&mut self, msg: &Message, reference: NaiveDateTime) -> bool {
+        match Ephemeris::from_message(msg, reference) {
+            Some(eph) => {
+                self.add(eph);
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn add(&mut self, eph: Ephemeris) {
+        let (gnss, prn) = (eph.gnss(), eph.prn());
+        let toe = eph.toe_gps();
+        self.ephemerides.insert((gnss, prn, eph.iode()), eph);
+        self.ephemerides.retain(|(g, p, _), e| {
+            *g != gnss || *p != prn || seconds_between(toe, e.toe_gps()) < STORE_SPAN_S
+        });
+    }
+    /// Ephemeris of a satellite with the reference time nearest GPS time
+    /// `time`, if within its validity
+    pub fn select(&self, gnss: Gnss, prn: u8, time: NaiveDateTime) -> Option<&Ephemeris> {
+        self.ephemerides
+            .range((gnss, prn, 0)..=(gnss, prn, u32::MAX))
+            .map(|(_, eph)| (seconds_between(time, eph.toe_gps()).abs(), eph))
+            .filter(|(age, eph)| *age <= eph.max_age_s())
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, eph)| eph)
+    }
+    /// State of a satellite at the transmission of a signal received at GPS
+    /// time `reception` with `pseudorange_m`, and the IODE of the ephemeris used
+    pub fn transmission_state(
+        &self,
+        gnss: Gnss,
+        prn: u8,
+        reception: NaiveDateTime,
+        pseudorange_m: Option<f64>,
+    ) -> Option<(SatelliteState, u32)> {
+        let travel_time_s = pseudorange_m.map_or(NOMINAL_TRAVEL_TIME_S, |pr| pr / CLIGHT);
+        let mut transmission = reception - seconds_f64(travel_time_s);
+        let eph = self.select(gnss, prn, transmission)?;
+        // the pseudorange includes the satellite clock bias
+        let clock_bias_s = eph.state(transmission).clock_bias_s;
+        transmission -= seconds_f64(clock_bias_s);
+        Some((eph.state(transmission), eph.iode()))
+    }
+}
+
+/// Derivative of the GLONASS state (position, velocity) in PZ-90
+fn glonass_derivative(x: &[f64; 6], acceleration: &[f64; 3]) -> [f64; 6] {
+    let r2 = x[0] * x[0] + x[1] * x[1] + x[2] * x[2];
+    if r2 <= 0.0 {
+        return [0.0; 6];
+    }
+    let r3 = r2 * r2.sqrt();
+    let omega2 = OMEGA_E_GLO * OMEGA_E_GLO;
+    let a = 1.5 * J2_GLO * MU_GLO * RE_GLO * RE_GLO / r2 / r3;
+    let b = 5.0 * x[2] * x[2] / r2;
+    let c = -MU_GLO / r3 - a * (1.0 - b);
+    [
+        x[3],
+        x[4],
+        x[5],
+        (c + omega2) * x[0] + 2.0 * OMEGA_E_GLO * x[4] + acceleration[0],
+        (c + omega2) * x[1] - 2.0 * OMEGA_E_GLO * x[3] + acceleration[1],
+        (c - 2.0 * a) * x[2] + acceleration[2],
+    ]
+}
+
+/// One Runge-Kutta 4th order step of `step` seconds
+fn glonass_rk4(x: &[f64; 6], acceleration: &[f64; 3], step: f64) -> [f64; 6] {
+    let offset = |k: &[f64; 6], factor: f64| -> [f64; 6] {
+        let mut y = *x;
+        for i in 0..6 {
+            y[i] += k[i] * factor;
+        }
+        y
+    };
+    let k1 = glonass_derivative(x, acceleration);
+    let k2 = glonass_derivative(&offset(&k1, step / 2.0), acceleration);
+    let k3 = glonass_derivative(&offset(&k2, step / 2.0), acceleration);
+    let k4 = glonass_derivative(&offset(&k3, step), acceleration);
+    let mut y = *x;
+    for i in 0..6 {
+        y[i] += step / 6.0 * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i]);
+    }
+    y
+}
+
+fn seconds_between(t: NaiveDateTime, t0: NaiveDateTime) -> f64 {
+    (t - t0).num_nanoseconds().unwrap_or(i64::MAX) as f64 * 1e-9
+}
+
+fn seconds_f64(s: f64) -> Duration {
+    Duration::nanoseconds((s * 1e9).round() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ephemeris::week_start;
+    use chrono::NaiveDate;
+    use std::f64::consts::PI;
+
+    const SQRT_A: f64 = 5153.7;
+
+    /// Unperturbed orbit with the reference times at the start of week 2295
+    fn kepler(e: f64, i0: f64, omega: f64, omega0: f64) -> KeplerEphemeris {
+        KeplerEphemeris {
+            gnss: Gnss::Gps,
+            prn: 1,
+            week: 2295,
+            toc_s: 0.0,
+            toe_s: 0.0,
+            af0: 1e-4,
+            af1: 1e-11,
+            af2: 1e-18,
+            iode: 0,
+            iodc: 0,
+            crs: 0.0,
+            crc: 0.0,
+            cus: 0.0,
+            cuc: 0.0,
+            cis: 0.0,
+            cic: 0.0,
+            delta_n: 0.0,
+            m0: 0.0,
+            e,
+            sqrt_a: SQRT_A,
+            omega0,
+            i0,
+            omega,
+            omega_dot: 0.0,
+            idot: 0.0,
+            tgd: [0.0, 0.0],
+            accuracy_index: 0,
+            health: 0,
+            codes_on_l2: 0,
+            l2p_flag: 0,
+            fit_interval_flag: 0,
+            data_sources: 0,
+        }
+    }
+
+    fn assert_position(position: [f64; 3], expected: [f64; 3], tolerance_m: f64) {
+        for i in 0..3 {
+            assert!(
+                (position[i] - expected[i]).abs() < tolerance_m,
+                "{:?} != {:?}",
+                position,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn circular_equatorial_orbit() {
+        let eph = kepler(0.0, 0.0, 0.0, 0.0);
+        let a = SQRT_A * SQRT_A;
+        let state = eph.state(week_start(2295));
+        assert_position(state.position, [a, 0.0, 0.0], 1e-6);
+        assert_eq!(state.clock_bias_s, 1e-4);
+        // a quarter of the orbit later, less the rotation of the earth
+        let n = (MU_GPS / (a * a * a)).sqrt();
+        let tk = (PI / 2.0 / n).round();
+        let state = eph.state(week_start(2295) + seconds_f64(tk));
+        let angle = (n - OMEGA_E_GPS) * tk;
+        assert_position(
+            state.position,
+            [a * angle.cos(), a * angle.sin(), 0.0],
+            1e-6,
+        );
+        let clock_bias_s = 1e-4 + 1e-11 * tk + 1e-18 * tk * tk;
+        assert!((state.clock_bias_s - clock_bias_s).abs() < 1e-18);
+    }
+
+    #[test]
+    fn inclined_eccentric_orbit() {
+        let (e, i, omega, omega0) = (0.02, 55f64.to_radians(), PI / 3.0, 1.0);
+        let eph = kepler(e, i, omega, omega0);
+        let a = SQRT_A * SQRT_A;
+        // at perigee at the reference time
+        let r = a * (1.0 - e);
+        let state = eph.state(week_start(2295));
+        assert_position(
+            state.position,
+            [
+                r * (omega.cos() * omega0.cos() - omega.sin() * i.cos() * omega0.sin()),
+                r * (omega.cos() * omega0.sin() + omega.sin() * i.cos() * omega0.cos()),
+                r * omega.sin() * i.sin(),
+            ],
+            1e-6,
+        );
+        assert_eq!(state.clock_bias_s, 1e-4);
+        // the radius of the eccentric anomaly solving Kepler's equation
+        let n = (MU_GPS / (a * a * a)).sqrt();
+        let tk = 10_800.0;
+        let state = eph.state(week_start(2295) + seconds_f64(tk));
+        let radius = state.position.iter().map(|x| x * x).sum::<f64>().sqrt();
+        let e_anomaly = ((1.0 - radius / a) / e).acos();
+        assert!((e_anomaly - e * e_anomaly.sin() - n * tk).abs() < 1e-6);
+        let relativistic = -2.0 * (MU_GPS * a).sqrt() * e * e_anomaly.sin() / (CLIGHT * CLIGHT);
+        let clock_bias_s = 1e-4 + 1e-11 * tk + 1e-18 * tk * tk + relativistic;
+        assert!((state.clock_bias_s - clock_bias_s).abs() < 1e-12);
+    }
+
+    #[test]
+    fn glonass_orbit() {
+        // example of the GLONASS ICD (edition 5.1, A.3.1.2), tb = 11700 s
+        // Moscow time, integrated to 12300 s
+        let eph = GlonassEphemeris {
+            prn: 1,
+            fcn: 1,
+            toe_utc: NaiveDate::from_ymd_opt(2024, 1, 5)
+                .unwrap()
+                .and_hms_opt(0, 15, 0)
+                .unwrap(),
+            tk_s: 11_700,
+            tau_n: 1e-5,
+            gamma_n: 1e-12,
+            position: [7003.008789, -12206.626953, 21280.765625],
+            velocity: [0.7835417, 2.8042530, 1.3525150],
+            acceleration: [0.0, 1.7e-9, -5.41e-9],
+            health: 0,
+            age_days: 0,
+        };
+        let state = eph.state(eph.toe_gps() + Duration::try_seconds(600).unwrap());
+        assert_position(
+            state.position,
+            [7_523_174.819, -10_506_961.965, 21_999_239.413],
+            1.0,
+        );
+        assert!((state.clock_bias_s - (-1e-5 + 1e-12 * 600.0)).abs() < 1e-15);
+        // integrating backwards
+        let state = eph.state(eph.toe_gps());
+        assert_position(
+            state.position,
+            [7_003_008.789, -12_206_626.953, 21_280_765.625],
+            1e-6,
+        );
+    }
+}
@@ -0,0 +1,546 @@
+use crate::arguments::RinexOptions;
+use crate::ephemeris::{
+    accuracy_index, week_start, Ephemeris, GlonassEphemeris, KeplerEphemeris, BDT_WEEK_OFFSET,
+};
+use crate::gnss_time::{gps_week_tow, leap_seconds_gps, utc_to_gps};
+use crate::msm::Gnss;
+use crate::rinex_obs::{column, header_line, invalid_data, parse_f64};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use rtcm_rs::Message;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+/// Value of unknown fields in RINEX 4 navigation records
+const UNKNOWN: f64 = 0.999999999999e9;
+
+/// Collects broadcast ephemerides and writes them as a RINEX navigation file.
+/// Ephemerides are repeated by the reference station every few seconds or
+/// minutes, only the first message of each ephemeris is kept.
+pub struct RinexNavWriter<'a> {
+    options: &'a RinexOptions,
+    ephemerides: BTreeMap<(Gnss, u8, NaiveDateTime, u32), (Ephemeris, NaiveDateTime)>,
+    leap_seconds: Option<i64>,
+}
+
+impl<'a> RinexNavWriter<'a> {
+    pub fn new(options: &'a RinexOptions) -> Self {
+        RinexNavWriter {
+            options,
+            ephemerides: BTreeMap::new(),
+            leap_seconds: None,
+        }
+    }
+    /// Adds the ephemeris of `msg` received at about the GPS time `reference`,
+    /// returns false if the message does not carry an ephemeris
+    pub fn add_message(&mut self, msg: &Message, reference: NaiveDateTime) -> bool {
+        let Some(eph) = Ephemeris::from_message(msg, reference) else {
+            return false;
+        };
+        self.leap_seconds = Some(leap_seconds_gps(reference));
+        self.ephemerides
+            .entry(eph.key())
+            .or_insert((eph, reference));
+        true
+    }
+    pub fn write<W: Write + ?Sized>(&self, output: &mut W) -> std::io::Result<()> {
+        output.write_all(self.header().as_bytes())?;
+        for (eph, received) in self.ephemerides.values() {
+            let record = match eph {
+                Ephemeris::Kepler(eph) => self.kepler_record(eph, *received),
+                Ephemeris::Glonass(eph) => self.glonass_record(eph),
+            };
+            output.write_all(record.as_bytes())?;
+        }
+        output.flush()
+    }
+    fn header(&self) -> String {
+        let mut header = String::new();
+        let content = if self.options.version >= 4 {
+            format!("{:9.2}{:11}{:<20}{}", 4.02, "", "NAVIGATION DATA", 'M')
+        } else {
+            format!(
+                "{:9.2}{:11}{:<20}{}",
+                3.04, "", "N: GNSS NAV DATA", "M: MIXED"
+            )
+        };
+        header_line(&mut header, content, "RINEX VERSION / TYPE");
+        header_line(
+            &mut header,
+            format!(
+                "{:<20}{:<20}{}",
+                concat!("rtcm-json ", env!("CARGO_PKG_VERSION")),
+                "",
+                Utc::now().format("%Y%m%d %H%M%S UTC")
+            ),
+            "PGM / RUN BY / DATE",
+        );
+        if let Some(leap_seconds) = self.leap_seconds {
+            header_line(&mut header, format!("{:6}", leap_seconds), "LEAP SECONDS");
+        }
+        header_line(&mut header, String::new(), "END OF HEADER");
+        header
+    }
+    fn kepler_record(&self, eph: &KeplerEphemeris, received: NaiveDateTime) -> String {
+        let mut record = String::new();
+        if self.options.version >= 4 {
+            let message_type = match eph.gnss {
+                Gnss::Galileo if eph.data_sources & 2 != 0 => "FNAV",
+                Gnss::Galileo => "INAV",
+                Gnss::Beidou if eph.is_beidou_geo() => "D2",
+                Gnss::Beidou => "D1",
+                _ => "LNAV",
+            };
+            record.push_str(&format!(
+                "> EPH {}{:02} {}\n",
+                eph.gnss.system_char(),
+                eph.prn,
+                message_type
+            ));
+        }
+        let mut transmission_s = gps_week_tow(received).1 as f64 * 0.001;
+        if eph.gnss == Gnss::Beidou {
+            transmission_s = (transmission_s - 14.0).rem_euclid(604_800.0);
+        }
+        record.push_str(&epoch_line(
+            eph.gnss,
+            eph.prn,
+            &eph.toc(),
+            [eph.af0, eph.af1, eph.af2],
+        ));
+        orbit_line(
+            &mut record,
+            &[eph.iode as f64, eph.crs, eph.delta_n, eph.m0],
+        );
+        orbit_line(&mut record, &[eph.cuc, eph.e, eph.cus, eph.sqrt_a]);
+        orbit_line(&mut record, &[eph.toe_s, eph.cic, eph.omega0, eph.cis]);
+        orbit_line(&mut record, &[eph.i0, eph.crc, eph.omega, eph.omega_dot]);
+        match eph.gnss {
+            Gnss::Galileo => {
+                orbit_line(
+                    &mut record,
+                    &[eph.idot, eph.data_sources as f64, eph.week as f64, 0.0],
+                );
+                orbit_line(
+                    &mut record,
+                    &[eph.accuracy_m(), eph.health as f64, eph.tgd[0], eph.tgd[1]],
+                );
+                orbit_line(&mut record, &[transmission_s]);
+            }
+            Gnss::Beidou => {
+                orbit_line(&mut record, &[eph.idot, 0.0, eph.week as f64, 0.0]);
+                orbit_line(
+                    &mut record,
+                    &[eph.accuracy_m(), eph.health as f64, eph.tgd[0], eph.tgd[1]],
+                );
+                orbit_line(&mut record, &[transmission_s, eph.iodc as f64]);
+            }
+            _ => {
+                orbit_line(
+                    &mut record,
+                    &[
+                        eph.idot,
+                        eph.codes_on_l2 as f64,
+                        eph.week as f64,
+                        eph.l2p_flag as f64,
+                    ],
+                );
+                orbit_line(
+                    &mut record,
+                    &[
+                        eph.accuracy_m(),
+                        eph.health as f64,
+                        eph.tgd[0],
+                        eph.iodc as f64,
+                    ],
+                );
+                // GPS gives the fit interval in hours, QZSS the flag itself
+                let fit_interval = match (eph.gnss, eph.fit_interval_flag) {
+                    (Gnss::Qzss, flag) => flag as f64,
+                    (_, 0) => 4.0,
+                    _ => 6.0,
+                };
+                orbit_line(&mut record, &[transmission_s, fit_interval]);
+            }
+        }
+        record
+    }
+    fn glonass_record(&self, eph: &GlonassEphemeris) -> String {
+        let mut record = String::new();
+        if self.options.version >= 4 {
+            record.push_str(&format!("> EPH R{:02} FDMA\n", eph.prn));
+        }
+        // message frame time in seconds of the UTC week
+        let toe_s = gps_week_tow(eph.toe_utc).1 / 1000;
+        let day_start_s = toe_s - toe_s % 86_400;
+        let mut tk_s = day_start_s + eph.tk_s as i64 - 3 * 3600;
+        if tk_s - toe_s > 43_200 {
+            tk_s -= 86_400;
+        } else if toe_s - tk_s > 43_200 {
+            tk_s += 86_400;
+        }
+        record.push_str(&epoch_line(
+            Gnss::Glonass,
+            eph.prn,
+            &eph.toe_utc,
+            [-eph.tau_n, eph.gamma_n, tk_s.rem_euclid(604_800) as f64],
+        ));
+        let [x, y, z] = eph.position;
+        let [vx, vy, vz] = eph.velocity;
+        let [ax, ay, az] = eph.acceleration;
+        orbit_line(&mut record, &[x, vx, ax, eph.health as f64]);
+        orbit_line(&mut record, &[y, vy, ay, eph.fcn as f64]);
+        orbit_line(&mut record, &[z, vz, az, eph.age_days as f64]);
+        if self.options.version >= 4 {
+            orbit_line(&mut record, &[UNKNOWN, UNKNOWN, UNKNOWN, UNKNOWN]);
+        }
+        record
+    }
+}
+
+/// First line of a record with satellite, epoch and three clock parameters
+fn epoch_line(gnss: Gnss, prn: u8, epoch: &NaiveDateTime, clock: [f64; 3]) -> String {
+    format!(
+        "{}{:02} {:04} {:02} {:02} {:02} {:02} {:02}{}{}{}\n",
+        gnss.system_char(),
+        prn,
+        epoch.year(),
+        epoch.month(),
+        epoch.day(),
+        epoch.hour(),
+        epoch.minute(),
+        epoch.second(),
+        d19(clock[0]),
+        d19(clock[1]),
+        d19(clock[2])
+    )
+}
+
+/// Appends a broadcast orbit line with up to four values
+fn orbit_line(record: &mut String, values: &[f64]) {
+    record.push_str("    ");
+    for value in values {
+        record.push_str(&d19(*value));
+    }
+    record.push('\n');
+}
+
+/// Formats `value` as FORTRAN D19.12, e.g. ` 1.234567890123E-05`
+fn d19(value: f64) -> String {
+    let formatted = format!("{:.12E}", value);
+    let (mantissa, exponent) = formatted.split_once('E').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap_or(0);
+    format!(
+        "{:>15}E{}{:02}",
+        mantissa,
+        if exponent < 0 { '-' } else { '+' },
+        exponent.abs()
+    )
+}
+
+/// Reads the ephemerides of a RINEX 3/4 navigation file together with the
+/// GPS time of their transmission, or of their reference time if the
+/// transmission time is unknown. Records other than GPS LNAV, GLONASS FDMA,
+/// Galileo INAV/FNAV, BeiDou D1/D2 and QZSS LNAV ephemerides are skipped.
+pub fn read_rinex_nav<R: BufRead>(input: R) -> io::Result<Vec<(NaiveDateTime, Ephemeris)>> {
+    let mut lines = input.lines();
+    loop {
+        let Some(line) = lines.next() else {
+            return Err(invalid_data("RINEX header without END OF HEADER"));
+        };
+        let line = line?;
+        match column(&line, 60, 20).trim() {
+            "RINEX VERSION / TYPE" => {
+                let version = parse_f64(column(&line, 0, 9)).unwrap_or(0.0);
+                if version < 3.0 || column(&line, 20, 1) != "N" {
+                    return Err(invalid_data(
+                        "only RINEX 3 and 4 navigation files are supported",
+                    ));
+                }
+            }
+            "END OF HEADER" => break,
+            _ => {}
+        }
+    }
+    let mut ephemerides = Vec::new();
+    let mut record: Vec<String> = Vec::new();
+    let mut skip = false;
+    for line in lines {
+        let line = line?;
+        let new_record = !line.starts_with(' ');
+        if new_record && !record.is_empty() {
+            ephemerides.extend(parse_record(&record));
+            record.clear();
+        }
+        if let Some(label) = line.strip_prefix('>') {
+            let fields = label.split_whitespace().collect::<Vec<_>>();
+            skip = !matches!(
+                fields[..],
+                [
+                    "EPH",
+                    _,
+                    "LNAV" | "INAV" | "FNAV" | "D1" | "D2" | "FDMA",
+                    ..
+                ]
+            );
+        } else if !skip && (new_record || !record.is_empty()) {
+            record.push(line);
+        }
+    }
+    if !record.is_empty() {
+        ephemerides.extend(parse_record(&record));
+    }
+    Ok(ephemerides)
+}
+
+/// Ephemeris of a navigation record, with the GPS time of its transmission
+fn parse_record(lines: &[String]) -> Option<(NaiveDateTime, Ephemeris)> {
+    let first = lines.first()?;
+    let gnss = first.chars().next().and_then(Gnss::from_system_char)?;
+    let prn = column(first, 1, 2).trim().parse::<u8>().ok()?;
+    let epoch = column(first, 4, 19)
+        .split_whitespace()
+        .map(|f| f.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let [year, month, day, hour, minute, second] = epoch[..] else {
+        return None;
+    };
+    let epoch =
+        NaiveDate::from_ymd_opt(year as i32, month, day)?.and_hms_opt(hour, minute, second)?;
+    let mut values = (0..3)
+        .map(|i| parse_f64(column(first, 23 + 19 * i, 19)))
+        .collect::<Vec<_>>();
+    for line in lines[1..].iter() {
+        values.extend((0..4).map(|i| parse_f64(column(line, 4 + 19 * i, 19))));
+    }
+    let value = |i: usize| values.get(i).copied().flatten().unwrap_or(0.0);
+    let known = |i: usize| values.get(i).copied().flatten().filter(|v| v.abs() < 0.9e9);
+    if gnss == Gnss::Glonass {
+        if values.len() < 15 {
+            return None;
+        }
+        // message frame time in seconds of the UTC week
+        let toe_s = gps_week_tow(epoch).1 / 1000;
+        let mut frame_s = known(2).map_or(toe_s, |s| s as i64);
+        if frame_s - toe_s > 302_400 {
+            frame_s -= 604_800;
+        } else if toe_s - frame_s > 302_400 {
+            frame_s += 604_800;
+        }
+        let transmission = utc_to_gps(epoch + Duration::try_seconds(frame_s - toe_s)?);
+        let eph = GlonassEphemeris {
+            prn,
+            fcn: value(10) as i8,
+            toe_utc: epoch,
+            tk_s: (frame_s + 3 * 3600).rem_euclid(86_400) as u32,
+            tau_n: -value(0),
+            gamma_n: value(1),
+            position: [value(3), value(7), value(11)],
+            velocity: [value(4), value(8), value(12)],
+            acceleration: [value(5), value(9), value(13)],
+            health: value(6) as u8,
+            age_days: value(14) as u8,
+        };
+        return Some((transmission, Ephemeris::Glonass(eph)));
+    }
+    if !matches!(gnss, Gnss::Gps | Gnss::Galileo | Gnss::Beidou | Gnss::Qzss) || values.len() < 28 {
+        return None;
+    }
+    let week = value(21) as i64;
+    let toe_s = value(11);
+    let accuracy_m = value(23);
+    let eph = KeplerEphemeris {
+        gnss,
+        prn,
+        week,
+        toc_s: (gps_week_tow(epoch).1 / 1000) as f64,
+        toe_s,
+        af0: value(0),
+        af1: value(1),
+        af2: value(2),
+        iode: value(3) as u32,
+        iodc: match gnss {
+            Gnss::Galileo => value(3) as u32,
+            Gnss::Beidou => value(28) as u32,
+            _ => value(26) as u32,
+        },
+        crs: value(4),
+        delta_n: value(5),
+        m0: value(6),
+        cuc: value(7),
+        e: value(8),
+        cus: value(9),
+        sqrt_a: value(10),
+        cic: value(12),
+        omega0: value(13),
+        cis: value(14),
+        i0: value(15),
+        crc: value(16),
+        omega: value(17),
+        omega_dot: value(18),
+        idot: value(19),
+        tgd: match gnss {
+            Gnss::Galileo | Gnss::Beidou => [value(25), value(26)],
+            _ => [value(25), 0.0],
+        },
+        accuracy_index: accuracy_index(gnss, accuracy_m),
+        health: value(24) as u32,
+        codes_on_l2: match gnss {
+            Gnss::Gps | Gnss::Qzss => value(20) as u32,
+            _ => 0,
+        },
+        l2p_flag: match gnss {
+            Gnss::Gps => value(22) as u32,
+            _ => 0,
+        },
+        fit_interval_flag: match gnss {
+            Gnss::Gps => (value(28) > 4.0) as u32,
+            Gnss::Qzss => value(28) as u32,
+            _ => 0,
+        },
+        data_sources: match gnss {
+            Gnss::Galileo => value(20) as u32,
+            _ => 0,
+        },
+    };
+    let mut transmission = match known(27) {
+        Some(transmission_s) => {
+            let mut week = week;
+            if transmission_s - toe_s > 302_400.0 {
+                week -= 1;
+            } else if toe_s - transmission_s > 302_400.0 {
+                week += 1;
+            }
+            if gnss == Gnss::Beidou {
+                week += BDT_WEEK_OFFSET;
+            }
+            week_start(week) + Duration::try_milliseconds((transmission_s * 1000.0) as i64)?
+        }
+        None => eph.toc(),
+    };
+    if gnss == Gnss::Beidou {
+        transmission += Duration::try_seconds(14)?;
+    }
+    Some((transmission, Ephemeris::Kepler(eph)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> RinexOptions {
+        RinexOptions {
+            version: 3,
+            marker_name: None,
+            start: None,
+            end: None,
+            interval: None,
+            nav_path: None,
+        }
+    }
+
+    fn time(day: u32, hour: u32, minute: u32, second: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, second)
+            .unwrap()
+    }
+
+    #[test]
+    fn d19_format() {
+        for (value, formatted) in [
+            (1.234567890123e-5, " 1.234567890123E-05"),
+            (-0.5, "-5.000000000000E-01"),
+            (0.0, " 0.000000000000E+00"),
+            (5153.6789012, " 5.153678901200E+03"),
+            (-2.5e-12, "-2.500000000000E-12"),
+            (UNKNOWN, " 9.999999999990E+08"),
+        ] {
+            assert_eq!(d19(value), formatted);
+            assert_eq!(parse_f64(formatted), Some(value));
+        }
+    }
+
+    #[test]
+    fn record_lines() {
+        assert_eq!(
+            epoch_line(Gnss::Gps, 5, &time(5, 0, 0, 0), [-1.5e-4, 0.0, 0.0]),
+            "G05 2024 01 05 00 00 00-1.500000000000E-04 0.000000000000E+00 0.000000000000E+00\n"
+        );
+        let mut record = String::new();
+        orbit_line(&mut record, &[1.0, -2.0]);
+        assert_eq!(record, "     1.000000000000E+00-2.000000000000E+00\n");
+    }
+
+    #[test]
+    fn kepler_record_read_back() {
+        // values of at most 13 significant digits are written exactly
+        let eph = KeplerEphemeris {
+            gnss: Gnss::Gps,
+            prn: 5,
+            week: 2295,
+            toc_s: 432_000.0,
+            toe_s: 432_000.0,
+            af0: -1.23456789012e-4,
+            af1: -2.5e-12,
+            af2: 0.0,
+            iode: 45,
+            iodc: 301,
+            crs: -12.34375,
+            delta_n: 4.5e-9,
+            m0: 1.234567890123,
+            cuc: -6.5e-7,
+            e: 0.0123456789012,
+            cus: 8.25e-6,
+            sqrt_a: 5153.6789012,
+            cic: 1.1e-7,
+            omega0: -2.345678901234,
+            cis: -3.3e-8,
+            i0: 0.9612345678901,
+            crc: 234.5,
+            omega: 0.7654321098765,
+            omega_dot: -8.1e-9,
+            idot: 2.1e-10,
+            tgd: [-1.1e-8, 0.0],
+            accuracy_index: 1,
+            health: 0,
+            codes_on_l2: 1,
+            l2p_flag: 0,
+            fit_interval_flag: 0,
+            data_sources: 0,
+        };
+        let received = eph.toc() - Duration::try_hours(2).unwrap();
+        let record = RinexNavWriter::new(&options()).kepler_record(&eph, received);
+        assert_eq!(record.lines().count(), 8);
+        let lines = record.lines().map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            parse_record(&lines),
+            Some((received, Ephemeris::Kepler(eph)))
+        );
+    }
+
+    #[test]
+    fn glonass_record_read_back() {
+        let eph = GlonassEphemeris {
+            prn: 12,
+            fcn: -3,
+            toe_utc: time(5, 12, 15, 0),
+            // 12:14:30 UTC in Moscow time
+            tk_s: 54_870,
+            tau_n: 1.23456789e-5,
+            gamma_n: -9.094947017729e-13,
+            position: [-12345.6789, 8765.4321, 19876.54321],
+            velocity: [1.2345678, -2.3456789, 0.4567891],
+            acceleration: [9.313225746155e-10, 0.0, -1.862645149231e-9],
+            health: 0,
+            age_days: 1,
+        };
+        let record = RinexNavWriter::new(&options()).glonass_record(&eph);
+        let lines = record.lines().map(String::from).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        let transmission = utc_to_gps(time(5, 12, 14, 30));
+        assert_eq!(
+            parse_record(&lines),
+            Some((transmission, Ephemeris::Glonass(eph)))
+        );
+    }
+}
@@ -0,0 +1,1057 @@
+use crate::arguments::RinexOptions;
+use crate::gnss_time::{gps_week_tow, leap_seconds_gps, msm_epoch_time, utc_to_gps, TimeResolver};
+use crate::msm::{Gnss, MsmDecoder, MsmObservations, SignalObservation};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use rtcm_rs::Message;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+
+/// Observation kinds in the order they are listed per signal
+const OBS_KINDS: [char; 4] = ['C', 'L', 'D', 'S'];
+/// Epochs collected before the header is written from their observation
+/// types, after which completed epochs are written as they arrive
+const HEADER_EPOCHS: usize = 10;
+
+/// Observation type, e.g. C1C, ordered by frequency band, attribute and kind
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ObsType {
+    band: u8,
+    attribute: char,
+    kind: usize,
+}
+
+impl ObsType {
+    fn label(&self) -> String {
+        format!("{}{}{}", OBS_KINDS[self.kind], self.band, self.attribute)
+    }
+}
+
+struct ObsValue {
+    value: f64,
+    lli: u8,
+    ssi: Option<u8>,
+}
+
+type SatelliteObs = BTreeMap<ObsType, ObsValue>;
+
+/// Station information gathered from 1005/1006/1007/1008/1033 messages
+#[derive(Default)]
+pub struct StationInfo {
+    pub reference_station_id: Option<u16>,
+    pub position: Option<(f64, f64, f64)>,
+    pub antenna_height: Option<f64>,
+    pub antenna_descriptor: Option<String>,
+    pub antenna_serial: Option<String>,
+    pub receiver_type: Option<String>,
+    pub receiver_firmware: Option<String>,
+    pub receiver_serial: Option<String>,
+}
+
+impl StationInfo {
+    /// Updates the station information from `msg`, returns false if the
+    /// message does not carry any station information
+    pub fn update(&mut self, msg: &Message) -> bool {
+        match msg {
+            Message::Msg1005(m) => {
+                self.reference_station_id = Some(m.reference_station_id);
+                self.position = Some((
+                    m.antenna_ref_point_ecef_x_m,
+                    m.antenna_ref_point_ecef_y_m,
+                    m.antenna_ref_point_ecef_z_m,
+                ));
+            }
+            Message::Msg1006(m) => {
+                self.reference_station_id = Some(m.reference_station_id);
+                self.position = Some((
+                    m.antenna_ref_point_ecef_x_m,
+                    m.antenna_ref_point_ecef_y_m,
+                    m.antenna_ref_point_ecef_z_m,
+                ));
+                self.antenna_height = Some(m.antenna_height_m);
+            }
+            Message::Msg1007(m) => {
+                self.antenna_descriptor = Some(m.antenna_descriptor_str.to_string());
+            }
+            Message::Msg1008(m) => {
+                self.antenna_descriptor = Some(m.antenna_descriptor_str.to_string());
+                self.antenna_serial = Some(m.antenna_serial_number_str.to_string());
+            }
+            Message::Msg1033(m) => {
+                self.antenna_descriptor = Some(m.antenna_descriptor_str.to_string());
+                self.antenna_serial = Some(m.antenna_serial_number_str.to_string());
+                self.receiver_type = Some(m.receiver_type_descriptor_str.to_string());
+                self.receiver_firmware = Some(m.receiver_firmware_version_str.to_string());
+                self.receiver_serial = Some(m.receiver_serial_number_str.to_string());
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Collects MSM observations and station information and writes them as a
+/// RINEX observation file. The header is written from the first epochs (see
+/// `HEADER_EPOCHS`), or from all of them if the input ends before, then each
+/// epoch is written once a later epoch has been seen. Observation types
+/// appearing after the header are announced with header records of event
+/// flag 4.
+pub struct RinexObsWriter<'a> {
+    options: &'a RinexOptions,
+    time_resolver: TimeResolver,
+    msm_decoder: MsmDecoder,
+    station: StationInfo,
+    obs_types: BTreeMap<Gnss, BTreeSet<ObsType>>,
+    /// Observation types of the header, or of the last header records
+    written_obs_types: Option<BTreeMap<Gnss, BTreeSet<ObsType>>>,
+    /// Epochs not yet written
+    epochs: BTreeMap<NaiveDateTime, BTreeMap<(Gnss, u8), SatelliteObs>>,
+    last_written: Option<NaiveDateTime>,
+    lock_times: HashMap<(Gnss, u8, u8, char), u64>,
+    glonass_fcn: BTreeMap<u8, i8>,
+}
+
+impl<'a> RinexObsWriter<'a> {
+    pub fn new(options: &'a RinexOptions, time_resolver: TimeResolver) -> Self {
+        RinexObsWriter {
+            options,
+            time_resolver,
+            msm_decoder: MsmDecoder::new(),
+            station: StationInfo::default(),
+            obs_types: BTreeMap::new(),
+            written_obs_types: None,
+            epochs: BTreeMap::new(),
+            last_written: None,
+            lock_times: HashMap::new(),
+            glonass_fcn: BTreeMap::new(),
+        }
+    }
+    /// Adds the content of `msg`, returns false once an epoch after the
+    /// end of the requested time span has been seen
+    pub fn add_message(&mut self, msg: &Message) -> bool {
+        if self.station.update(msg) {
+            return true;
+        }
+        let Some(obs) = self.msm_decoder.decode(msg) else {
+            return true;
+        };
+        let epoch =
+            self.time_resolver
+                .resolve_msm(obs.gnss, obs.epoch_time_ms, obs.glo_day_of_week);
+        if self.options.end.is_some_and(|end| epoch > end) {
+            return false;
+        }
+        if self.options.start.is_some_and(|start| epoch < start) {
+            return true;
+        }
+        // messages arriving after their epoch has been written
+        if self.last_written.is_some_and(|last| epoch <= last) {
+            return true;
+        }
+        if let Some(interval) = self.options.interval {
+            let interval_ms = (interval * 1000.0).round() as i64;
+            if interval_ms > 0 && gps_week_tow(epoch).1 % interval_ms != 0 {
+                return true;
+            }
+        }
+        self.station.reference_station_id = Some(obs.reference_station_id);
+        let integer_ms_known = obs.integer_ms_known();
+        let epoch_obs = self.epochs.entry(epoch).or_default();
+        let obs_types = self.obs_types.entry(obs.gnss).or_default();
+        for sig in obs.signals.iter() {
+            let prn = obs.gnss.rinex_prn(sig.satellite_id);
+            if let Some(fcn) = sig.glonass_fcn {
+                self.glonass_fcn.insert(prn, fcn);
+            }
+            let mut lli = 0;
+            if let Some(lock_time) = sig.lock_time_ms {
+                let key = (obs.gnss, prn, sig.band, sig.attribute);
+                if self
+                    .lock_times
+                    .insert(key, lock_time)
+                    .is_some_and(|prev| lock_time < prev)
+                {
+                    lli |= 1;
+                }
+            }
+            if sig.half_cycle_ambiguity == Some(true) {
+                lli |= 2;
+            }
+            let ssi = sig.cnr_dbhz.map(|cnr| (cnr / 6.0).clamp(1.0, 9.0) as u8);
+            // MSM1-3 pseudorange and phase are modulo one light millisecond
+            let values = [
+                sig.pseudorange_m.filter(|_| integer_ms_known),
+                sig.carrier_phase_cycles.filter(|_| integer_ms_known),
+                sig.doppler_hz,
+                sig.cnr_dbhz,
+            ];
+            let sat_obs = epoch_obs.entry((obs.gnss, prn)).or_default();
+            for (kind, value) in values.into_iter().enumerate() {
+                let Some(value) = value else {
+                    continue;
+                };
+                let obs_type = ObsType {
+                    band: sig.band,
+                    attribute: sig.attribute,
+                    kind,
+                };
+                obs_types.insert(obs_type);
+                sat_obs.insert(
+                    obs_type,
+                    ObsValue {
+                        value,
+                        lli: if kind == 1 { lli } else { 0 },
+                        ssi: if kind <= 1 { ssi } else { None },
+                    },
+                );
+            }
+        }
+        true
+    }
+    /// GPS time of the last observation epoch, or the initial reference
+    pub fn reference(&self) -> NaiveDateTime {
+        self.time_resolver.reference()
+    }
+    /// Writes the epochs completed by a later epoch, and the header once
+    /// enough epochs have been collected
+    pub fn write_completed<W: Write + ?Sized>(&mut self, output: &mut W) -> io::Result<()> {
+        if self.written_obs_types.is_none() {
+            if self.epochs.len() <= HEADER_EPOCHS {
+                return Ok(());
+            }
+            output.write_all(self.header(false).as_bytes())?;
+            self.written_obs_types = Some(self.obs_types.clone());
+        }
+        let Some(open_epoch) = self.epochs.keys().last().copied() else {
+            return Ok(());
+        };
+        let completed = self.epochs.split_off(&open_epoch);
+        let completed = std::mem::replace(&mut self.epochs, completed);
+        if completed.is_empty() {
+            return Ok(());
+        }
+        for (epoch, satellites) in completed.iter() {
+            self.write_epoch(output, epoch, satellites)?;
+        }
+        output.flush()
+    }
+    /// Writes the header if not yet written, and the remaining epochs
+    pub fn write<W: Write + ?Sized>(&mut self, output: &mut W) -> io::Result<()> {
+        if self.written_obs_types.is_none() {
+            output.write_all(self.header(true).as_bytes())?;
+            self.written_obs_types = Some(self.obs_types.clone());
+        }
+        let epochs = std::mem::take(&mut self.epochs);
+        for (epoch, satellites) in epochs.iter() {
+            self.write_epoch(output, epoch, satellites)?;
+        }
+        output.flush()
+    }
+    fn write_epoch<W: Write + ?Sized>(
+        &mut self,
+        output: &mut W,
+        epoch: &NaiveDateTime,
+        satellites: &BTreeMap<(Gnss, u8), SatelliteObs>,
+    ) -> io::Result<()> {
+        if self.written_obs_types.as_ref() != Some(&self.obs_types) {
+            let records = self.obs_types_records();
+            output.write_all(
+                format!(
+                    "> {:04} {:02} {:02} {:02} {:02}{:11.7}  4{:3}\n{}",
+                    epoch.year(),
+                    epoch.month(),
+                    epoch.day(),
+                    epoch.hour(),
+                    epoch.minute(),
+                    epoch.second() as f64 + epoch.nanosecond() as f64 * 1e-9,
+                    records.lines().count(),
+                    records
+                )
+                .as_bytes(),
+            )?;
+            self.written_obs_types = Some(self.obs_types.clone());
+        }
+        self.last_written = Some(*epoch);
+        let mut record = format!(
+            "> {:04} {:02} {:02} {:02} {:02}{:11.7}  0{:3}\n",
+            epoch.year(),
+            epoch.month(),
+            epoch.day(),
+            epoch.hour(),
+            epoch.minute(),
+            epoch.second() as f64 + epoch.nanosecond() as f64 * 1e-9,
+            satellites.len()
+        );
+        for ((gnss, prn), sat_obs) in satellites.iter() {
+            let mut line = format!("{}{:02}", gnss.system_char(), prn);
+            for obs_type in self.obs_types.get(gnss).into_iter().flatten() {
+                match sat_obs.get(obs_type) {
+                    Some(obs) => {
+                        let _ = write!(
+                            line,
+                            "{:14.3}{}{}",
+                            obs.value,
+                            if obs.lli == 0 {
+                                ' '
+                            } else {
+                                (b'0' + obs.lli) as char
+                            },
+                            obs.ssi.map(|s| (b'0' + s) as char).unwrap_or(' ')
+                        );
+                    }
+                    None => line.push_str(&" ".repeat(16)),
+                }
+            }
+            record.push_str(line.trim_end());
+            record.push('\n');
+        }
+        output.write_all(record.as_bytes())
+    }
+    /// Header records of the observation types and phase shifts, written
+    /// with event flag 4 when observation types are added after the header
+    fn obs_types_records(&self) -> String {
+        self.obs_types_lines() + &self.phase_shift_lines()
+    }
+    fn obs_types_lines(&self) -> String {
+        let mut records = String::new();
+        for (gnss, obs_types) in self.obs_types.iter() {
+            let labels = obs_types.iter().map(ObsType::label).collect::<Vec<_>>();
+            for (i, chunk) in labels.chunks(13).enumerate() {
+                let mut content = if i == 0 {
+                    format!("{}  {:3}", gnss.system_char(), labels.len())
+                } else {
+                    " ".repeat(6)
+                };
+                for label in chunk {
+                    content.push(' ');
+                    content.push_str(label);
+                }
+                header_line(&mut records, content, "SYS / # / OBS TYPES");
+            }
+        }
+        records
+    }
+    fn phase_shift_lines(&self) -> String {
+        let mut records = String::new();
+        for (gnss, obs_types) in self.obs_types.iter() {
+            for obs_type in obs_types.iter().filter(|t| OBS_KINDS[t.kind] == 'L') {
+                header_line(
+                    &mut records,
+                    format!("{} {}", gnss.system_char(), obs_type.label()),
+                    "SYS / PHASE SHIFT",
+                );
+            }
+        }
+        records
+    }
+    /// Header from the collected epochs, with the time of the last
+    /// observation only if all epochs are known
+    fn header(&self, complete: bool) -> String {
+        let mut header = String::new();
+        let system = if self.obs_types.len() == 1 {
+            self.obs_types.keys().next().unwrap().system_char()
+        } else {
+            'M'
+        };
+        let version = if self.options.version >= 4 {
+            4.02
+        } else {
+            3.05
+        };
+        header_line(
+            &mut header,
+            format!(
+                "{:9.2}{:11}{:<20}{}",
+                version, "", "OBSERVATION DATA", system
+            ),
+            "RINEX VERSION / TYPE",
+        );
+        header_line(
+            &mut header,
+            format!(
+                "{:<20}{:<20}{}",
+                concat!("rtcm-json ", env!("CARGO_PKG_VERSION")),
+                "",
+                Utc::now().format("%Y%m%d %H%M%S UTC")
+            ),
+            "PGM / RUN BY / DATE",
+        );
+        let marker_name = self.options.marker_name.clone().unwrap_or_else(|| {
+            self.station
+                .reference_station_id
+                .map(|id| format!("{:04}", id))
+                .unwrap_or_default()
+        });
+        header_line(&mut header, marker_name, "MARKER NAME");
+        header_line(&mut header, "GEODETIC".into(), "MARKER TYPE");
+        header_line(&mut header, String::new(), "OBSERVER / AGENCY");
+        header_line(
+            &mut header,
+            format!(
+                "{:<20.20}{:<20.20}{:<20.20}",
+                self.station.receiver_serial.as_deref().unwrap_or(""),
+                self.station.receiver_type.as_deref().unwrap_or(""),
+                self.station.receiver_firmware.as_deref().unwrap_or("")
+            ),
+            "REC # / TYPE / VERS",
+        );
+        header_line(
+            &mut header,
+            format!(
+                "{:<20.20}{:<20.20}",
+                self.station.antenna_serial.as_deref().unwrap_or(""),
+                self.station.antenna_descriptor.as_deref().unwrap_or("")
+            ),
+            "ANT # / TYPE",
+        );
+        let (x, y, z) = self.station.position.unwrap_or_default();
+        header_line(
+            &mut header,
+            format!("{:14.4}{:14.4}{:14.4}", x, y, z),
+            "APPROX POSITION XYZ",
+        );
+        header_line(
+            &mut header,
+            format!(
+                "{:14.4}{:14.4}{:14.4}",
+                self.station.antenna_height.unwrap_or_default(),
+                0.0,
+                0.0
+            ),
+            "ANTENNA: DELTA H/E/N",
+        );
+        header.push_str(&self.obs_types_lines());
+        header_line(&mut header, "DBHZ".into(), "SIGNAL STRENGTH UNIT");
+        if let Some(interval) = self.interval() {
+            header_line(&mut header, format!("{:10.3}", interval), "INTERVAL");
+        }
+        if let (Some(first), Some(last)) = (self.epochs.keys().next(), self.epochs.keys().last()) {
+            header_line(&mut header, time_of_obs(first), "TIME OF FIRST OBS");
+            if complete {
+                header_line(&mut header, time_of_obs(last), "TIME OF LAST OBS");
+            }
+        }
+        header.push_str(&self.phase_shift_lines());
+        if self.obs_types.contains_key(&Gnss::Glonass) {
+            let slots = self.glonass_fcn.iter().collect::<Vec<_>>();
+            for (i, chunk) in slots.chunks(8).enumerate() {
+                let mut content = if i == 0 {
+                    format!("{:3} ", slots.len())
+                } else {
+                    " ".repeat(4)
+                };
+                for (prn, fcn) in chunk {
+                    let _ = write!(content, "R{:02} {:2} ", prn, fcn);
+                }
+                header_line(&mut header, content, "GLONASS SLOT / FRQ #");
+            }
+            header_line(
+                &mut header,
+                [" C1C", " C1P", " C2C", " C2P"]
+                    .map(|c| format!("{:<13}", c))
+                    .concat(),
+                "GLONASS COD/PHS/BIS",
+            );
+        }
+        if let Some(first) = self.epochs.keys().next() {
+            header_line(
+                &mut header,
+                format!("{:6}", leap_seconds_gps(*first)),
+                "LEAP SECONDS",
+            );
+        }
+        header_line(&mut header, String::new(), "END OF HEADER");
+        header
+    }
+    /// Observation interval, as requested or the smallest epoch difference
+    fn interval(&self) -> Option<f64> {
+        self.options.interval.or_else(|| {
+            self.epochs
+                .keys()
+                .zip(self.epochs.keys().skip(1))
+                .map(|(a, b)| (*b - *a).num_milliseconds())
+                .min()
+                .map(|ms| ms as f64 * 0.001)
+        })
+    }
+}
+
+fn time_of_obs(epoch: &NaiveDateTime) -> String {
+    format!(
+        "{:6}{:6}{:6}{:6}{:6}{:13.7}     GPS",
+        epoch.year(),
+        epoch.month(),
+        epoch.day(),
+        epoch.hour(),
+        epoch.minute(),
+        epoch.second() as f64 + epoch.nanosecond() as f64 * 1e-9
+    )
+}
+
+/// Adds the observation types of a `SYS / # / OBS TYPES` record, continuation
+/// lines belonging to the system of the previous record
+fn add_obs_types(
+    content: &str,
+    last_system: &mut Option<Gnss>,
+    obs_types: &mut BTreeMap<Gnss, Vec<String>>,
+) {
+    if let Some(gnss) = content.chars().next().and_then(Gnss::from_system_char) {
+        *last_system = Some(gnss);
+        // a record of a system replaces its observation types
+        obs_types.remove(&gnss);
+    }
+    if let Some(gnss) = *last_system {
+        obs_types
+            .entry(gnss)
+            .or_default()
+            .extend(column(content, 7, 53).split_whitespace().map(String::from));
+    }
+}
+
+/// Appends a header line with the content in columns 1-60 and the label in columns 61-80
+pub fn header_line(header: &mut String, content: String, label: &str) {
+    let content = content.chars().take(60).collect::<String>();
+    let _ = writeln!(header, "{:<60}{:<20}", content, label);
+}
+
+/// Reads a RINEX 3/4 observation file epoch by epoch and converts the
+/// observations to MSM observables. Lock times are derived from the loss of
+/// lock indicators, counting from the first epoch after a cycle slip.
+pub struct RinexObsReader<R: BufRead> {
+    input: R,
+    msm_type: u8,
+    reference_station_id: u16,
+    obs_types: BTreeMap<Gnss, Vec<String>>,
+    time_system: String,
+    position: Option<(f64, f64, f64)>,
+    antenna_height: f64,
+    glonass_fcn: HashMap<u8, i8>,
+    lock_start: HashMap<(Gnss, u8, u8, char), NaiveDateTime>,
+}
+
+impl<R: BufRead> RinexObsReader<R> {
+    /// Reads the header of the observation file
+    pub fn new(mut input: R, msm_type: u8, reference_station_id: u16) -> io::Result<Self> {
+        let mut obs_types: BTreeMap<Gnss, Vec<String>> = BTreeMap::new();
+        let mut time_system = String::new();
+        let mut position = None;
+        let mut antenna_height = 0.0;
+        let mut glonass_fcn = HashMap::new();
+        let mut last_system = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return Err(invalid_data("RINEX header without END OF HEADER"));
+            }
+            let content = column(&line, 0, 60);
+            match column(&line, 60, 20).trim() {
+                "RINEX VERSION / TYPE" => {
+                    let version = parse_f64(column(content, 0, 9)).unwrap_or(0.0);
+                    if version < 3.0 || column(content, 20, 1) != "O" {
+                        return Err(invalid_data(
+                            "only RINEX 3 and 4 observation files are supported",
+                        ));
+                    }
+                    time_system = match column(content, 40, 1) {
+                        "R" => "GLO".into(),
+                        "C" => "BDT".into(),
+                        _ => "GPS".into(),
+                    };
+                }
+                "SYS / # / OBS TYPES" => add_obs_types(content, &mut last_system, &mut obs_types),
+                "APPROX POSITION XYZ" => {
+                    let xyz = content
+                        .split_whitespace()
+                        .filter_map(parse_f64)
+                        .collect::<Vec<_>>();
+                    if let [x, y, z] = xyz[..] {
+                        position = Some((x, y, z));
+                    }
+                }
+                "ANTENNA: DELTA H/E/N" => {
+                    antenna_height = parse_f64(column(content, 0, 14)).unwrap_or(0.0);
+                }
+                "TIME OF FIRST OBS" => {
+                    let system = column(content, 48, 3).trim();
+                    if !system.is_empty() {
+                        time_system = system.into();
+                    }
+                }
+                "GLONASS SLOT / FRQ #" => {
+                    let tokens = column(content, 4, 56)
+                        .split_whitespace()
+                        .collect::<Vec<_>>();
+                    for slot in tokens.chunks(2) {
+                        if let [sat, fcn] = slot {
+                            if let (Ok(prn), Ok(fcn)) =
+                                (column(sat, 1, 2).parse::<u8>(), fcn.parse::<i8>())
+                            {
+                                glonass_fcn.insert(prn, fcn);
+                            }
+                        }
+                    }
+                }
+                "END OF HEADER" => break,
+                _ => {}
+            }
+        }
+        Ok(RinexObsReader {
+            input,
+            msm_type,
+            reference_station_id,
+            obs_types,
+            time_system,
+            position,
+            antenna_height,
+            glonass_fcn,
+            lock_start: HashMap::new(),
+        })
+    }
+    /// Adds a GLONASS frequency channel number, e.g. from navigation data,
+    /// for files lacking the GLONASS SLOT / FRQ # header line
+    pub fn set_glonass_fcn(&mut self, prn: u8, fcn: i8) {
+        self.glonass_fcn.entry(prn).or_insert(fcn);
+    }
+    /// Station position message, 1006 if an antenna height is given, otherwise 1005
+    pub fn station_message(&self) -> Option<Message> {
+        let (x, y, z) = self.position?;
+        let mut fields = Map::new();
+        fields.insert(
+            "reference_station_id".into(),
+            json!(self.reference_station_id),
+        );
+        fields.insert("reserved_24_6".into(), json!(0));
+        for (flag, gnss) in [
+            ("gps_flag", Gnss::Gps),
+            ("glonass_flag", Gnss::Glonass),
+            ("galileo_flag", Gnss::Galileo),
+        ] {
+            fields.insert(flag.into(), json!(self.obs_types.contains_key(&gnss) as u8));
+        }
+        fields.insert("reference_station_ind".into(), json!(0));
+        fields.insert("antenna_ref_point_ecef_x_m".into(), json!(x));
+        fields.insert("single_receiver_osc_ind".into(), json!(0));
+        fields.insert("reserved_73_1".into(), json!(0));
+        fields.insert("antenna_ref_point_ecef_y_m".into(), json!(y));
+        fields.insert("quarter_cycle_ind".into(), json!(0));
+        fields.insert("antenna_ref_point_ecef_z_m".into(), json!(z));
+        let message = if self.antenna_height != 0.0 {
+            fields.insert("antenna_height_m".into(), json!(self.antenna_height));
+            "Msg1006"
+        } else {
+            "Msg1005"
+        };
+        let mut value = Map::new();
+        value.insert(message.into(), Value::Object(fields));
+        serde_json::from_value(Value::Object(value)).ok()
+    }
+    /// Reads the next observation epoch, returns its GPS time and the
+    /// observables per constellation
+    pub fn next_epoch(&mut self) -> io::Result<Option<(NaiveDateTime, Vec<MsmObservations>)>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let Some(record) = line.strip_prefix('>') else {
+                continue;
+            };
+            let fields = record.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 8 {
+                return Err(invalid_data("invalid RINEX epoch record"));
+            }
+            let flag = fields[6].parse::<u8>().unwrap_or(0);
+            let count = fields[7].parse::<usize>().unwrap_or(0);
+            let epoch = self.parse_epoch(&fields[..6]);
+            let satellite_lines = (0..count)
+                .map(|_| {
+                    let mut line = String::new();
+                    self.input.read_line(&mut line).map(|_| line)
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            // flags 2-6 are events followed by header or cycle slip records,
+            // with flag 4 the records may redefine the observation types of
+            // the systems they name
+            if flag == 4 {
+                let mut obs_types = BTreeMap::new();
+                let mut last_system = None;
+                for line in satellite_lines.iter() {
+                    if column(line, 60, 20).trim() == "SYS / # / OBS TYPES" {
+                        add_obs_types(column(line, 0, 60), &mut last_system, &mut obs_types);
+                    }
+                }
+                self.obs_types.extend(obs_types);
+            }
+            if flag > 1 {
+                continue;
+            }
+            let epoch = epoch.ok_or_else(|| invalid_data("invalid RINEX epoch time"))?;
+            let observations = self.observations(epoch, &satellite_lines);
+            return Ok(Some((epoch, observations)));
+        }
+    }
+    /// GPS time of the epoch fields year, month, day, hour, minute and second
+    fn parse_epoch(&self, fields: &[&str]) -> Option<NaiveDateTime> {
+        let ymdhm = fields[..5]
+            .iter()
+            .map(|f| f.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let ms = (parse_f64(fields[5])? * 1000.0).round() as i64;
+        let time = NaiveDate::from_ymd_opt(ymdhm[0] as i32, ymdhm[1], ymdhm[2])?
+            .and_hms_opt(ymdhm[3], ymdhm[4], 0)?
+            + Duration::try_milliseconds(ms)?;
+        Some(match self.time_system.as_str() {
+            "GLO" | "UTC" => utc_to_gps(time),
+            "BDT" => time + Duration::try_seconds(14)?,
+            _ => time,
+        })
+    }
+    fn observations(&mut self, epoch: NaiveDateTime, lines: &[String]) -> Vec<MsmObservations> {
+        let mut signals: BTreeMap<Gnss, Vec<SignalObservation>> = BTreeMap::new();
+        for line in lines {
+            let Some(gnss) = line.chars().next().and_then(Gnss::from_system_char) else {
+                continue;
+            };
+            let Some(prn) = column(line, 1, 2).trim().parse::<u8>().ok() else {
+                continue;
+            };
+            let (Some(satellite_id), Some(obs_types)) =
+                (gnss.msm_satellite_id(prn), self.obs_types.get(&gnss))
+            else {
+                continue;
+            };
+            let mut sat_signals: BTreeMap<(u8, char), SignalObservation> = BTreeMap::new();
+            for (i, obs_type) in obs_types.iter().enumerate() {
+                let mut code = obs_type.chars();
+                let (Some(kind), Some(band), Some(attribute)) = (
+                    code.next(),
+                    code.next().and_then(|b| b.to_digit(10)),
+                    code.next(),
+                ) else {
+                    continue;
+                };
+                let start = 3 + 16 * i;
+                let Some(value) = parse_f64(column(line, start, 14)) else {
+                    continue;
+                };
+                let lli = column(line, start + 14, 1).parse::<u8>().unwrap_or(0);
+                let sig = sat_signals
+                    .entry((band as u8, attribute))
+                    .or_insert_with(|| SignalObservation {
+                        satellite_id,
+                        band: band as u8,
+                        attribute,
+                        glonass_fcn: match gnss {
+                            Gnss::Glonass => self.glonass_fcn.get(&prn).copied(),
+                            _ => None,
+                        },
+                        ..Default::default()
+                    });
+                match kind {
+                    'C' => sig.pseudorange_m = Some(value),
+                    'L' => {
+                        sig.carrier_phase_cycles = Some(value);
+                        sig.half_cycle_ambiguity = Some(lli & 2 != 0);
+                        let key = (gnss, prn, band as u8, attribute);
+                        let start = self.lock_start.entry(key).or_insert(epoch);
+                        if lli & 1 != 0 {
+                            *start = epoch;
+                        }
+                        sig.lock_time_ms = Some((epoch - *start).num_milliseconds() as u64);
+                    }
+                    'D' => sig.doppler_hz = Some(value),
+                    'S' => sig.cnr_dbhz = Some(value),
+                    _ => {}
+                }
+            }
+            signals
+                .entry(gnss)
+                .or_default()
+                .extend(sat_signals.into_values());
+        }
+        signals
+            .into_iter()
+            .map(|(gnss, signals)| {
+                let (epoch_time_ms, glo_day_of_week) = msm_epoch_time(gnss, epoch);
+                MsmObservations {
+                    gnss,
+                    msm_type: self.msm_type,
+                    reference_station_id: self.reference_station_id,
+                    epoch_time_ms,
+                    glo_day_of_week,
+                    multiple_message: false,
+                    issue_of_data_station: None,
+                    clock_steering_ind: 0,
+                    external_clock_ind: 0,
+                    smoothing_type_ind: 0,
+                    smoothing_interval_index: 0,
+                    signals,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Columns `start` to `start + len` of a line, shorter or empty if the line is shorter
+pub fn column(line: &str, start: usize, len: usize) -> &str {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let end = (start + len).min(line.len());
+    line.get(start.min(end)..end).unwrap_or("")
+}
+
+/// Parses a RINEX number, accepting FORTRAN D exponents, None if blank
+pub fn parse_f64(field: &str) -> Option<f64> {
+    field.trim().replace(['D', 'd'], "E").parse::<f64>().ok()
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msm::MsmEncoder;
+
+    const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+    fn signal(
+        gnss: Gnss,
+        satellite_id: u8,
+        band: u8,
+        attribute: char,
+        i: i64,
+    ) -> SignalObservation {
+        let pseudorange_m = 21_000_000.0 + 100.0 * band as f64 + i as f64;
+        let wavelength = SPEED_OF_LIGHT / gnss.frequency(band, None).unwrap();
+        SignalObservation {
+            satellite_id,
+            band,
+            attribute,
+            pseudorange_m: Some(pseudorange_m),
+            carrier_phase_cycles: Some(pseudorange_m / wavelength + 0.25),
+            doppler_hz: Some(-1234.5),
+            cnr_dbhz: Some(45.0),
+            lock_time_ms: Some(600_000 + 1000 * i as u64),
+            half_cycle_ambiguity: Some(false),
+            ..Default::default()
+        }
+    }
+
+    fn messages(
+        gnss: Gnss,
+        msm_type: u8,
+        epoch: NaiveDateTime,
+        signals: Vec<SignalObservation>,
+    ) -> Vec<Message> {
+        let (epoch_time_ms, glo_day_of_week) = msm_epoch_time(gnss, epoch);
+        MsmEncoder::new().encode(&MsmObservations {
+            gnss,
+            msm_type,
+            reference_station_id: 1,
+            epoch_time_ms,
+            glo_day_of_week,
+            multiple_message: false,
+            issue_of_data_station: Some(0),
+            clock_steering_ind: 0,
+            external_clock_ind: 0,
+            smoothing_type_ind: 0,
+            smoothing_interval_index: 0,
+            signals,
+        })
+    }
+
+    fn signals(i: i64) -> Vec<(Gnss, SignalObservation)> {
+        let mut signals = vec![(Gnss::Gps, signal(Gnss::Gps, 3, 1, 'C', i))];
+        // a signal and a system appearing after the header has been written
+        if i >= 15 {
+            signals.push((Gnss::Gps, signal(Gnss::Gps, 3, 2, 'W', i)));
+            signals.push((Gnss::Galileo, signal(Gnss::Galileo, 7, 1, 'C', i)));
+        }
+        signals
+    }
+
+    #[test]
+    fn written_observations_read_back() {
+        let options = RinexOptions {
+            version: 3,
+            marker_name: None,
+            start: None,
+            end: None,
+            interval: None,
+            nav_path: None,
+        };
+        let start = NaiveDate::from_ymd_opt(2024, 1, 5)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let mut writer = RinexObsWriter::new(&options, TimeResolver::new(start));
+        let mut output = Vec::new();
+        // observables as decoded from the messages, the phase including the
+        // integer cycles chosen by the encoder
+        let mut decoder = MsmDecoder::new();
+        let mut expected: HashMap<i64, Vec<(Gnss, SignalObservation)>> = HashMap::new();
+        for i in 0..20 {
+            let epoch = start + Duration::try_seconds(i).unwrap();
+            for gnss in [Gnss::Gps, Gnss::Galileo] {
+                let gnss_signals = signals(i)
+                    .into_iter()
+                    .filter(|(g, _)| *g == gnss)
+                    .map(|(_, sig)| sig)
+                    .collect::<Vec<_>>();
+                if gnss_signals.is_empty() {
+                    continue;
+                }
+                for msg in messages(gnss, 7, epoch, gnss_signals) {
+                    let obs = decoder.decode(&msg).unwrap();
+                    expected
+                        .entry(i)
+                        .or_default()
+                        .extend(obs.signals.into_iter().map(|sig| (gnss, sig)));
+                    assert!(writer.add_message(&msg));
+                    writer.write_completed(&mut output).unwrap();
+                }
+            }
+        }
+        writer.write(&mut output).unwrap();
+
+        let mut reader = RinexObsReader::new(&output[..], 7, 1).unwrap();
+        let mut epochs = 0;
+        while let Some((epoch, observations)) = reader.next_epoch().unwrap() {
+            let i = (epoch - start).num_seconds();
+            let read = observations
+                .iter()
+                .flat_map(|obs| obs.signals.iter().map(move |sig| (obs.gnss, sig)))
+                .collect::<Vec<_>>();
+            let expected = &expected[&i];
+            assert_eq!(read.len(), signals(i).len(), "epoch {}", i);
+            assert_eq!(read.len(), expected.len(), "epoch {}", i);
+            for (gnss, sig) in expected.iter() {
+                let (_, read_sig) = read
+                    .iter()
+                    .find(|(g, s)| g == gnss && s.code() == sig.code())
+                    .unwrap_or_else(|| panic!("epoch {}: {:?} {} missing", i, gnss, sig.code()));
+                for (value, read_value) in [
+                    (sig.pseudorange_m, read_sig.pseudorange_m),
+                    (sig.carrier_phase_cycles, read_sig.carrier_phase_cycles),
+                    (sig.doppler_hz, read_sig.doppler_hz),
+                    (sig.cnr_dbhz, read_sig.cnr_dbhz),
+                ] {
+                    assert!(
+                        match (value, read_value) {
+                            (Some(value), Some(read_value)) => (value - read_value).abs() < 0.002,
+                            (value, read_value) => value == read_value,
+                        },
+                        "epoch {}: {:?} {}: {:?} read as {:?}",
+                        i,
+                        gnss,
+                        sig.code(),
+                        value,
+                        read_value
+                    );
+                }
+            }
+            epochs += 1;
+        }
+        assert_eq!(epochs, 20);
+    }
+
+    #[test]
+    fn msm3_phase_modulo_1ms_not_written() {
+        let options = RinexOptions {
+            version: 3,
+            marker_name: None,
+            start: None,
+            end: None,
+            interval: None,
+            nav_path: None,
+        };
+        let start = NaiveDate::from_ymd_opt(2024, 1, 5)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let mut writer = RinexObsWriter::new(&options, TimeResolver::new(start));
+        let signals = vec![signal(Gnss::Gps, 3, 1, 'C', 0)];
+        for msg in messages(Gnss::Gps, 3, start, signals) {
+            assert!(writer.add_message(&msg));
+        }
+        let mut output = Vec::new();
+        writer.write(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("L1C"), "{}", output);
+        assert!(!output.contains("C1C"), "{}", output);
+    }
+
+    #[test]
+    fn observation_columns() {
+        let options = RinexOptions {
+            version: 3,
+            marker_name: None,
+            start: None,
+            end: None,
+            interval: None,
+            nav_path: None,
+        };
+        let epoch = NaiveDate::from_ymd_opt(2024, 1, 5)
+            .unwrap()
+            .and_hms_milli_opt(12, 34, 56, 500)
+            .unwrap();
+        let mut writer = RinexObsWriter::new(&options, TimeResolver::new(epoch));
+        for msg in messages(Gnss::Gps, 7, epoch, vec![signal(Gnss::Gps, 3, 1, 'C', 0)]) {
+            assert!(writer.add_message(&msg));
+        }
+        let mut output = Vec::new();
+        writer.write(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let end_of_header = output.find("END OF HEADER").unwrap() + 21;
+        let (header, data) = output.split_at(end_of_header);
+        for line in header.lines() {
+            assert_eq!(line.len(), 80, "{:?}", line);
+        }
+        assert!(header.contains(
+            "G    4 C1C L1C D1C S1C                                      SYS / # / OBS TYPES \n"
+        ));
+        assert!(header.contains(
+            "  2024     1     5    12    34   56.5000000     GPS         TIME OF FIRST OBS   \n"
+        ));
+        // values in F14.3 followed by the LLI and the SSI, 45 dB-Hz being 7
+        assert_eq!(
+            data.lines().collect::<Vec<_>>(),
+            [
+                "> 2024 01 05 12 34 56.5000000  0  1",
+                "G03  21000100.000 7 110356324.594 7     -1234.500          45.000",
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_and_numbers() {
+        let line = "G01  21000100.000 7 110356324.594 7\r\n";
+        assert_eq!(column(line, 0, 3), "G01");
+        assert_eq!(column(line, 3, 14), "  21000100.000");
+        assert_eq!(column(line, 17, 1), " ");
+        assert_eq!(column(line, 34, 2), "7");
+        assert_eq!(column(line, 35, 14), "");
+        assert_eq!(column(line, 100, 14), "");
+        assert_eq!(parse_f64("  21000100.000"), Some(21_000_100.0));
+        assert_eq!(parse_f64(" -1.234567890123D-05"), Some(-1.234567890123e-5));
+        assert_eq!(parse_f64(" 5.000000000000d+02"), Some(500.0));
+        assert_eq!(parse_f64("              "), None);
+
+        let mut header = String::new();
+        header_line(&mut header, "x".repeat(70), "COMMENT");
+        assert_eq!(header, format!("{}{:<20}\n", "x".repeat(60), "COMMENT"));
+        assert_eq!(
+            time_of_obs(
+                &NaiveDate::from_ymd_opt(2024, 12, 31)
+                    .unwrap()
+                    .and_hms_opt(23, 59, 59)
+                    .unwrap()
+            ),
+            "  2024    12    31    23    59   59.0000000     GPS"
+        );
+    }
+}
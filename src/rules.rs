@@ -0,0 +1,210 @@
+use rtcm_rs::Message;
+use serde_json::{Map, Value};
+use std::str::FromStr;
+
+/// Transformation of one field of the serde representation of messages,
+/// written as `<messages> <action> <field> <values>`:
+///
+/// - `<messages>` is a `MessageSet`, e.g. `*` or `1005,1006,1074-1077`
+/// - `set <field> <value>` replaces the value of the field
+/// - `add <field> <number>` adds an offset to a numeric field
+/// - `replace <field> <old value> <new value>` replaces the value of the
+///   field only if it equals the old value
+///
+/// Values are JSON values, strings without spaces may be given unquoted.
+/// Fields missing in a message are not added.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    messages: MessageSet,
+    field: String,
+    action: Action,
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    Set(Value),
+    Add(f64),
+    Replace(Value, Value),
+}
+
+/// Set of message numbers: `*` for all messages, or a comma separated list
+/// of message numbers, ranges (`1074-1077`), `msm` for all MSM messages and
+/// `eph` for the ephemeris messages 1019, 1020, 1042, 1044, 1045 and 1046
+#[derive(Clone, Debug)]
+pub struct MessageSet {
+    /// Ranges of message numbers, empty for all messages
+    ranges: Vec<(u16, u16)>,
+}
+
+impl MessageSet {
+    pub fn contains(&self, number: u16) -> bool {
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|(first, last)| (*first..=*last).contains(&number))
+    }
+}
+
+impl FromStr for MessageSet {
+    type Err = RuleParseError;
+    fn from_str(messages: &str) -> Result<Self, Self::Err> {
+        if messages.trim() == "*" {
+            return Ok(MessageSet { ranges: Vec::new() });
+        }
+        let mut ranges = Vec::new();
+        for range in messages.split(',').map(str::trim) {
+            match range {
+                "msm" => ranges.push((1071, 1137)),
+                "eph" => ranges
+                    .extend([1019, 1020, 1042, 1044, 1045, 1046].map(|number| (number, number))),
+                _ => {
+                    let (first, last) = range.split_once('-').unwrap_or((range, range));
+                    match (first.parse(), last.parse()) {
+                        (Ok(first), Ok(last)) => ranges.push((first, last)),
+                        _ => {
+                            return Err(RuleParseError(format!(
+                                "invalid message numbers \"{}\"",
+                                messages
+                            )))
+                        }
+                    }
+                }
+            }
+        }
+        Ok(MessageSet { ranges })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RuleParseError(pub String);
+
+impl std::fmt::Display for RuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error parsing rule: {}", self.0)
+    }
+}
+impl std::error::Error for RuleParseError {}
+
+impl FromStr for Rule {
+    type Err = RuleParseError;
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let error = |msg: &str| RuleParseError(format!("{} in \"{}\"", msg, rule.trim()));
+        let mut parts = rule.trim().splitn(4, char::is_whitespace);
+        let (Some(messages), Some(action), Some(field)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(error("expected <messages> <action> <field> <values>"));
+        };
+        let messages = messages
+            .parse::<MessageSet>()
+            .map_err(|_| error("invalid message numbers"))?;
+        let mut values = parse_values(parts.next().unwrap_or(""));
+        let action = match (action, values.len()) {
+            ("set", 1) => Action::Set(values.remove(0)),
+            ("add", 1) => Action::Add(values[0].as_f64().ok_or_else(|| error("invalid number"))?),
+            ("replace", 2) => {
+                let new = values.remove(1);
+                Action::Replace(values.remove(0), new)
+            }
+            ("set" | "add" | "replace", _) => return Err(error("wrong number of values")),
+            _ => return Err(error("unknown action")),
+        };
+        Ok(Rule {
+            messages,
+            field: field.to_string(),
+            action,
+        })
+    }
+}
+
+impl Rule {
+    fn matches(&self, number: u16) -> bool {
+        self.messages.contains(number)
+    }
+    /// Applies the rule to the fields of a message, returns true on a change
+    fn apply_fields(&self, fields: &mut Map<String, Value>) -> bool {
+        let Some(value) = fields.get_mut(&self.field) else {
+            return false;
+        };
+        let new = match &self.action {
+            Action::Set(new) => new.clone(),
+            Action::Add(offset) => match (value.as_i64(), offset.fract() == 0.0) {
+                (Some(v), true) => Value::from(v + *offset as i64),
+                _ => match value.as_f64() {
+                    Some(v) => Value::from(v + offset),
+                    None => return false,
+                },
+            },
+            Action::Replace(old, new) if value == old => new.clone(),
+            Action::Replace(..) => return false,
+        };
+        *value = new;
+        true
+    }
+}
+
+/// Rules read from a file, one rule per line. Empty lines and lines
+/// starting with `#` are ignored.
+pub fn parse_rules_file(path: &str) -> Result<Vec<Rule>, RuleParseError> {
+    std::fs::read_to_string(path)
+        .map_err(|e| RuleParseError(format!("{}: {}", path, e)))?
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(Rule::from_str)
+        .collect()
+}
+
+/// Applies `rules` in order to `msg`. The message is left unchanged if the
+/// transformed fields do not deserialize into a message.
+pub fn apply_rules(rules: &[Rule], msg: Message) -> Message {
+    let Some(number) = msg.number() else {
+        return msg;
+    };
+    if !rules.iter().any(|rule| rule.matches(number)) {
+        return msg;
+    }
+    let Ok(mut value) = serde_json::to_value(&msg) else {
+        return msg;
+    };
+    let Some(fields) = value
+        .as_object_mut()
+        .and_then(|m| m.values_mut().next())
+        .and_then(Value::as_object_mut)
+    else {
+        return msg;
+    };
+    let mut changed = false;
+    for rule in rules.iter().filter(|rule| rule.matches(number)) {
+        changed |= rule.apply_fields(fields);
+    }
+    if !changed {
+        return msg;
+    }
+    serde_json::from_value(value).unwrap_or(msg)
+}
+
+/// Whitespace separated values, each a JSON value or, if not valid JSON,
+/// a string
+fn parse_values(values: &str) -> Vec<Value> {
+    let mut parsed = Vec::new();
+    let mut rest = values.trim_start();
+    while !rest.is_empty() {
+        let mut stream = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+        let json = stream.next().and_then(Result::ok);
+        let end = stream.byte_offset();
+        match json {
+            Some(value) if rest[end..].chars().next().is_none_or(char::is_whitespace) => {
+                parsed.push(value);
+                rest = &rest[end..];
+            }
+            _ => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                parsed.push(Value::from(&rest[..end]));
+                rest = &rest[end..];
+            }
+        }
+        rest = rest.trim_start();
+    }
+    parsed
+}
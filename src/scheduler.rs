@@ -0,0 +1,230 @@
+use crate::arguments::SchedulerOptions;
+use crate::decimate::observation_epoch;
+use crate::gnss_time::TimeResolver;
+use crate::rules::{MessageSet, RuleParseError};
+use chrono::NaiveDate;
+use rtcm_rs::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Priority of a set of message types, written as `<messages>:<priority>`,
+/// 0 being the highest priority
+#[derive(Clone, Debug)]
+pub struct Priority {
+    messages: MessageSet,
+    priority: u8,
+}
+
+impl FromStr for Priority {
+    type Err = RuleParseError;
+    fn from_str(priority: &str) -> Result<Self, Self::Err> {
+        let error = || RuleParseError(format!("invalid priority \"{}\"", priority));
+        let (messages, level) = priority.rsplit_once(':').ok_or_else(error)?;
+        Ok(Priority {
+            messages: messages.parse()?,
+            priority: level.trim().parse().map_err(|_| error())?,
+        })
+    }
+}
+
+/// Priority of messages without a configured priority: observations,
+/// station position and biases, ephemerides, and all other messages
+/// (descriptors, text, ...)
+fn default_priority(number: u16) -> u8 {
+    match number {
+        1001..=1004 | 1009..=1012 | 1071..=1137 => 0,
+        1005 | 1006 | 1230 => 1,
+        1019 | 1020 | 1042 | 1044..=1046 => 2,
+        _ => 3,
+    }
+}
+
+struct QueuedFrame {
+    number: u16,
+    priority: u8,
+    queued_ms: i64,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct FrameStats {
+    sent: u64,
+    delayed: u64,
+    dropped: u64,
+}
+
+/// Output stage fitting a stream of RTCM frames into a bit rate budget.
+///
+/// Frames written to the scheduler are sent on to the output in order of
+/// priority: priority 0 frames (by default the observations) are always sent
+/// immediately, frames of lower priority are sent as long as the budget
+/// allows, and otherwise delayed until it does. Delayed frames are dropped
+/// after the maximum delay, dropped frames are reported to stderr.
+///
+/// The budget is a token bucket holding one second of the bit rate, refilled
+/// by the GNSS epoch time of the observation messages, so that a stream is
+/// scheduled the same whether it is read in real time or from a file. Before
+/// the first observation epoch the elapsed time is used.
+pub struct Scheduler<W: Write> {
+    output: W,
+    bytes_per_ms: f64,
+    capacity: f64,
+    max_delay_ms: i64,
+    priorities: Vec<Priority>,
+    time_resolver: TimeResolver,
+    /// Budget in bytes, negative after priority 0 frames exceeding the budget
+    tokens: f64,
+    clock_ms: i64,
+    /// GPS time (ms) and clock of the first observation epoch
+    first_epoch: Option<(i64, i64)>,
+    start: Instant,
+    queue: VecDeque<QueuedFrame>,
+    buffer: Vec<u8>,
+    stats: BTreeMap<u16, FrameStats>,
+}
+
+impl<W: Write> Scheduler<W> {
+    pub fn new(output: W, options: &SchedulerOptions, date: Option<NaiveDate>) -> Self {
+        let bytes_per_ms = options.bit_rate / 8000.0;
+        Scheduler {
+            output,
+            bytes_per_ms,
+            capacity: bytes_per_ms * 1000.0,
+            max_delay_ms: (options.max_delay * 1000.0).round() as i64,
+            priorities: options.priorities.clone(),
+            time_resolver: TimeResolver::from_date_or_now(date),
+            tokens: bytes_per_ms * 1000.0,
+            clock_ms: 0,
+            first_epoch: None,
+            start: Instant::now(),
+            queue: VecDeque::new(),
+            buffer: Vec::new(),
+            stats: BTreeMap::new(),
+        }
+    }
+    fn priority(&self, number: u16) -> u8 {
+        self.priorities
+            .iter()
+            .find(|priority| priority.messages.contains(number))
+            .map_or_else(|| default_priority(number), |priority| priority.priority)
+    }
+    /// Advances the clock to the epoch of an observation message, or to the
+    /// elapsed time before the first epoch, and refills the budget
+    fn advance(&mut self, msg: &Message) {
+        let epoch_ms = observation_epoch(msg).map(|epoch| epoch.gps_ms(&mut self.time_resolver));
+        let clock_ms = match (epoch_ms, self.first_epoch) {
+            (Some(epoch_ms), Some((first_ms, first_clock_ms))) => {
+                first_clock_ms + epoch_ms - first_ms
+            }
+            (Some(epoch_ms), None) => {
+                self.first_epoch = Some((epoch_ms, self.clock_ms));
+                self.clock_ms
+            }
+            (None, Some(_)) => self.clock_ms,
+            (None, None) => self.start.elapsed().as_millis() as i64,
+        };
+        if clock_ms > self.clock_ms {
+            self.tokens = (self.tokens + (clock_ms - self.clock_ms) as f64 * self.bytes_per_ms)
+                .min(self.capacity);
+            self.clock_ms = clock_ms;
+        }
+    }
+    fn send(&mut self, number: u16, data: &[u8]) -> std::io::Result<()> {
+        self.tokens -= data.len() as f64;
+        self.stats.entry(number).or_default().sent += 1;
+        self.output.write_all(data)
+    }
+    fn drop_frame(&mut self, frame: &QueuedFrame, reason: &str) {
+        self.stats.entry(frame.number).or_default().dropped += 1;
+        eprintln!(
+            "scheduler: dropped message {} ({} bytes) {}",
+            frame.number,
+            frame.data.len(),
+            reason
+        );
+    }
+    /// Drops expired frames and sends the delayed frames, highest priority
+    /// first, as far as the budget allows
+    fn send_queued(&mut self) -> std::io::Result<()> {
+        while let Some(i) = self
+            .queue
+            .iter()
+            .position(|frame| self.clock_ms - frame.queued_ms > self.max_delay_ms)
+        {
+            let frame = self.queue.remove(i).unwrap();
+            self.drop_frame(&frame, "after maximum delay");
+        }
+        while let Some(i) = (0..self.queue.len()).min_by_key(|i| self.queue[*i].priority) {
+            if (self.queue[i].data.len() as f64) > self.tokens {
+                break;
+            }
+            let frame = self.queue.remove(i).unwrap();
+            self.send(frame.number, &frame.data)?;
+        }
+        Ok(())
+    }
+    fn schedule(&mut self, number: u16, priority: u8, data: &[u8]) -> std::io::Result<()> {
+        self.send_queued()?;
+        let waiting = self.queue.iter().any(|frame| frame.priority <= priority);
+        if priority == 0 || (!waiting && data.len() as f64 <= self.tokens) {
+            return self.send(number, data);
+        }
+        self.stats.entry(number).or_default().delayed += 1;
+        self.queue.push_back(QueuedFrame {
+            number,
+            priority,
+            queued_ms: self.clock_ms,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+    /// Frames sent, delayed and dropped per message type
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "{:>8} {:>10} {:>10} {:>10}\n",
+            "message", "sent", "delayed", "dropped"
+        );
+        for (number, stats) in self.stats.iter() {
+            report.push_str(&format!(
+                "{:>8} {:>10} {:>10} {:>10}\n",
+                number, stats.sent, stats.delayed, stats.dropped
+            ));
+        }
+        report
+    }
+}
+
+impl<W: Write> Write for Scheduler<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut iter = MsgFrameIter::new(&buffer);
+        for mf in &mut iter {
+            let msg = mf.get_message();
+            let number = msg.number().unwrap_or(0);
+            self.advance(&msg);
+            self.schedule(number, self.priority(number), mf.frame_data())?;
+        }
+        self.buffer = buffer[iter.consumed()..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_queued()?;
+        self.output.flush()
+    }
+}
+
+impl<W: Write> Drop for Scheduler<W> {
+    /// Reports the frames still delayed at the end of the stream as dropped,
+    /// and the totals per message type
+    fn drop(&mut self) {
+        let _ = self.flush();
+        for frame in std::mem::take(&mut self.queue) {
+            self.drop_frame(&frame, "at end of stream");
+        }
+        eprint!("{}", self.report());
+    }
+}
@@ -1,6 +1,11 @@
-use crate::tcp_handler::TcpHandler;
+use crate::arguments::ReconnectOptions;
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::tcp_handler::{is_timeout, TcpHandler};
+use crate::watchdog::Watchdog;
+use rustls::ClientConfig;
 use std::io::{Read, Write};
-use std::time::Duration;
+use std::sync::Arc;
 
 pub struct TcpClient {
     // Change to use TcpReconnect
@@ -8,16 +13,28 @@ pub struct TcpClient {
 }
 
 impl TcpClient {
-    pub fn new(addr: String, port: u16, reconnect: u64) -> Self {
+    pub fn new(addr: String, port: u16, reconnect: ReconnectOptions) -> Self {
         TcpClient {
-            tcp_handler: TcpHandler::new(addr, port, Duration::from_secs(reconnect)),
+            tcp_handler: TcpHandler::new(addr, port, reconnect),
         }
     }
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.tcp_handler.set_watchdog(watchdog);
+    }
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.tcp_handler.set_metrics(metrics);
+    }
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>) {
+        self.tcp_handler.set_tls(config);
+    }
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.tcp_handler.set_proxy(proxy);
+    }
 }
 
 impl Read for TcpClient {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let stream = match self.tcp_handler.get_stream_reconnect() {
+        let stream = match self.tcp_handler.get_stream_reconnect()? {
             crate::tcp_handler::Connection::ExistingConnection(stream) => stream,
             crate::tcp_handler::Connection::NewConnection(stream) => stream,
         };
@@ -30,6 +47,11 @@ impl Read for TcpClient {
                     Ok(v)
                 }
             }
+            Err(v) if is_timeout(&v) => {
+                let silent = self.tcp_handler.read_timeout().unwrap_or_default();
+                self.tcp_handler.discard_stalled_stream(silent);
+                Err(std::io::ErrorKind::TimedOut.into())
+            }
             Err(v) => {
                 self.tcp_handler.discard_stream();
                 Err(v)
@@ -40,7 +62,7 @@ impl Read for TcpClient {
 
 impl Write for TcpClient {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let stream = match self.tcp_handler.get_stream_reconnect() {
+        let stream = match self.tcp_handler.get_stream_reconnect()? {
             crate::tcp_handler::Connection::ExistingConnection(stream) => stream,
             crate::tcp_handler::Connection::NewConnection(stream) => stream,
         };
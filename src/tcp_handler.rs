@@ -1,53 +1,244 @@
-use std::net::TcpStream;
-use std::time::{Duration, Instant};
+use crate::arguments::ReconnectOptions;
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::tls::Stream;
+use crate::watchdog::Watchdog;
+use rustls::ClientConfig;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct TcpHandler {
     addr: String,
     port: u16,
-    tcp_stream: Option<TcpStream>,
-    reconnect: Duration,
+    tcp_stream: Option<Stream>,
+    reconnect: ReconnectOptions,
+    /// Delay before the next connection attempt
+    wait: Duration,
+    /// Delay after the next failed attempt, before randomization
+    delay: Duration,
+    /// Failed connection attempts since the last connection
+    failed_attempts: u32,
+    /// The maximum number of failed attempts was reached
+    gave_up: bool,
     last_connect: Instant,
+    connected_before: bool,
+    watchdog: Option<Watchdog>,
+    metrics: Option<Metrics>,
+    tls: Option<Arc<ClientConfig>>,
+    proxy: Option<Proxy>,
 }
 
 pub enum Connection<'a> {
-    ExistingConnection(&'a mut TcpStream),
-    NewConnection(&'a mut TcpStream),
+    ExistingConnection(&'a mut Stream),
+    NewConnection(&'a mut Stream),
 }
 
 impl TcpHandler {
-    pub fn new(addr: String, port: u16, reconnect: Duration) -> Self {
+    pub fn new(addr: String, port: u16, reconnect: ReconnectOptions) -> Self {
         TcpHandler {
             addr,
             port,
             tcp_stream: None,
+            wait: reconnect.delay,
+            delay: reconnect.delay,
+            failed_attempts: 0,
+            gave_up: false,
+            last_connect: Instant::now() - reconnect.delay,
             reconnect,
-            last_connect: Instant::now() - reconnect,
+            connected_before: false,
+            watchdog: None,
+            metrics: None,
+            tls: None,
+            proxy: None,
         }
     }
-    pub fn get_stream_reconnect(&mut self) -> Connection<'_> {
-        if self.tcp_stream.is_some() {
-            return Connection::ExistingConnection(self.tcp_stream.as_mut().unwrap());
+    /// Reports disconnections and reconnections to `watchdog`
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.watchdog = Some(watchdog);
+    }
+    /// Reports the connection state and reconnections to `metrics`
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+    /// Connects with TLS
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>) {
+        self.tls = Some(config);
+    }
+    /// Connects through `proxy`
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.proxy = Some(proxy);
+    }
+    /// Existing connection, or a new connection after waiting for the
+    /// reconnect delay. Fails with a `NotConnected` error after the maximum
+    /// number of failed attempts, see `gave_up`.
+    pub fn get_stream_reconnect(&mut self) -> std::io::Result<Connection<'_>> {
+        if let Some(ref mut tcp_stream) = self.tcp_stream {
+            return Ok(Connection::ExistingConnection(tcp_stream));
+        }
+        if self.gave_up {
+            return Err(self.give_up_error());
         }
         while self.tcp_stream.is_none() {
-            let sleep_time = self
-                .reconnect
-                .saturating_sub(Instant::now() - self.last_connect);
+            let sleep_time = self.wait.saturating_sub(Instant::now() - self.last_connect);
             if !sleep_time.is_zero() {
                 std::thread::sleep(sleep_time);
             }
-            let _ = self.connect();
+            let event = if self.connected_before { "reconnected" } else { "connected" };
+            match self.connect() {
+                Ok(()) => {
+                    eprintln!("tcp connection {}:{}: {}", self.addr, self.port, event);
+                    self.failed_attempts = 0;
+                    self.wait = self.reconnect.delay;
+                    self.delay = self.reconnect.delay;
+                }
+                Err(e) => {
+                    self.failed_attempts += 1;
+                    if self
+                        .reconnect
+                        .max_attempts
+                        .is_some_and(|max| self.failed_attempts >= max)
+                    {
+                        eprintln!(
+                            "tcp connection {}:{}: giving up after {} failed attempts ({})",
+                            self.addr, self.port, self.failed_attempts, e
+                        );
+                        self.gave_up = true;
+                        return Err(self.give_up_error());
+                    }
+                    self.wait = jitter(self.delay);
+                    self.delay = self.delay.saturating_mul(2).min(self.reconnect.max_delay);
+                    eprintln!(
+                        "tcp connection {}:{}: connection failed ({}), retrying in {:.1} s",
+                        self.addr,
+                        self.port,
+                        e,
+                        self.wait.as_secs_f64()
+                    );
+                }
+            }
         }
-        Connection::NewConnection(self.tcp_stream.as_mut().unwrap())
+        Ok(Connection::NewConnection(self.tcp_stream.as_mut().unwrap()))
     }
-    pub fn get_stream(&mut self) -> Option<&mut TcpStream> {
+    fn give_up_error(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            GaveUp(format!(
+                "tcp connection {}:{}: gave up after {} failed attempts",
+                self.addr, self.port, self.failed_attempts
+            )),
+        )
+    }
+    pub fn get_stream(&mut self) -> Option<&mut Stream> {
         self.tcp_stream.as_mut()
     }
+    /// Read timeout of the connections, none if reads wait indefinitely
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.reconnect.read_timeout
+    }
     pub fn discard_stream(&mut self) {
-        self.tcp_stream = None;
+        self.close("disconnected");
+    }
+    /// Discards a connection without data received for `silent`, e.g. a
+    /// caster keeping the connection open without sending data
+    pub fn discard_stalled_stream(&mut self, silent: Duration) {
+        if self.tcp_stream.is_some() {
+            if let Some(metrics) = self.metrics.as_ref() {
+                metrics.read_timeout(&self.addr, self.port);
+            }
+        }
+        self.close(&format!(
+            "disconnected (no data for {:.1} s)",
+            silent.as_secs_f64()
+        ));
+    }
+    fn close(&mut self, event: &str) {
+        if self.tcp_stream.take().is_some() {
+            eprintln!("tcp connection {}:{}: {}", self.addr, self.port, event);
+            if let Some(watchdog) = self.watchdog.as_ref() {
+                watchdog.connection("disconnect", &self.addr, self.port);
+            }
+            if let Some(metrics) = self.metrics.as_ref() {
+                metrics.connection(&self.addr, self.port, false, false);
+            }
+        }
     }
     fn connect(&mut self) -> std::io::Result<()> {
         self.last_connect = Instant::now();
-        self.tcp_stream = Some(TcpStream::connect(format!("{}:{}", self.addr, self.port))?);
+        let timeout = self.reconnect.connect_timeout;
+        // the proxy and TLS handshakes are limited by the connect timeout
+        let tcp_stream = match self.proxy.as_ref() {
+            Some(proxy) => {
+                let mut tcp_stream = connect_timeout(&proxy.host, proxy.port, timeout)?;
+                tcp_stream.set_read_timeout(Some(timeout))?;
+                proxy.tunnel(&mut tcp_stream, &self.addr, self.port)?;
+                tcp_stream
+            }
+            None => {
+                let tcp_stream = connect_timeout(&self.addr, self.port, timeout)?;
+                tcp_stream.set_read_timeout(Some(timeout))?;
+                tcp_stream
+            }
+        };
+        let stream = Stream::client(tcp_stream, &self.addr, self.tls.as_ref())?;
+        stream.tcp().set_read_timeout(self.reconnect.read_timeout)?;
+        self.tcp_stream = Some(stream);
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            let event = if self.connected_before { "reconnect" } else { "connect" };
+            watchdog.connection(event, &self.addr, self.port);
+        }
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.connection(&self.addr, self.port, true, self.connected_before);
+        }
+        self.connected_before = true;
         Ok(())
     }
 }
+
+/// Connects to the first address of `host` accepting the connection
+fn connect_timeout(host: &str, port: u16, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut result = Err(std::io::ErrorKind::AddrNotAvailable.into());
+    for address in (host, port).to_socket_addrs()? {
+        result = TcpStream::connect_timeout(&address, timeout);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Error of a connection given up after the maximum number of failed attempts
+#[derive(Debug)]
+struct GaveUp(String);
+
+impl std::fmt::Display for GaveUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for GaveUp {}
+
+/// Error of a connection given up after the maximum number of failed
+/// attempts, which ends the conversion
+pub fn gave_up(error: &std::io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<GaveUp>())
+}
+
+/// Error of a read exceeding the read timeout
+pub fn is_timeout(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// `delay` reduced by a random part of up to 50 %, to spread the
+/// reconnections of clients losing their connections at the same time
+fn jitter(delay: Duration) -> Duration {
+    let random = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |t| t.subsec_nanos()) as f64
+        / 1e9;
+    delay.mul_f64(1.0 - random / 2.0)
+}
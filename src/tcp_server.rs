@@ -1,11 +1,241 @@
+use crate::arguments::{AccessOptions, SlowClientPolicy};
+use crate::metrics::Metrics;
+use crate::tls::Stream;
+use crate::watchdog::Watchdog;
+use rtcm_rs::next_msg_frame;
+use rustls::ServerConfig;
+use std::collections::VecDeque;
 use std::net::{TcpListener, TcpStream};
 use std::sync::mpsc::{channel, Receiver};
-use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
 
+/// Time for clients to send the authentication token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Data received from a client and buffered until read as whole messages.
+/// Clients are not read while their buffer is full, and disconnected if it
+/// holds no whole message, e.g. a line longer than the buffer.
+const MAX_INPUT: usize = 64 * 1024;
+/// Time a client may block the output under `SlowClientPolicy::Block` before
+/// it is disconnected
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How data received from or sent to clients is split into messages
+#[derive(Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// RTCM frames
+    Rtcm,
+    /// Lines, e.g. ndjson
+    Lines,
+}
+
+/// Identity (`<ip>:<port>`) of the connection the last message read from a
+/// `TcpServer` was received on
+#[derive(Clone, Default)]
+pub struct InputSource(Arc<Mutex<Option<String>>>);
+
+impl InputSource {
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().ok().and_then(|source| source.clone())
+    }
+    pub fn set(&self, source: &str) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = Some(source.to_string());
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    peer: String,
+    /// Data received but not yet read as whole messages
+    input: Vec<u8>,
+    /// Messages not yet sent, the first one possibly in part
+    queue: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+    /// Bytes of the first queued message already sent
+    sent: usize,
+    dropped_messages: u64,
+    dropped_bytes: u64,
+    /// Whether the client has sent the authentication token, if required
+    authenticated: bool,
+    connected: Instant,
+    /// Last data received from or sent to the client
+    last_activity: Instant,
+    closed: bool,
+    /// Reason for closing the connection, logged at disconnect
+    close_reason: &'static str,
+}
+
+impl Connection {
+    fn receive(&mut self) {
+        let mut buf = [0; 4096];
+        while self.input.len() < MAX_INPUT {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.closed = true;
+                    return;
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    self.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => {
+                    self.closed = true;
+                    return;
+                }
+            }
+        }
+    }
+    /// Queues a message, applying `policy` if more than `queue_size` bytes
+    /// are queued
+    fn enqueue(&mut self, message: &[u8], queue_size: usize, policy: SlowClientPolicy) {
+        self.queued_bytes += message.len();
+        self.queue.push_back(message.to_vec());
+        self.send_queued();
+        if self.queued_bytes <= queue_size || self.closed {
+            return;
+        }
+        match policy {
+            SlowClientPolicy::DropOldest => {
+                // a message sent in part is completed to keep the stream valid
+                let first = usize::from(self.sent > 0);
+                while self.queued_bytes > queue_size && self.queue.len() > first + 1 {
+                    if let Some(dropped) = self.queue.remove(first) {
+                        self.queued_bytes -= dropped.len();
+                        self.dropped_messages += 1;
+                        self.dropped_bytes += dropped.len() as u64;
+                    }
+                }
+            }
+            SlowClientPolicy::Disconnect => {
+                self.dropped_messages += self.queue.len() as u64;
+                self.dropped_bytes += (self.queued_bytes - self.sent) as u64;
+                self.close("slow client");
+            }
+            SlowClientPolicy::Block => {
+                let tcp = self.stream.tcp();
+                if tcp.set_nonblocking(false).is_err()
+                    || tcp.set_write_timeout(Some(BLOCK_TIMEOUT)).is_err()
+                {
+                    self.closed = true;
+                    return;
+                }
+                let deadline = Instant::now() + BLOCK_TIMEOUT;
+                while self.queued_bytes > queue_size && !self.closed {
+                    if Instant::now() >= deadline {
+                        self.dropped_messages += self.queue.len() as u64;
+                        self.dropped_bytes += (self.queued_bytes - self.sent) as u64;
+                        self.close("slow client, blocked too long");
+                        break;
+                    }
+                    self.send_queued();
+                }
+                let tcp = self.stream.tcp();
+                if tcp.set_write_timeout(None).is_err() || tcp.set_nonblocking(true).is_err() {
+                    self.closed = true;
+                }
+            }
+        }
+    }
+    /// Sends queued messages until the socket would block
+    fn send_queued(&mut self) {
+        while let Some(message) = self.queue.front() {
+            match self.stream.write(&message[self.sent..]) {
+                Ok(0) => self.closed = true,
+                Ok(n) => {
+                    self.sent += n;
+                    self.last_activity = Instant::now();
+                    if self.sent == message.len() {
+                        self.queued_bytes -= message.len();
+                        self.sent = 0;
+                        self.queue.pop_front();
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(_) => self.closed = true,
+            }
+            break;
+        }
+    }
+    fn close(&mut self, reason: &'static str) {
+        self.closed = true;
+        self.close_reason = reason;
+    }
+    /// Checks the first line received against `token`
+    fn authenticate(&mut self, token: &str) {
+        match self.input.iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                if String::from_utf8_lossy(&self.input[..end]).trim() == token {
+                    self.authenticated = true;
+                    self.input.drain(..=end);
+                } else {
+                    self.close("authentication failed");
+                }
+            }
+            None if self.connected.elapsed() > AUTH_TIMEOUT || self.input.len() > 1024 => {
+                self.close("authentication failed")
+            }
+            None => (),
+        }
+    }
+    fn drop_report(&self) -> Option<String> {
+        (self.dropped_messages > 0).then(|| {
+            format!(
+                "tcp server client {}: {} messages ({} bytes) dropped",
+                self.peer, self.dropped_messages, self.dropped_bytes
+            )
+        })
+    }
+}
+
+/// First complete message of `data`, removed with any data before it, i.e.
+/// data between RTCM frames is skipped
+fn next_message(data: &mut Vec<u8>, framing: Framing) -> Option<Vec<u8>> {
+    match framing {
+        Framing::Rtcm => {
+            let (consumed, frame_len) = match next_msg_frame(data) {
+                (consumed, Some(mf)) => (consumed, mf.frame_len()),
+                (consumed, None) => (consumed, 0),
+            };
+            let message = data[consumed - frame_len..consumed].to_vec();
+            data.drain(..consumed);
+            (frame_len > 0).then_some(message)
+        }
+        Framing::Lines => {
+            let end = data.iter().position(|b| *b == b'\n')?;
+            Some(data.drain(..=end).collect())
+        }
+    }
+}
+
+/// Tcp server, as output sending to all connected clients, or as input
+/// reading the messages sent by connected clients, e.g. base stations.
+///
+/// As output, whole messages are queued for each client, up to `queue_size`
+/// bytes before the slow client policy applies. As input, the messages of
+/// all clients are read whole and interleaved, the client of the last message
+/// read is available from `source()`.
 pub struct TcpServer {
     new_connections:Receiver<TcpStream>,
-    connections:Vec<TcpStream>,
+    connections:Vec<Connection>,
+    metrics: Option<Metrics>,
+    watchdog: Option<Watchdog>,
+    framing: Framing,
+    source: InputSource,
+    /// Rest of the last message read, if it did not fit the read buffer
+    pending: Vec<u8>,
+    /// Data written but not yet queued as whole messages
+    output: Vec<u8>,
+    queue_size: usize,
+    policy: SlowClientPolicy,
+    access: AccessOptions,
+    tls: Option<Arc<ServerConfig>>,
+    /// Client to read the next message from, to serve all clients in turn
+    next_client: usize,
 }
 
 impl TcpServer {
@@ -14,61 +244,246 @@ impl TcpServer {
             TcpListener::bind(format!("{}:{}", host, port)).expect("Could not bind to port");
         let (sender,new_connections) = channel();
         let _ = std::thread::spawn(move || {
-            for stream in listener.incoming() {
-                if let Ok(stream) = stream {
-                    stream.set_nonblocking(true).expect("could not set non-blocking mode on tcp connection");
-                    if sender.send(stream).is_err() {
-                        break;
-                    }
-                }                
+            for stream in listener.incoming().flatten() {
+                stream.set_nonblocking(true).expect("could not set non-blocking mode on tcp connection");
+                if sender.send(stream).is_err() {
+                    break;
+                }
             }
         });
         TcpServer {
             new_connections,
             connections: Vec::new(),
+            metrics: None,
+            watchdog: None,
+            framing: Framing::Rtcm,
+            source: InputSource::default(),
+            pending: Vec::new(),
+            output: Vec::new(),
+            queue_size: 1 << 20,
+            policy: SlowClientPolicy::DropOldest,
+            access: AccessOptions::default(),
+            tls: None,
+            next_client: 0,
         }
     }
+    /// Reports the number of connected clients to `metrics`
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+    /// Reports connecting and disconnecting clients to `watchdog`
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.watchdog = Some(watchdog);
+    }
+    /// Framing of the messages read from clients
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+    /// Bytes queued for a client before `policy` applies
+    pub fn set_queue(&mut self, queue_size: usize, policy: SlowClientPolicy) {
+        self.queue_size = queue_size;
+        self.policy = policy;
+    }
+    /// Address ranges, number of clients, authentication and idle timeout of
+    /// the clients accepted
+    pub fn set_access(&mut self, access: AccessOptions) {
+        self.access = access;
+    }
+    /// Serves the clients with TLS
+    pub fn set_tls(&mut self, config: Arc<ServerConfig>) {
+        self.tls = Some(config);
+    }
+    pub fn source(&self) -> InputSource {
+        self.source.clone()
+    }
     fn add_new_connection(&mut self) {
-        for nc in self.new_connections.iter() {
-            self.connections.push(nc);
+        for nc in self.new_connections.try_iter() {
+            let Ok(address) = nc.peer_addr() else {
+                continue;
+            };
+            let peer = address.to_string();
+            if !self.access.allows(address.ip()) {
+                eprintln!("tcp server client {}: rejected (address not allowed)", peer);
+                continue;
+            }
+            if self.access.max_clients.is_some_and(|max| self.live_clients() >= max) {
+                eprintln!("tcp server client {}: rejected (too many clients)", peer);
+                continue;
+            }
+            let Ok(stream) = Stream::server(nc, self.tls.as_ref()) else {
+                eprintln!("tcp server client {}: rejected (TLS setup failed)", peer);
+                continue;
+            };
+            eprintln!("tcp server client {}: connected", peer);
+            self.connection_event("connect", &peer);
+            self.connections.push(Connection {
+                stream,
+                peer,
+                input: Vec::new(),
+                queue: VecDeque::new(),
+                queued_bytes: 0,
+                sent: 0,
+                dropped_messages: 0,
+                dropped_bytes: 0,
+                authenticated: self.access.token.is_none(),
+                connected: Instant::now(),
+                last_activity: Instant::now(),
+                closed: false,
+                close_reason: "closed by client",
+            });
+        }
+    }
+    /// Connected clients counting towards the maximum number of clients,
+    /// i.e. without clients still to authenticate
+    fn live_clients(&self) -> usize {
+        self.connections.iter().filter(|c| c.authenticated && !c.closed).count()
+    }
+    /// Authenticates new clients and disconnects idle ones
+    fn check_clients(&mut self) {
+        let mut clients = self.live_clients();
+        for c in self.connections.iter_mut().filter(|c| !c.closed) {
+            if let Some(token) = self.access.token.as_ref().filter(|_| !c.authenticated) {
+                c.receive();
+                c.authenticate(token);
+                if c.authenticated {
+                    if self.access.max_clients.is_some_and(|max| clients >= max) {
+                        c.close("too many clients");
+                    } else {
+                        clients += 1;
+                    }
+                }
+            }
+            if self
+                .access
+                .idle_timeout
+                .is_some_and(|timeout| c.last_activity.elapsed() > timeout)
+            {
+                c.close("idle timeout");
+            }
+        }
+    }
+    fn remove_closed(&mut self) {
+        let closed: Vec<(String, &str)> = self
+            .connections
+            .iter()
+            .filter(|c| c.closed)
+            .map(|c| (c.peer.clone(), c.close_reason))
+            .collect();
+        for (peer, reason) in closed.iter() {
+            eprintln!("tcp server client {}: disconnected ({})", peer, reason);
+            self.connection_event("disconnect", peer);
+        }
+        let closed = self.connections.iter().filter(|c| c.closed);
+        for report in closed.filter_map(Connection::drop_report) {
+            eprintln!("{}", report);
+        }
+        self.connections.retain(|c| !c.closed);
+    }
+    fn connection_event(&self, event: &str, peer: &str) {
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            let (host, port) = peer.rsplit_once(':').unwrap_or((peer, "0"));
+            watchdog.connection(event, host, port.parse().unwrap_or(0));
+        }
+    }
+    fn report_clients(&self) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.tcp_server_clients(self.live_clients());
+        }
+    }
+    /// Next whole message of any client, with the client's identity
+    fn next_message(&mut self) -> Option<(Vec<u8>, String)> {
+        let count = self.connections.len();
+        for i in 0..count {
+            let index = (self.next_client + i) % count;
+            let connection = &mut self.connections[index];
+            if !connection.authenticated {
+                continue;
+            }
+            if let Some(message) = next_message(&mut connection.input, self.framing) {
+                self.next_client = index + 1;
+                return Some((message, connection.peer.clone()));
+            }
+            if connection.input.len() >= MAX_INPUT {
+                connection.close("input buffer full without a whole message");
+            }
         }
+        None
+    }
+}
+
+impl Read for TcpServer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending.is_empty() {
+            self.add_new_connection();
+            for c in self.connections.iter_mut() {
+                c.receive();
+            }
+            self.check_clients();
+            match self.next_message() {
+                Some((message, peer)) => {
+                    self.source.set(&peer);
+                    self.pending = message;
+                }
+                None => {
+                    self.remove_closed();
+                    self.report_clients();
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
     }
 }
 
 impl Write for TcpServer {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.add_new_connection();
-        self.connections.retain_mut(|c| {
-            match c.write(buf) {
-                Ok(_) => true,
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        true
-                    } else {
-                        false
+        self.check_clients();
+        self.output.extend_from_slice(buf);
+        while let Some(message) = next_message(&mut self.output, self.framing) {
+            let clients = self.connections.iter_mut().filter(|c| c.authenticated && !c.closed);
+            for c in clients {
+                let dropped = c.dropped_messages;
+                c.enqueue(&message, self.queue_size, self.policy);
+                if let Some(metrics) = self.metrics.as_ref() {
+                    if c.dropped_messages > dropped {
+                        metrics.tcp_server_drops(&c.peer, c.dropped_messages - dropped);
                     }
-                },
+                }
             }
-        });
+        }
+        self.remove_closed();
+        self.report_clients();
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         self.add_new_connection();
-        self.connections.retain_mut(|c| {
-            match c.flush() {
-                Ok(_) => true,
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        true
-                    } else {
-                        false
-                    }
-                },
-            }
-        });
+        for c in self.connections.iter_mut() {
+            c.send_queued();
+        }
+        self.remove_closed();
+        self.report_clients();
         Ok(())
     }
 }
 
-
+impl Drop for TcpServer {
+    fn drop(&mut self) {
+        for c in self.connections.iter_mut() {
+            // the remaining output is sent unless a client stops reading
+            let blocking = c.stream.tcp().set_nonblocking(false)
+                .and_then(|_| c.stream.tcp().set_write_timeout(Some(Duration::from_secs(5))));
+            if blocking.is_ok() {
+                c.send_queued();
+                let _ = c.stream.flush();
+            }
+        }
+        for report in self.connections.iter().filter_map(Connection::drop_report) {
+            eprintln!("{}", report);
+        }
+    }
+}
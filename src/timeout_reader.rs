@@ -0,0 +1,75 @@
+use crate::tcp_server::InputSource;
+use std::io::{ErrorKind, Read};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+/// Data read on the reader thread, with the client it was received from
+type Chunk = (std::io::Result<Vec<u8>>, Option<String>);
+
+/// Reads the input on a separate thread, so that a read returns a `TimedOut`
+/// error when no data has arrived within the timeout, also for inputs
+/// without a read timeout of their own such as stdin
+pub struct TimeoutReader {
+    receiver: Receiver<Chunk>,
+    timeout: Duration,
+    pending: Vec<u8>,
+    consumed: usize,
+    /// Client of the data returned by the last read, as the input's source
+    /// runs ahead by the data read on the thread
+    source: InputSource,
+}
+
+impl TimeoutReader {
+    /// Reads `input` on a new thread, with `source` the client of the data
+    /// last read from `input`, if it has one
+    pub fn new<R: Read + Send + 'static>(
+        mut input: R,
+        timeout: Duration,
+        source: Option<InputSource>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(16);
+        std::thread::spawn(move || {
+            let mut buffer = vec![0; 4096];
+            loop {
+                let data = input.read(&mut buffer).map(|n| buffer[..n].to_vec());
+                let end = matches!(data, Ok(ref data) if data.is_empty());
+                let client = source.as_ref().and_then(InputSource::get);
+                if sender.send((data, client)).is_err() || end {
+                    break;
+                }
+            }
+        });
+        TimeoutReader {
+            receiver,
+            timeout,
+            pending: Vec::new(),
+            consumed: 0,
+            source: InputSource::default(),
+        }
+    }
+    pub fn source(&self) -> InputSource {
+        self.source.clone()
+    }
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.consumed == self.pending.len() {
+            self.pending = match self.receiver.recv_timeout(self.timeout) {
+                Ok((data, client)) => {
+                    if let Some(client) = client {
+                        self.source.set(&client);
+                    }
+                    data?
+                }
+                Err(RecvTimeoutError::Timeout) => return Err(ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => Vec::new(),
+            };
+            self.consumed = 0;
+        }
+        let n = buf.len().min(self.pending.len() - self.consumed);
+        buf[..n].copy_from_slice(&self.pending[self.consumed..self.consumed + n]);
+        self.consumed += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,138 @@
+use crate::arguments::TlsOptions;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::StreamOwned;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+/// Tcp connection, plain or TLS
+pub enum Stream {
+    Plain(TcpStream),
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Tls client connection to `host` if `config` is given, with the
+    /// handshake completed to report certificate errors on connecting
+    pub fn client(
+        mut stream: TcpStream,
+        host: &str,
+        config: Option<&Arc<ClientConfig>>,
+    ) -> std::io::Result<Self> {
+        let Some(config) = config else {
+            return Ok(Stream::Plain(stream));
+        };
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mut connection =
+            ClientConnection::new(config.clone(), server_name).map_err(std::io::Error::other)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(Stream::TlsClient(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+    /// Tls server connection if `config` is given
+    pub fn server(stream: TcpStream, config: Option<&Arc<ServerConfig>>) -> std::io::Result<Self> {
+        let Some(config) = config else {
+            return Ok(Stream::Plain(stream));
+        };
+        let connection = ServerConnection::new(config.clone()).map_err(std::io::Error::other)?;
+        Ok(Stream::TlsServer(Box::new(StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+    /// Underlying tcp stream, e.g. to set timeouts
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::TlsClient(stream) => stream.get_ref(),
+            Stream::TlsServer(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::TlsClient(stream) => stream.read(buf),
+            Stream::TlsServer(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::TlsClient(stream) => stream.write(buf),
+            Stream::TlsServer(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::TlsClient(stream) => stream.flush(),
+            Stream::TlsServer(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Client configuration trusting the CA bundle of the options, or the Mozilla
+/// root certificates, with an optional client certificate
+pub fn client_config(
+    options: &TlsOptions,
+) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error>> {
+    let mut roots = RootCertStore::empty();
+    match options.ca_file.as_ref() {
+        Some(ca_file) => {
+            for certificate in CertificateDer::pem_file_iter(ca_file)? {
+                roots.add(certificate?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match (
+        options.client_cert_file.as_ref(),
+        options.client_key_file.as_ref(),
+    ) {
+        (Some(cert_file), Some(key_file)) => {
+            builder.with_client_auth_cert(read_certificates(cert_file)?, read_key(key_file)?)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Server configuration with the certificate chain and private key of PEM
+/// files
+pub fn server_config(
+    cert_file: &str,
+    key_file: &str,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(read_certificates(cert_file)?, read_key(key_file)?)?;
+    Ok(Arc::new(config))
+}
+
+fn read_certificates(
+    path: &str,
+) -> Result<Vec<CertificateDer<'static>>, Box<dyn std::error::Error>> {
+    let certificates = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {}", path).into());
+    }
+    Ok(certificates)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, Box<dyn std::error::Error>> {
+    Ok(PrivateKeyDer::from_pem_file(path)?)
+}
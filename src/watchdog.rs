@@ -0,0 +1,287 @@
+use crate::annotate::reference_station_id;
+use crate::arguments::WatchdogOptions;
+use crate::rules::{MessageSet, RuleParseError};
+use chrono::Utc;
+use rtcm_rs::Message;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Intervals shorter than this are messages of the same burst, e.g. several
+/// MSM of one epoch, and are not used to learn the expected interval
+const BURST_S: f64 = 0.1;
+/// Number of intervals the expected interval is learned from
+const LEARN_INTERVALS: usize = 15;
+/// Minimum number of intervals before a learned interval is used
+const MIN_INTERVALS: usize = 5;
+
+/// Expected interval of a set of message types, written as
+/// `<messages>:<interval s>`, e.g. `msm:1` or `1005,1006:10`. An interval
+/// of 0 disables the monitoring of the message types.
+#[derive(Clone, Debug)]
+pub struct ExpectedInterval {
+    messages: MessageSet,
+    interval_s: f64,
+}
+
+impl FromStr for ExpectedInterval {
+    type Err = RuleParseError;
+    fn from_str(expected: &str) -> Result<Self, Self::Err> {
+        let error = || RuleParseError(format!("invalid expected interval \"{}\"", expected));
+        let (messages, interval) = expected.rsplit_once(':').ok_or_else(error)?;
+        let interval_s = interval.trim().parse::<f64>().map_err(|_| error())?;
+        if interval_s < 0.0 {
+            return Err(error());
+        }
+        Ok(ExpectedInterval {
+            messages: messages.parse()?,
+            interval_s,
+        })
+    }
+}
+
+/// Destination of the alert events, one JSON object per line
+#[derive(Clone, Debug)]
+pub enum AlertSink {
+    StdErr,
+    /// File the events are appended to
+    File(String),
+    /// HTTP endpoint the events are posted to, `http://<host>:<port>/<path>`
+    Webhook {
+        host: String,
+        port: u16,
+        path: String,
+    },
+}
+
+impl FromStr for AlertSink {
+    type Err = RuleParseError;
+    fn from_str(sink: &str) -> Result<Self, Self::Err> {
+        let error = || RuleParseError(format!("invalid webhook url \"{}\"", sink));
+        if sink == "stderr" {
+            return Ok(AlertSink::StdErr);
+        }
+        let Some(url) = sink.strip_prefix("http://") else {
+            return Ok(AlertSink::File(sink.to_string()));
+        };
+        let (host_port, path) = url.split_once('/').unwrap_or((url, ""));
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| error())?),
+            None => (host_port, 80),
+        };
+        if host.is_empty() {
+            return Err(error());
+        }
+        Ok(AlertSink::Webhook {
+            host: host.to_string(),
+            port,
+            path: format!("/{}", path),
+        })
+    }
+}
+
+impl AlertSink {
+    fn send(&self, event: &Value) {
+        let line = event.to_string();
+        let result = match self {
+            AlertSink::StdErr => {
+                eprintln!("{}", line);
+                Ok(())
+            }
+            AlertSink::File(path) => std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line)),
+            AlertSink::Webhook { host, port, path } => post(host, *port, path, &line),
+        };
+        if let Err(e) = result {
+            eprintln!("watchdog: could not send alert ({}): {}", e, line);
+        }
+    }
+}
+
+/// Posts `body` as JSON with HTTP/1.0, the response is not awaited
+fn post(host: &str, port: u16, path: &str, body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect((host, port))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(
+        format!(
+            "POST {} HTTP/1.0\r\nHost: {}:{}\r\nUser-Agent: rtcm-json/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            host,
+            port,
+            version!(),
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    )
+}
+
+/// Arrivals of one message type of one station
+struct Arrivals {
+    last: Instant,
+    intervals: Vec<f64>,
+    /// Configured expected interval (s), 0 for not monitored
+    configured_s: Option<f64>,
+    /// A gap has been reported and the message type is still missing
+    gap: bool,
+}
+
+impl Arrivals {
+    /// Configured or learned expected interval (s)
+    fn expected_s(&self) -> Option<f64> {
+        if let Some(interval_s) = self.configured_s {
+            return Some(interval_s).filter(|interval_s| *interval_s > 0.0);
+        }
+        if self.intervals.len() < MIN_INTERVALS {
+            return None;
+        }
+        let mut sorted = self.intervals.clone();
+        sorted.sort_by(f64::total_cmp);
+        Some(sorted[sorted.len() / 2])
+    }
+}
+
+struct WatchdogState {
+    options: WatchdogOptions,
+    arrivals: HashMap<(u16, u16), Arrivals>,
+    last_message: Instant,
+    stall: Option<Instant>,
+}
+
+impl WatchdogState {
+    fn event(&self, event: &str, fields: Value) {
+        let mut alert = json!({
+            "event": event,
+            "time": Utc::now().naive_utc().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        });
+        if let (Some(alert), Value::Object(fields)) = (alert.as_object_mut(), fields) {
+            alert.extend(fields);
+        }
+        self.options.sink.send(&alert);
+    }
+    fn check(&mut self) {
+        let now = Instant::now();
+        let silent_s = (now - self.last_message).as_secs_f64();
+        if self.stall.is_none() && silent_s > self.options.stall_timeout {
+            self.stall = Some(self.last_message);
+            self.event("stall", json!({ "silent_s": silent_s }));
+        }
+        let mut gaps = Vec::new();
+        for ((number, station), arrivals) in self.arrivals.iter_mut() {
+            let Some(expected_s) = arrivals.expected_s() else {
+                continue;
+            };
+            let silent_s = (now - arrivals.last).as_secs_f64();
+            if !arrivals.gap && silent_s > expected_s * self.options.gap_factor {
+                arrivals.gap = true;
+                gaps.push(json!({
+                    "message": number,
+                    "station": station,
+                    "expected_interval_s": expected_s,
+                    "silent_s": silent_s,
+                }));
+            }
+        }
+        for gap in gaps {
+            self.event("gap", gap);
+        }
+    }
+}
+
+/// Monitors the arrival of messages per message type and reference station,
+/// and the connection of the input, and sends alert events on gaps (a message
+/// type missing for several expected intervals), stalls (no messages at all),
+/// disconnections, reconnections and recovery.
+///
+/// Expected intervals are configured per message type or learned as the
+/// median interval between the latest messages. The watchdog checks for gaps
+/// in a thread of its own, so stalls are detected while the input blocks.
+#[derive(Clone)]
+pub struct Watchdog {
+    state: Arc<Mutex<WatchdogState>>,
+}
+
+impl Watchdog {
+    pub fn new(options: WatchdogOptions) -> Self {
+        let check_interval = Duration::from_secs_f64((options.stall_timeout / 4.0).clamp(0.1, 1.0));
+        let state = Arc::new(Mutex::new(WatchdogState {
+            options,
+            arrivals: HashMap::new(),
+            last_message: Instant::now(),
+            stall: None,
+        }));
+        let thread_state = Arc::downgrade(&state);
+        let _ = std::thread::spawn(move || {
+            while let Some(state) = thread_state.upgrade() {
+                if let Ok(mut state) = state.lock() {
+                    state.check();
+                }
+                drop(state);
+                std::thread::sleep(check_interval);
+            }
+        });
+        Watchdog { state }
+    }
+    /// Registers the arrival of `msg`
+    pub fn message(&self, msg: &Message) {
+        let Some(number) = msg.number() else {
+            return;
+        };
+        let station = reference_station_id(msg);
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        if let Some(stall) = state.stall.take() {
+            state.event(
+                "recovery",
+                json!({ "stall_s": (now - stall).as_secs_f64() }),
+            );
+        }
+        state.last_message = now;
+        let configured_s = state
+            .options
+            .expected
+            .iter()
+            .find(|expected| expected.messages.contains(number))
+            .map(|expected| expected.interval_s);
+        let arrivals = state
+            .arrivals
+            .entry((number, station))
+            .or_insert_with(|| Arrivals {
+                last: now,
+                intervals: Vec::new(),
+                configured_s,
+                gap: false,
+            });
+        let interval_s = (now - arrivals.last).as_secs_f64();
+        if interval_s >= BURST_S {
+            if arrivals.intervals.len() == LEARN_INTERVALS {
+                arrivals.intervals.remove(0);
+            }
+            arrivals.intervals.push(interval_s);
+        }
+        let recovered = std::mem::take(&mut arrivals.gap);
+        arrivals.last = now;
+        if recovered {
+            state.event(
+                "recovery",
+                json!({ "message": number, "station": station, "gap_s": interval_s }),
+            );
+        }
+    }
+    /// Registers a connection event of the input, e.g. `disconnect` or
+    /// `reconnect`
+    pub fn connection(&self, event: &str, host: &str, port: u16) {
+        if let Ok(state) = self.state.lock() {
+            state.event(event, json!({ "host": host, "port": port }));
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::json_stream::{message_number, split_values};
+use crate::rules::MessageSet;
+use base64::Engine as _;
+use rtcm_rs::prelude::*;
+use serde_json::Value;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Clients with more pending output than this are disconnected
+const MAX_PENDING: usize = 1 << 20;
+/// Data received from a client and buffered until read as whole frames.
+/// Clients sending a frame longer than this are disconnected.
+const MAX_INPUT: usize = 64 * 1024;
+/// Time for clients to complete the opening handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+struct Client {
+    stream: TcpStream,
+    /// Message types the client subscribed to, all if none
+    subscription: Option<MessageSet>,
+    input: Vec<u8>,
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl Client {
+    fn send(&mut self, opcode: u8, payload: &[u8]) {
+        self.pending.extend(encode_frame(opcode, payload));
+        self.flush_pending();
+    }
+    fn flush_pending(&mut self) {
+        while !self.pending.is_empty() && !self.closed {
+            match self.stream.write(&self.pending) {
+                Ok(0) => self.closed = true,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => self.closed = true,
+            }
+        }
+        if self.pending.len() > MAX_PENDING {
+            self.closed = true;
+        }
+    }
+    /// Reads and handles the frames sent by the client: subscriptions,
+    /// pings and close
+    fn receive(&mut self) {
+        let mut buf = [0; 1024];
+        while self.input.len() < MAX_INPUT {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.closed = true;
+                    return;
+                }
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.closed = true;
+                    return;
+                }
+            }
+        }
+        while let Some((opcode, payload, len)) = decode_frame(&self.input) {
+            self.input.drain(..len);
+            match opcode {
+                OPCODE_TEXT => self.control(&payload),
+                OPCODE_PING => self.send(OPCODE_PONG, &payload),
+                OPCODE_CLOSE => {
+                    self.send(OPCODE_CLOSE, &payload);
+                    self.closed = true;
+                }
+                _ => (),
+            }
+        }
+        if frame_header(&self.input).is_some_and(|(len, offset)| len > MAX_INPUT - offset) {
+            self.closed = true;
+        }
+    }
+    /// Handles a control message, `{"subscribe":"<messages>"}` with a
+    /// `MessageSet`, e.g. `"1005,1006,msm"` or `"*"`
+    fn control(&mut self, payload: &[u8]) {
+        let subscription = serde_json::from_slice::<Value>(payload)
+            .ok()
+            .and_then(|control| {
+                control
+                    .get("subscribe")?
+                    .as_str()?
+                    .parse::<MessageSet>()
+                    .ok()
+            });
+        match subscription {
+            Some(subscription) => self.subscription = Some(subscription),
+            None => self.send(
+                OPCODE_TEXT,
+                br#"{"error":"expected {\"subscribe\":\"<messages>\"}"}"#,
+            ),
+        }
+    }
+    fn subscribed(&self, number: Option<u16>) -> bool {
+        match (self.subscription.as_ref(), number) {
+            (Some(subscription), Some(number)) => subscription.contains(number),
+            _ => true,
+        }
+    }
+}
+
+/// WebSocket server output for browsers: each json line written is sent as
+/// a text frame, or in binary mode each RTCM frame as a binary frame, to all
+/// connected clients.
+///
+/// Clients may restrict the messages they receive by sending the control
+/// message `{"subscribe":"<messages>"}`, e.g. `{"subscribe":"1005,msm"}`.
+/// Messages without a message number, e.g. grouped epochs, are sent to all
+/// clients.
+pub struct WebSocketServer {
+    new_connections: Receiver<TcpStream>,
+    clients: Vec<Client>,
+    binary: bool,
+    buffer: Vec<u8>,
+}
+
+impl WebSocketServer {
+    pub fn new(host: String, port: u16, binary: bool) -> Self {
+        let listener =
+            TcpListener::bind(format!("{}:{}", host, port)).expect("Could not bind to port");
+        let (sender, new_connections) = channel();
+        let _ = std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // a slow client does not hold up the handshakes of others
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    if handshake(&mut stream).is_ok() && stream.set_nonblocking(true).is_ok() {
+                        let _ = sender.send(stream);
+                    }
+                });
+            }
+        });
+        WebSocketServer {
+            new_connections,
+            clients: Vec::new(),
+            binary,
+            buffer: Vec::new(),
+        }
+    }
+    fn update_clients(&mut self) {
+        for stream in self.new_connections.try_iter() {
+            self.clients.push(Client {
+                stream,
+                subscription: None,
+                input: Vec::new(),
+                pending: Vec::new(),
+                closed: false,
+            });
+        }
+        for client in self.clients.iter_mut() {
+            client.receive();
+            client.flush_pending();
+        }
+        self.clients.retain(|client| !client.closed);
+    }
+    fn broadcast(&mut self, opcode: u8, number: Option<u16>, payload: &[u8]) {
+        for client in self.clients.iter_mut() {
+            if client.subscribed(number) {
+                client.send(opcode, payload);
+            }
+        }
+    }
+}
+
+impl Write for WebSocketServer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update_clients();
+        self.buffer.extend_from_slice(buf);
+        let buffer = std::mem::take(&mut self.buffer);
+        let consumed = if self.binary {
+            let mut iter = MsgFrameIter::new(&buffer);
+            for mf in &mut iter {
+                self.broadcast(OPCODE_BINARY, mf.message_number(), mf.frame_data());
+            }
+            iter.consumed()
+        } else {
+            // json values are sent compact, also if written pretty printed
+            let (values, consumed) = split_values(&buffer);
+            for value in values {
+                let text = value.to_string();
+                self.broadcast(OPCODE_TEXT, message_number(&value), text.as_bytes());
+            }
+            consumed
+        };
+        self.buffer = buffer[consumed..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.update_clients();
+        Ok(())
+    }
+}
+
+/// Answers the WebSocket opening handshake of a client
+fn handshake(stream: &mut TcpStream) -> std::io::Result<()> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let n = stream.read(&mut buf)?;
+        if n == 0 || request.len() > 8192 {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let key = request.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("sec-websocket-key")
+            .then(|| value.trim().to_string())
+    });
+    let Some(key) = key else {
+        stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+        return Err(ErrorKind::InvalidData.into());
+    };
+    let accept = accept_key(&key);
+    stream.write_all(
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept
+        )
+        .as_bytes(),
+    )?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)
+}
+
+/// `Sec-WebSocket-Accept` value answering the `Sec-WebSocket-Key` of a client
+fn accept_key(key: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(
+        sha1_smol::Sha1::from(format!("{}{}", key, WEBSOCKET_GUID))
+            .digest()
+            .bytes(),
+    )
+}
+
+/// Unmasked frame with FIN set, as sent by a server
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len < 65536 => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Payload length and header length (with mask) of the first frame in
+/// `data`, once its header is complete
+fn frame_header(data: &[u8]) -> Option<(usize, usize)> {
+    let masked = data.get(1)? & 0x80 != 0;
+    let (len, offset) = match data[1] & 0x7f {
+        126 => (
+            u16::from_be_bytes(data.get(2..4)?.try_into().ok()?) as usize,
+            4,
+        ),
+        127 => (
+            usize::try_from(u64::from_be_bytes(data.get(2..10)?.try_into().ok()?))
+                .unwrap_or(usize::MAX),
+            10,
+        ),
+        len => (len as usize, 2),
+    };
+    Some((len, if masked { offset + 4 } else { offset }))
+}
+
+/// Opcode, unmasked payload and length of the first complete frame in `data`
+fn decode_frame(data: &[u8]) -> Option<(u8, Vec<u8>, usize)> {
+    let opcode = data.first()? & 0x0f;
+    let (len, offset) = frame_header(data)?;
+    let mask = if data[1] & 0x80 != 0 {
+        data.get(offset - 4..offset)?.to_vec()
+    } else {
+        vec![0; 4]
+    };
+    let payload = data
+        .get(offset..offset.checked_add(len)?)?
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Some((opcode, payload, offset + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // examples of RFC 6455, section 5.7
+    const HELLO: &[u8] = &[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    const MASKED_HELLO: &[u8] = &[
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+
+    #[test]
+    fn accept_key_of_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn encode_frames() {
+        assert_eq!(encode_frame(OPCODE_TEXT, b"Hello"), HELLO);
+        assert_eq!(
+            encode_frame(OPCODE_PONG, b"Hello"),
+            [0x8a, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+        let frame = encode_frame(OPCODE_BINARY, &[0; 256]);
+        assert_eq!(frame[..4], [0x82, 0x7e, 0x01, 0x00]);
+        assert_eq!(frame.len(), 4 + 256);
+        let frame = encode_frame(OPCODE_BINARY, &[0; 65536]);
+        assert_eq!(
+            frame[..10],
+            [0x82, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(frame.len(), 10 + 65536);
+    }
+
+    #[test]
+    fn decode_frames() {
+        assert_eq!(
+            decode_frame(HELLO),
+            Some((OPCODE_TEXT, b"Hello".to_vec(), 7))
+        );
+        assert_eq!(
+            decode_frame(MASKED_HELLO),
+            Some((OPCODE_TEXT, b"Hello".to_vec(), 11))
+        );
+        // the length of the first frame of several
+        let mut data = MASKED_HELLO.to_vec();
+        data.extend_from_slice(HELLO);
+        assert_eq!(decode_frame(&data).unwrap().2, 11);
+        // incomplete frames
+        for len in 0..MASKED_HELLO.len() {
+            assert_eq!(decode_frame(&MASKED_HELLO[..len]), None);
+        }
+        let frame = encode_frame(OPCODE_BINARY, &[7; 300]);
+        assert_eq!(
+            decode_frame(&frame),
+            Some((OPCODE_BINARY, vec![7; 300], 304))
+        );
+        assert_eq!(decode_frame(&frame[..303]), None);
+    }
+
+    #[test]
+    fn frame_headers() {
+        assert_eq!(frame_header(&[0x81]), None);
+        assert_eq!(frame_header(HELLO), Some((5, 2)));
+        assert_eq!(frame_header(&MASKED_HELLO[..2]), Some((5, 6)));
+        assert_eq!(frame_header(&[0x82, 0xfe, 0x01]), None);
+        assert_eq!(frame_header(&[0x82, 0xfe, 0x01, 0x00]), Some((256, 8)));
+        // lengths beyond the input cap are known from the header alone
+        let header = [0x82, 0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(frame_header(&header), Some((1 << 32, 14)));
+        let header = [0x82, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(frame_header(&header), Some((usize::MAX, 10)));
+        assert_eq!(decode_frame(&header), None);
+    }
+}